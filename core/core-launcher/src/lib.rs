@@ -50,6 +50,37 @@ pub fn parse_args(args: &[String], default_dir: &str) -> ParsedArgs {
                     std::process::exit(1);
                 }
             }
+            "--since" => {
+                if let Some(v) = args.get(i + 1) {
+                    out.diff_args = vec!["--diff-since".into(), v.clone()];
+                    i += 2;
+                } else {
+                    eprintln!("Error: --since requires a value");
+                    std::process::exit(1);
+                }
+            }
+            "--stash" => {
+                if let Some(v) = args.get(i + 1) {
+                    out.diff_args = vec!["--diff-stash".into(), v.clone()];
+                    i += 2;
+                } else {
+                    eprintln!("Error: --stash requires a value");
+                    std::process::exit(1);
+                }
+            }
+            "--tags" => {
+                if let Some(v) = args.get(i + 1) {
+                    out.diff_args = vec!["--diff-tags".into(), v.clone()];
+                    i += 2;
+                } else {
+                    eprintln!("Error: --tags requires a value");
+                    std::process::exit(1);
+                }
+            }
+            "--conflicts" => {
+                out.diff_args = vec!["--diff-conflicts".into()];
+                i += 1;
+            }
             arg if !arg.starts_with("--") => {
                 out.working_dir = arg.to_string();
                 i += 1;
@@ -63,3 +94,28 @@ pub fn parse_args(args: &[String], default_dir: &str) -> ParsedArgs {
 
     out
 }
+
+/// First line the app writes to the feedback file when the reviewer approved
+/// and committed instead of sending feedback.
+pub const COMMITTED_MARKER: &str = "air:committed ";
+
+/// How a `--wait` review ended, read from the feedback file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReviewOutcome {
+    /// Comments for the agent, printed as they are.
+    Feedback(String),
+    /// The changes were approved and committed as `commit`; `report` is what
+    /// to print.
+    Committed { commit: String, report: String },
+}
+
+pub fn parse_outcome(contents: &str) -> ReviewOutcome {
+    let Some(rest) = contents.strip_prefix(COMMITTED_MARKER) else {
+        return ReviewOutcome::Feedback(contents.to_string());
+    };
+    let (commit, report) = rest.split_once('\n').unwrap_or((rest, ""));
+    ReviewOutcome::Committed {
+        commit: commit.trim().to_string(),
+        report: report.to_string(),
+    }
+}
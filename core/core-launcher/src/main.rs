@@ -1,4 +1,4 @@
-use core_launcher::{parse_args, ParsedArgs};
+use core_launcher::{parse_args, parse_outcome, ParsedArgs, ReviewOutcome};
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitCode};
 
@@ -174,7 +174,10 @@ fn run() -> ExitCode {
             Ok(status) if status.success() => {
                 if let Some(p) = &feedback_path {
                     if let Ok(contents) = std::fs::read_to_string(p) {
-                        print!("{}", contents);
+                        match parse_outcome(&contents) {
+                            ReviewOutcome::Feedback(feedback) => print!("{}", feedback),
+                            ReviewOutcome::Committed { report, .. } => print!("{}", report),
+                        }
                         let _ = std::fs::remove_file(p);
                         return ExitCode::from(0);
                     }
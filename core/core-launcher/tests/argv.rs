@@ -1,4 +1,4 @@
-use core_launcher::{parse_args, ParsedArgs};
+use core_launcher::{parse_args, parse_outcome, ParsedArgs, ReviewOutcome};
 
 #[test]
 fn parses_wait_and_json_flags() {
@@ -39,6 +39,44 @@ fn parses_commits_range() {
     );
 }
 
+#[test]
+fn parses_since_flag() {
+    let args = vec!["air".into(), "--since".into(), "abc1234".into()];
+    let parsed = parse_args(&args, "/home/me");
+    assert_eq!(
+        parsed.diff_args,
+        vec!["--diff-since".to_string(), "abc1234".to_string()]
+    );
+}
+
+#[test]
+fn parses_stash_flag() {
+    let args = vec!["air".into(), "--stash".into(), "stash@{1}".into()];
+    let parsed = parse_args(&args, "/home/me");
+    assert_eq!(
+        parsed.diff_args,
+        vec!["--diff-stash".to_string(), "stash@{1}".to_string()]
+    );
+}
+
+#[test]
+fn parses_tags_range() {
+    let args = vec!["air".into(), "--tags".into(), "v1.0..v1.1".into()];
+    let parsed = parse_args(&args, "/home/me");
+    assert_eq!(
+        parsed.diff_args,
+        vec!["--diff-tags".to_string(), "v1.0..v1.1".to_string()]
+    );
+}
+
+#[test]
+fn parses_conflicts_flag() {
+    let args = vec!["air".into(), "--conflicts".into(), "/repos/foo".into()];
+    let parsed = parse_args(&args, "/home/me");
+    assert_eq!(parsed.diff_args, vec!["--diff-conflicts".to_string()]);
+    assert_eq!(parsed.working_dir, "/repos/foo");
+}
+
 #[test]
 fn positional_arg_overrides_default_dir() {
     let args = vec!["air".into(), "/repos/foo".into()];
@@ -57,3 +95,18 @@ fn defaults_to_provided_cwd_when_no_positional() {
 fn _ensure_default_constructible() -> ParsedArgs {
     ParsedArgs::default()
 }
+
+#[test]
+fn reads_feedback_and_commit_outcomes() {
+    assert_eq!(
+        parse_outcome("Please rename foo\n"),
+        ReviewOutcome::Feedback("Please rename foo\n".to_string())
+    );
+    assert_eq!(
+        parse_outcome("air:committed abc123\nApproved and committed abc: Fix\n"),
+        ReviewOutcome::Committed {
+            commit: "abc123".to_string(),
+            report: "Approved and committed abc: Fix\n".to_string(),
+        }
+    );
+}
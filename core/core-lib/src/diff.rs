@@ -0,0 +1,941 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffLineKind {
+    Context,
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffLine {
+    pub kind: DiffLineKind,
+    pub content: String,
+    pub old_line: Option<u32>,
+    pub new_line: Option<u32>,
+    /// Set when git printed "\ No newline at end of file" after this line.
+    pub no_newline: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffHunk {
+    pub old_start: u32,
+    pub old_lines: u32,
+    pub new_start: u32,
+    pub new_lines: u32,
+    pub header: String, // text after the closing "@@", usually the enclosing function
+    pub lines: Vec<DiffLine>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDiff {
+    pub old_path: Option<String>, // None when the file is added
+    pub new_path: Option<String>, // None when the file is deleted
+    pub status: String,           // "modified", "added", "deleted", "renamed", "copied"
+    pub old_mode: Option<String>,
+    pub new_mode: Option<String>,
+    pub old_blob: Option<String>, // abbreviated ids from the "index" line
+    pub new_blob: Option<String>,
+    pub is_binary: bool,
+    pub hunks: Vec<DiffHunk>,
+}
+
+impl FileDiff {
+    fn new() -> Self {
+        FileDiff {
+            old_path: None,
+            new_path: None,
+            status: "modified".to_string(),
+            old_mode: None,
+            new_mode: None,
+            old_blob: None,
+            new_blob: None,
+            is_binary: false,
+            hunks: Vec::new(),
+        }
+    }
+
+    /// The path to display for this file: the new path, or the old one for deletions.
+    pub fn path(&self) -> &str {
+        self.new_path
+            .as_deref()
+            .or(self.old_path.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Added and removed line counts, as `git diff --numstat` reports them.
+    pub fn line_counts(&self) -> (u32, u32) {
+        self.hunks
+            .iter()
+            .flat_map(|hunk| &hunk.lines)
+            .fold((0, 0), |(added, removed), line| match line.kind {
+                DiffLineKind::Added => (added + 1, removed),
+                DiffLineKind::Removed => (added, removed + 1),
+                DiffLineKind::Context => (added, removed),
+            })
+    }
+}
+
+/// Parse the output of `git diff` / `git show` (unified format) into typed
+/// per-file records.
+///
+/// Hunk bodies are consumed by their line counts rather than by prefix, so
+/// removed lines that happen to start with "--" or "diff" are not mistaken
+/// for headers.
+pub fn parse_unified_diff(diff: &str) -> Vec<FileDiff> {
+    let mut files = Vec::new();
+    let mut current: Option<FileDiff> = None;
+    // Split on '\n' only: a CRLF file's lines keep their '\r' as content.
+    let mut lines = diff.split_terminator('\n').peekable();
+
+    while let Some(line) = lines.next() {
+        if let Some(rest) = line.strip_prefix("diff --git ") {
+            if let Some(file) = current.take() {
+                files.push(file);
+            }
+            let mut file = FileDiff::new();
+            if let Some((old, new)) = split_diff_git_paths(rest) {
+                file.old_path = Some(old);
+                file.new_path = Some(new);
+            }
+            current = Some(file);
+            continue;
+        }
+
+        // Combined diffs of merge commits name the file once.
+        if let Some(path) = line
+            .strip_prefix("diff --cc ")
+            .or_else(|| line.strip_prefix("diff --combined "))
+        {
+            if let Some(file) = current.take() {
+                files.push(file);
+            }
+            let mut file = FileDiff::new();
+            file.old_path = Some(unquote_path(path));
+            file.new_path = Some(unquote_path(path));
+            current = Some(file);
+            continue;
+        }
+
+        let Some(file) = current.as_mut() else {
+            continue;
+        };
+
+        if line.starts_with("@@") {
+            if let Some(hunk) = parse_hunk(line, &mut lines) {
+                file.hunks.push(hunk);
+            }
+        } else if let Some(mode) = line.strip_prefix("new file mode ") {
+            file.status = "added".to_string();
+            file.old_path = None;
+            file.new_mode = Some(mode.trim().to_string());
+        } else if let Some(mode) = line.strip_prefix("deleted file mode ") {
+            file.status = "deleted".to_string();
+            file.new_path = None;
+            file.old_mode = Some(mode.trim().to_string());
+        } else if let Some(mode) = line.strip_prefix("old mode ") {
+            file.old_mode = Some(mode.trim().to_string());
+        } else if let Some(mode) = line.strip_prefix("new mode ") {
+            file.new_mode = Some(mode.trim().to_string());
+        } else if let Some(index) = line.strip_prefix("index ") {
+            let ids = index.split(' ').next().unwrap_or_default();
+            if let Some((old, new)) = ids.split_once("..") {
+                file.old_blob = Some(old.to_string());
+                file.new_blob = Some(new.to_string());
+            }
+            // "index abc..def 100644" carries the mode when it did not change.
+            if let Some((_, mode)) = index.split_once(' ') {
+                let mode = mode.trim().to_string();
+                if file.old_mode.is_none() && file.status != "added" {
+                    file.old_mode = Some(mode.clone());
+                }
+                if file.new_mode.is_none() && file.status != "deleted" {
+                    file.new_mode = Some(mode);
+                }
+            }
+        } else if let Some(path) = line.strip_prefix("rename from ") {
+            file.status = "renamed".to_string();
+            file.old_path = Some(unquote_path(path));
+        } else if let Some(path) = line.strip_prefix("rename to ") {
+            file.status = "renamed".to_string();
+            file.new_path = Some(unquote_path(path));
+        } else if let Some(path) = line.strip_prefix("copy from ") {
+            file.status = "copied".to_string();
+            file.old_path = Some(unquote_path(path));
+        } else if let Some(path) = line.strip_prefix("copy to ") {
+            file.status = "copied".to_string();
+            file.new_path = Some(unquote_path(path));
+        } else if let Some(path) = line.strip_prefix("--- ") {
+            file.old_path = parse_marker_path(path, "a/");
+        } else if let Some(path) = line.strip_prefix("+++ ") {
+            file.new_path = parse_marker_path(path, "b/");
+        } else if line.starts_with("Binary files ") || line == "GIT binary patch" {
+            file.is_binary = true;
+        } else if line.starts_with("Binary file ") && line.ends_with(" added") {
+            // Synthetic marker used for untracked binary files.
+            file.is_binary = true;
+        }
+    }
+
+    if let Some(file) = current.take() {
+        files.push(file);
+    }
+
+    files
+}
+
+fn mark_no_newline(hunk: &mut DiffHunk) {
+    if let Some(last) = hunk.lines.last_mut() {
+        last.no_newline = true;
+    }
+}
+
+/// Parse one hunk starting at its "@@" header line, consuming its body.
+///
+/// Combined diffs ("@@@ -a,b -c,d +e,f @@@") carry one marker column per
+/// parent; old line numbers there refer to the first parent.
+fn parse_hunk<'a, I>(header: &str, lines: &mut std::iter::Peekable<I>) -> Option<DiffHunk>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let (old_ranges, mut hunk) = parse_hunk_header(header)?;
+    let parents = old_ranges.len();
+    let mut old_remaining: Vec<u32> = old_ranges.iter().map(|(_, count)| *count).collect();
+    let mut new_remaining = hunk.new_lines;
+    let mut old_line = hunk.old_start;
+    let mut new_line = hunk.new_start;
+
+    while new_remaining > 0 || old_remaining.iter().any(|n| *n > 0) {
+        let Some(body) = lines.peek() else { break };
+        if body.starts_with('\\') {
+            mark_no_newline(&mut hunk);
+            lines.next();
+            continue;
+        }
+        // Some tools strip the single space off empty context lines.
+        let (markers, content) = if body.is_empty() {
+            ("", "")
+        } else if body.len() >= parents && body.is_char_boundary(parents) {
+            body.split_at(parents)
+        } else {
+            break;
+        };
+        if !markers.bytes().all(|c| matches!(c, b' ' | b'+' | b'-')) {
+            break;
+        }
+
+        let removed = markers.contains('-');
+        let kind = if removed {
+            DiffLineKind::Removed
+        } else if markers.contains('+') {
+            DiffLineKind::Added
+        } else {
+            DiffLineKind::Context
+        };
+        let mut parsed = DiffLine {
+            kind,
+            content: content.to_string(),
+            old_line: None,
+            new_line: None,
+            no_newline: false,
+        };
+
+        // A removed line exists in the parents marked '-'; any other line
+        // exists in the result and in every parent not marked '+'.
+        for (i, remaining) in old_remaining.iter_mut().enumerate() {
+            let marker = markers.as_bytes().get(i).copied().unwrap_or(b' ');
+            let in_parent = if removed {
+                marker == b'-'
+            } else {
+                marker != b'+'
+            };
+            if in_parent {
+                *remaining = remaining.saturating_sub(1);
+                if i == 0 {
+                    parsed.old_line = Some(old_line);
+                    old_line += 1;
+                }
+            }
+        }
+        if !removed {
+            parsed.new_line = Some(new_line);
+            new_line += 1;
+            new_remaining = new_remaining.saturating_sub(1);
+        }
+
+        hunk.lines.push(parsed);
+        lines.next();
+    }
+
+    // A trailing "\ No newline at end of file" follows the last counted line.
+    if lines.peek().is_some_and(|l| l.starts_with('\\')) {
+        mark_no_newline(&mut hunk);
+        lines.next();
+    }
+
+    Some(hunk)
+}
+
+/// Parse a hunk header line such as "@@ -1,3 +1,4 @@ fn main()", returning
+/// the old range of every parent along with the hunk.
+fn parse_hunk_header(line: &str) -> Option<(Vec<(u32, u32)>, DiffHunk)> {
+    let marker_len = line.bytes().take_while(|c| *c == b'@').count();
+    if marker_len < 2 {
+        return None;
+    }
+    let (ranges, context) = line[marker_len..].split_once(&line[..marker_len])?;
+
+    let mut old_ranges = Vec::new();
+    let mut new_range = None;
+    for part in ranges.split_whitespace() {
+        if let Some(range) = part.strip_prefix('-') {
+            old_ranges.push(parse_range(range)?);
+        } else if let Some(range) = part.strip_prefix('+') {
+            new_range = Some(parse_range(range)?);
+        }
+    }
+    if old_ranges.len() != marker_len - 1 {
+        return None;
+    }
+    let (old_start, old_lines) = old_ranges[0];
+    let (new_start, new_lines) = new_range?;
+
+    let hunk = DiffHunk {
+        old_start,
+        old_lines,
+        new_start,
+        new_lines,
+        header: context.trim().to_string(),
+        lines: Vec::new(),
+    };
+    Some((old_ranges, hunk))
+}
+
+/// Parse "start,count" or "start" (count defaults to 1).
+fn parse_range(range: &str) -> Option<(u32, u32)> {
+    match range.split_once(',') {
+        Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+        None => Some((range.parse().ok()?, 1)),
+    }
+}
+
+/// Parse a "---" / "+++" path, returning None for /dev/null.
+fn parse_marker_path(raw: &str, prefix: &str) -> Option<String> {
+    // git appends a tab after paths containing spaces.
+    let raw = raw.trim_end_matches('\t');
+    if raw == "/dev/null" {
+        return None;
+    }
+    let path = unquote_path(raw);
+    Some(path.strip_prefix(prefix).unwrap_or(&path).to_string())
+}
+
+/// Split the "a/<old> b/<new>" part of a `diff --git` line.
+///
+/// Unquoted paths may contain spaces, so this is ambiguous in general. Both
+/// sides are identical for everything but renames and copies, whose real
+/// paths come from the "rename from/to" lines that follow.
+fn split_diff_git_paths(rest: &str) -> Option<(String, String)> {
+    if rest.starts_with('"') {
+        let (old, tail) = take_quoted(rest)?;
+        let tail = tail.trim_start();
+        let new = if tail.starts_with('"') {
+            take_quoted(tail)?.0
+        } else {
+            tail.to_string()
+        };
+        return Some((strip_side(&old, "a/"), strip_side(&new, "b/")));
+    }
+
+    if rest.ends_with('"') {
+        let start = rest.rfind(" \"")?;
+        let new = unquote_path(&rest[start + 1..]);
+        return Some((strip_side(&rest[..start], "a/"), strip_side(&new, "b/")));
+    }
+
+    // Prefer the split where both halves name the same path.
+    let bytes = rest.as_bytes();
+    if bytes.len() % 2 == 1 {
+        let mid = bytes.len() / 2;
+        if bytes[mid] == b' ' && rest.is_char_boundary(mid) {
+            let (old, new) = (&rest[..mid], &rest[mid + 1..]);
+            if old.strip_prefix("a/") == new.strip_prefix("b/") {
+                return Some((strip_side(old, "a/"), strip_side(new, "b/")));
+            }
+        }
+    }
+
+    let split = rest.find(" b/")?;
+    Some((
+        strip_side(&rest[..split], "a/"),
+        strip_side(&rest[split + 1..], "b/"),
+    ))
+}
+
+fn strip_side(path: &str, prefix: &str) -> String {
+    path.strip_prefix(prefix).unwrap_or(path).to_string()
+}
+
+/// Take a leading C-style quoted string, returning it unquoted and the rest.
+fn take_quoted(s: &str) -> Option<(String, &str)> {
+    let bytes = s.as_bytes();
+    let mut i = 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return Some((unquote_path(&s[..=i]), &s[i + 1..])),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Quote a path the way git does in diff headers when it holds a double
+/// quote, backslash, control character or non-ASCII byte; other paths are
+/// returned unchanged. The inverse of `unquote_path`.
+pub fn quote_path(path: &str) -> String {
+    let needs_quoting = path
+        .bytes()
+        .any(|b| b == b'"' || b == b'\\' || !(0x20..0x7f).contains(&b));
+    if !needs_quoting {
+        return path.to_string();
+    }
+
+    let mut out = String::from("\"");
+    for b in path.bytes() {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b'\r' => out.push_str("\\r"),
+            0x07 => out.push_str("\\a"),
+            0x08 => out.push_str("\\b"),
+            0x0c => out.push_str("\\f"),
+            0x0b => out.push_str("\\v"),
+            b if !(0x20..0x7f).contains(&b) => out.push_str(&format!("\\{:03o}", b)),
+            b => out.push(b as char),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Undo git's C-style path quoting ("a\tb", "\303\251", ...). Unquoted input is
+/// returned unchanged.
+pub fn unquote_path(raw: &str) -> String {
+    let Some(inner) = raw.strip_prefix('"').and_then(|s| s.strip_suffix('"')) else {
+        return raw.to_string();
+    };
+
+    let mut out: Vec<u8> = Vec::with_capacity(inner.len());
+    let bytes = inner.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 >= bytes.len() {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let escaped = bytes[i + 1];
+        match escaped {
+            b'n' => out.push(b'\n'),
+            b't' => out.push(b'\t'),
+            b'r' => out.push(b'\r'),
+            b'a' => out.push(0x07),
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0c),
+            b'v' => out.push(0x0b),
+            b'0'..=b'7' if i + 4 <= bytes.len() => {
+                let octal = std::str::from_utf8(&bytes[i + 1..i + 4])
+                    .ok()
+                    .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+                match octal {
+                    Some(value) => {
+                        out.push(value);
+                        i += 4;
+                        continue;
+                    }
+                    None => out.push(escaped),
+                }
+            }
+            other => out.push(other),
+        }
+        i += 2;
+    }
+
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// Cut a single-file unified diff down to the chosen changes, for `git apply`.
+///
+/// Removed lines are chosen by old line number and added lines by new line
+/// number. A change left out is dropped, or turned into context where the
+/// side the patch applies to still has it: the old side, or the new side
+/// when the patch will be applied in `reverse`. Hunk headers are rewritten
+/// to match. Returns `None` when nothing was chosen.
+pub fn select_patch_lines(
+    patch: &[u8],
+    is_old_chosen: impl Fn(u32) -> bool,
+    is_new_chosen: impl Fn(u32) -> bool,
+    reverse: bool,
+) -> Option<Vec<u8>> {
+    let lines: Vec<&[u8]> = patch.split_inclusive(|&b| b == b'\n').collect();
+    let first_hunk = lines.iter().position(|line| line.starts_with(b"@@ "))?;
+
+    let mut hunks = Vec::new();
+    let mut converted = false;
+    // Lines the new side gains over the old in the hunks written so far.
+    let mut delta: i64 = 0;
+    let mut i = first_hunk;
+    while i < lines.len() {
+        let header = String::from_utf8_lossy(lines[i]);
+        let (_, hunk) = parse_hunk_header(header.trim_end())?;
+        i += 1;
+
+        let (mut old_line, mut new_line) = (hunk.old_start, hunk.new_start);
+        let (mut old_count, mut new_count) = (0i64, 0i64);
+        let mut body = Vec::new();
+        let mut changed = false;
+        // Whether the line a "\ No newline at end of file" marker belongs to was kept.
+        let mut kept = false;
+        while i < lines.len() && !lines[i].starts_with(b"@@ ") {
+            let line = lines[i];
+            i += 1;
+            let (chosen, in_target) = match line.first() {
+                Some(b'\\') => {
+                    if kept {
+                        body.extend_from_slice(line);
+                    }
+                    continue;
+                }
+                Some(b'-') => {
+                    old_line += 1;
+                    (is_old_chosen(old_line - 1), !reverse)
+                }
+                Some(b'+') => {
+                    new_line += 1;
+                    (is_new_chosen(new_line - 1), reverse)
+                }
+                _ => {
+                    old_line += 1;
+                    new_line += 1;
+                    body.extend_from_slice(line);
+                    old_count += 1;
+                    new_count += 1;
+                    kept = true;
+                    continue;
+                }
+            };
+            kept = chosen || in_target;
+            if chosen {
+                changed = true;
+                body.extend_from_slice(line);
+                if line[0] == b'-' {
+                    old_count += 1;
+                } else {
+                    new_count += 1;
+                }
+            } else if in_target {
+                converted = true;
+                body.push(b' ');
+                body.extend_from_slice(&line[1..]);
+                old_count += 1;
+                new_count += 1;
+            }
+        }
+        if !changed {
+            continue;
+        }
+
+        // Keep the target side's positions and derive the other side's.
+        let first_line = |start: u32, count: u32| start as i64 + i64::from(count == 0);
+        let (first_old, first_new) = if reverse {
+            let first_new = first_line(hunk.new_start, hunk.new_lines);
+            (first_new - delta, first_new)
+        } else {
+            let first_old = first_line(hunk.old_start, hunk.old_lines);
+            (first_old, first_old + delta)
+        };
+        let start = |first: i64, count: i64| if count == 0 { first - 1 } else { first };
+        let context = if hunk.header.is_empty() {
+            String::new()
+        } else {
+            format!(" {}", hunk.header)
+        };
+        hunks.extend_from_slice(
+            format!(
+                "@@ -{},{} +{},{} @@{}\n",
+                start(first_old, old_count),
+                old_count,
+                start(first_new, new_count),
+                new_count,
+                context
+            )
+            .as_bytes(),
+        );
+        hunks.extend_from_slice(&body);
+        delta += new_count - old_count;
+    }
+    if hunks.is_empty() {
+        return None;
+    }
+
+    let mut out = Vec::new();
+    let header = &lines[..first_hunk];
+    let creates_or_deletes = header
+        .iter()
+        .any(|line| line.starts_with(b"new file mode ") || line.starts_with(b"deleted file mode "));
+    if converted && creates_or_deletes {
+        // Part of a new or deleted file: what's left is an edit of a file
+        // that exists on both sides.
+        let old_marker = header.iter().find(|line| line.starts_with(b"--- "));
+        let new_marker = header.iter().find(|line| line.starts_with(b"+++ "));
+        for line in header {
+            if line.starts_with(b"new file mode ")
+                || line.starts_with(b"deleted file mode ")
+                || line.starts_with(b"index ")
+            {
+                continue;
+            }
+            if *line == b"--- /dev/null\n" {
+                out.extend_from_slice(&swap_side(new_marker?, b"+++ b/", b"--- a/"));
+            } else if *line == b"+++ /dev/null\n" {
+                out.extend_from_slice(&swap_side(old_marker?, b"--- a/", b"+++ b/"));
+            } else {
+                out.extend_from_slice(line);
+            }
+        }
+    } else {
+        out.extend(header.iter().flat_map(|line| line.iter()));
+    }
+    out.extend_from_slice(&hunks);
+    Some(out)
+}
+
+/// Turn a "+++ b/<path>" marker into "--- a/<path>" or back, quoted or not.
+fn swap_side(marker: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let quoted_from = [&from[..4], b"\"", &from[4..]].concat();
+    if let Some(rest) = marker.strip_prefix(quoted_from.as_slice()) {
+        return [&to[..4], b"\"", &to[4..], rest].concat();
+    }
+    match marker.strip_prefix(from) {
+        Some(rest) => [to, rest].concat(),
+        None => marker.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_modified_file() {
+        let diff = concat!(
+            "diff --git a/src/main.rs b/src/main.rs\n",
+            "index 1111111..2222222 100644\n",
+            "--- a/src/main.rs\n",
+            "+++ b/src/main.rs\n",
+            "@@ -1,3 +1,4 @@ fn main() {\n",
+            " line 1\n",
+            "-line 2\n",
+            "+line two\n",
+            "+line three\n",
+            " line 4\n",
+        );
+        let files = parse_unified_diff(diff);
+
+        assert_eq!(files.len(), 1);
+        let file = &files[0];
+        assert_eq!(file.old_path.as_deref(), Some("src/main.rs"));
+        assert_eq!(file.new_path.as_deref(), Some("src/main.rs"));
+        assert_eq!(file.status, "modified");
+        assert_eq!(file.old_mode.as_deref(), Some("100644"));
+        assert_eq!(file.new_mode.as_deref(), Some("100644"));
+        assert!(!file.is_binary);
+
+        let hunk = &file.hunks[0];
+        assert_eq!((hunk.old_start, hunk.old_lines), (1, 3));
+        assert_eq!((hunk.new_start, hunk.new_lines), (1, 4));
+        assert_eq!(hunk.header, "fn main() {");
+        assert_eq!(hunk.lines.len(), 5);
+        assert_eq!(hunk.lines[1].kind, DiffLineKind::Removed);
+        assert_eq!(hunk.lines[1].old_line, Some(2));
+        assert_eq!(hunk.lines[1].new_line, None);
+        assert_eq!(hunk.lines[3].kind, DiffLineKind::Added);
+        assert_eq!(hunk.lines[3].content, "line three");
+        assert_eq!(hunk.lines[3].new_line, Some(3));
+        assert_eq!(hunk.lines[4].old_line, Some(3));
+        assert_eq!(hunk.lines[4].new_line, Some(4));
+        assert_eq!(file.line_counts(), (2, 1));
+    }
+
+    #[test]
+    fn test_parse_keeps_carriage_returns() {
+        let diff = concat!(
+            "diff --git a/win.txt b/win.txt\n",
+            "index 1111111..2222222 100644\n",
+            "--- a/win.txt\n",
+            "+++ b/win.txt\n",
+            "@@ -1,2 +1,2 @@\r\n",
+            " one\r\n",
+            "-two\r\n",
+            "+2\r\n",
+        );
+        let files = parse_unified_diff(diff);
+
+        let lines = &files[0].hunks[0].lines;
+        assert_eq!(lines[0].content, "one\r");
+        assert_eq!(lines[1].content, "two\r");
+        assert_eq!(lines[2].content, "2\r");
+        assert_eq!(files[0].hunks[0].header, "");
+    }
+
+    #[test]
+    fn test_parse_added_and_deleted_files() {
+        let diff = concat!(
+            "diff --git a/new.txt b/new.txt\n",
+            "new file mode 100755\n",
+            "index 0000000..1111111\n",
+            "--- /dev/null\n",
+            "+++ b/new.txt\n",
+            "@@ -0,0 +1 @@\n",
+            "+hello\n",
+            "diff --git a/old.txt b/old.txt\n",
+            "deleted file mode 100644\n",
+            "index 1111111..0000000\n",
+            "--- a/old.txt\n",
+            "+++ /dev/null\n",
+            "@@ -1 +0,0 @@\n",
+            "-bye\n",
+        );
+        let files = parse_unified_diff(diff);
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].status, "added");
+        assert_eq!(files[0].old_path, None);
+        assert_eq!(files[0].new_path.as_deref(), Some("new.txt"));
+        assert_eq!(files[0].new_mode.as_deref(), Some("100755"));
+        assert_eq!(files[0].hunks[0].lines[0].new_line, Some(1));
+
+        assert_eq!(files[1].status, "deleted");
+        assert_eq!(files[1].new_path, None);
+        assert_eq!(files[1].path(), "old.txt");
+        assert_eq!(files[1].old_mode.as_deref(), Some("100644"));
+        assert_eq!(files[1].hunks[0].lines[0].old_line, Some(1));
+    }
+
+    #[test]
+    fn test_parse_rename_without_content_change() {
+        let diff = concat!(
+            "diff --git a/a b.txt b/c d.txt\n",
+            "similarity index 100%\n",
+            "rename from a b.txt\n",
+            "rename to c d.txt\n",
+        );
+        let files = parse_unified_diff(diff);
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].status, "renamed");
+        assert_eq!(files[0].old_path.as_deref(), Some("a b.txt"));
+        assert_eq!(files[0].new_path.as_deref(), Some("c d.txt"));
+        assert!(files[0].hunks.is_empty());
+    }
+
+    #[test]
+    fn test_parse_mode_change() {
+        let diff = "diff --git a/run.sh b/run.sh\nold mode 100644\nnew mode 100755\n";
+        let files = parse_unified_diff(diff);
+
+        assert_eq!(files[0].old_mode.as_deref(), Some("100644"));
+        assert_eq!(files[0].new_mode.as_deref(), Some("100755"));
+        assert_eq!(files[0].path(), "run.sh");
+    }
+
+    #[test]
+    fn test_parse_binary_file() {
+        let diff = concat!(
+            "diff --git a/logo.png b/logo.png\n",
+            "index 1111111..2222222 100644\n",
+            "Binary files a/logo.png and b/logo.png differ\n",
+        );
+        let files = parse_unified_diff(diff);
+
+        assert!(files[0].is_binary);
+        assert!(files[0].hunks.is_empty());
+    }
+
+    #[test]
+    fn test_parse_synthetic_binary_marker() {
+        let diff =
+            "diff --git a/logo.png b/logo.png\nnew file mode 100644\nBinary file logo.png added\n";
+        let files = parse_unified_diff(diff);
+
+        assert!(files[0].is_binary);
+        assert_eq!(files[0].status, "added");
+    }
+
+    #[test]
+    fn test_parse_removed_line_that_looks_like_header() {
+        let diff = concat!(
+            "diff --git a/notes.md b/notes.md\n",
+            "--- a/notes.md\n",
+            "+++ b/notes.md\n",
+            "@@ -1,2 +1,1 @@\n",
+            "--- a/other\n",
+            " keep\n",
+        );
+        let files = parse_unified_diff(diff);
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].new_path.as_deref(), Some("notes.md"));
+        assert_eq!(files[0].hunks[0].lines[0].kind, DiffLineKind::Removed);
+        assert_eq!(files[0].hunks[0].lines[0].content, "-- a/other");
+    }
+
+    #[test]
+    fn test_parse_no_newline_marker() {
+        let diff = concat!(
+            "diff --git a/a.txt b/a.txt\n",
+            "--- a/a.txt\n",
+            "+++ b/a.txt\n",
+            "@@ -1 +1 @@\n",
+            "-old\n",
+            "\\ No newline at end of file\n",
+            "+new\n",
+            "\\ No newline at end of file\n",
+        );
+        let files = parse_unified_diff(diff);
+        let lines = &files[0].hunks[0].lines;
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].no_newline);
+        assert!(lines[1].no_newline);
+    }
+
+    #[test]
+    fn test_parse_multiple_hunks() {
+        let diff = concat!(
+            "diff --git a/a.txt b/a.txt\n",
+            "--- a/a.txt\n",
+            "+++ b/a.txt\n",
+            "@@ -1,2 +1,2 @@\n",
+            "-a\n",
+            "+b\n",
+            " c\n",
+            "@@ -10,1 +10,2 @@ section\n",
+            " x\n",
+            "+y\n",
+        );
+        let files = parse_unified_diff(diff);
+
+        assert_eq!(files[0].hunks.len(), 2);
+        assert_eq!(files[0].hunks[1].header, "section");
+        assert_eq!(files[0].hunks[1].lines[1].new_line, Some(11));
+    }
+
+    #[test]
+    fn test_parse_quoted_paths() {
+        let diff = concat!(
+            "diff --git \"a/caf\\303\\251.txt\" \"b/caf\\303\\251.txt\"\n",
+            "--- \"a/caf\\303\\251.txt\"\n",
+            "+++ \"b/caf\\303\\251.txt\"\n",
+            "@@ -1 +1 @@\n",
+            "-a\n",
+            "+b\n",
+        );
+        let files = parse_unified_diff(diff);
+
+        assert_eq!(files[0].old_path.as_deref(), Some("café.txt"));
+        assert_eq!(files[0].new_path.as_deref(), Some("café.txt"));
+    }
+
+    #[test]
+    fn test_parse_combined_diff() {
+        let diff = concat!(
+            "diff --cc f\n",
+            "index a1b2c3d,e4f5a6b..0123456\n",
+            "--- a/f\n",
+            "+++ b/f\n",
+            "@@@ -1,3 -1,3 +1,3 @@@\n",
+            "  a\n",
+            "- bb\n",
+            " -B\n",
+            "++resolved\n",
+            "  c\n",
+        );
+        let files = parse_unified_diff(diff);
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path(), "f");
+        let hunk = &files[0].hunks[0];
+        assert_eq!((hunk.old_start, hunk.old_lines), (1, 3));
+        let kinds: Vec<_> = hunk.lines.iter().map(|l| l.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                DiffLineKind::Context,
+                DiffLineKind::Removed,
+                DiffLineKind::Removed,
+                DiffLineKind::Added,
+                DiffLineKind::Context,
+            ]
+        );
+        assert_eq!(hunk.lines[1].content, "bb");
+        assert_eq!(hunk.lines[1].old_line, Some(2));
+        // Removed only from the second parent, so no first-parent line number.
+        assert_eq!(hunk.lines[2].old_line, None);
+        assert_eq!(hunk.lines[3].new_line, Some(2));
+        assert_eq!(hunk.lines[4].old_line, Some(3));
+        assert_eq!(hunk.lines[4].new_line, Some(3));
+    }
+
+    #[test]
+    fn test_parse_empty_diff() {
+        assert!(parse_unified_diff("").is_empty());
+    }
+
+    #[test]
+    fn test_unquote_path_escapes() {
+        assert_eq!(unquote_path("plain.txt"), "plain.txt");
+        assert_eq!(unquote_path("\"tab\\there\""), "tab\there");
+        assert_eq!(unquote_path("\"quote\\\"d\""), "quote\"d");
+    }
+
+    #[test]
+    fn test_quote_path_round_trips() {
+        assert_eq!(quote_path("a/plain name.txt"), "a/plain name.txt");
+        assert_eq!(quote_path("a/new\nline"), "\"a/new\\nline\"");
+        assert_eq!(quote_path("é"), "\"\\303\\251\"");
+        for path in ["b/tab\there", "b/quote\"d\\", "b/ünïcödé\u{7f}"] {
+            assert_eq!(unquote_path(&quote_path(path)), path);
+        }
+    }
+
+    #[test]
+    fn test_select_patch_lines_recounts_hunks() {
+        let patch = b"diff --git a/f b/f\nindex 1111111..2222222 100644\n--- a/f\n+++ b/f\n@@ -1,3 +1,3 @@ fn main\n a\n-b\n+B\n c\n@@ -8,2 +8,3 @@\n h\n+i\n j\n";
+
+        // Only the second hunk: the first one's change is left out entirely.
+        let second = select_patch_lines(patch, |_| false, |n| n == 9, false).unwrap();
+        assert!(String::from_utf8(second)
+            .unwrap()
+            .ends_with("--- a/f\n+++ b/f\n@@ -8,2 +8,3 @@\n h\n+i\n j\n"));
+
+        // Only the removal: "B" is dropped, and the new side shifts up a line.
+        let removal = select_patch_lines(patch, |n| n == 2, |_| false, false).unwrap();
+        assert!(String::from_utf8(removal)
+            .unwrap()
+            .ends_with("@@ -1,3 +1,2 @@ fn main\n a\n-b\n c\n"));
+
+        // In reverse the new side is what the patch applies to, so the
+        // unchosen removal is dropped instead.
+        let reverse = select_patch_lines(patch, |_| false, |n| n == 2, true).unwrap();
+        assert!(String::from_utf8(reverse)
+            .unwrap()
+            .ends_with("@@ -1,2 +1,3 @@ fn main\n a\n+B\n c\n"));
+
+        assert!(select_patch_lines(patch, |_| false, |_| false, false).is_none());
+    }
+}
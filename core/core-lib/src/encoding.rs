@@ -0,0 +1,325 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+/// Pick the encoding of some text: a byte order mark wins, then the
+/// encoding the repo declares for it (`working-tree-encoding`), then
+/// UTF-16 without a BOM, valid UTF-8, and finally a statistical guess.
+pub fn detect(bytes: &[u8], declared: Option<&str>) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+    if let Some(encoding) = declared.and_then(|label| Encoding::for_label(label.as_bytes())) {
+        return encoding;
+    }
+    if let Some(encoding) = sniff_utf16(bytes) {
+        return encoding;
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return UTF_8;
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    detector.guess(None, true)
+}
+
+/// Decode text for display, dropping any byte order mark.
+pub fn decode(bytes: &[u8], declared: Option<&str>) -> (String, &'static Encoding) {
+    let encoding = detect(bytes, declared);
+    let (text, _) = encoding.decode_with_bom_removal(bytes);
+    (text.into_owned(), encoding)
+}
+
+/// Whether NUL-laden bytes are really UTF-16 text, which git's binary test
+/// would call binary: declared so in `.gitattributes`, or mostly-ASCII text
+/// with the telltale NUL in every pair (matching the BOM, if any).
+pub fn is_utf16_text(bytes: &[u8], declared: Option<&str>) -> bool {
+    let declared = declared.and_then(|label| Encoding::for_label(label.as_bytes()));
+    if declared.is_some_and(|encoding| encoding == UTF_16LE || encoding == UTF_16BE) {
+        return true;
+    }
+    match Encoding::for_bom(bytes) {
+        Some((encoding, bom_len)) => sniff_utf16(&bytes[bom_len..]) == Some(encoding),
+        None => sniff_utf16(bytes).is_some(),
+    }
+}
+
+/// BOM-less UTF-16 of mostly-ASCII text: one byte of every pair is NUL,
+/// always the same one.
+fn sniff_utf16(bytes: &[u8]) -> Option<&'static Encoding> {
+    let sample = &bytes[..bytes.len().min(8000) & !1];
+    if sample.len() < 4 {
+        return None;
+    }
+    let pairs = sample.len() / 2;
+    let even = sample.iter().step_by(2).filter(|&&b| b == 0).count();
+    let odd = sample
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .filter(|&&b| b == 0)
+        .count();
+    if even == 0 && odd * 3 >= pairs * 2 {
+        Some(UTF_16LE)
+    } else if odd == 0 && even * 3 >= pairs * 2 {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
+}
+
+/// "lf", "crlf" or "mixed" for the complete lines of some text; `None`
+/// when it has no line break at all.
+pub fn line_endings<'a>(lines: impl IntoIterator<Item = &'a [u8]>) -> Option<&'static str> {
+    let (mut lf, mut crlf) = (0, 0);
+    for line in lines {
+        match line {
+            [.., b'\r', b'\n'] => crlf += 1,
+            [.., b'\n'] => lf += 1,
+            _ => {}
+        }
+    }
+    match (lf, crlf) {
+        (0, 0) => None,
+        (_, 0) => Some("lf"),
+        (0, _) => Some("crlf"),
+        _ => Some("mixed"),
+    }
+}
+
+/// Line endings of the file a unified diff section describes, judged from
+/// its new-side lines, or the old side when the file was deleted. A line
+/// followed by "\ No newline at end of file" never had a line break of its
+/// own, so it doesn't count.
+pub fn diff_line_endings(section: &[u8]) -> Option<&'static str> {
+    let mut new_side = Vec::new();
+    let mut old_side = Vec::new();
+    let mut lines = hunk_lines(section).peekable();
+    while let Some(line) = lines.next() {
+        if lines.peek().is_some_and(|next| next.starts_with(b"\\")) {
+            continue;
+        }
+        match line.first() {
+            Some(b'-') => old_side.push(line),
+            Some(b'+') | Some(b' ') => new_side.push(line),
+            _ => {}
+        }
+    }
+    line_endings(new_side).or_else(|| line_endings(old_side))
+}
+
+/// Decode `git diff` output file by file, since every file in it can be in
+/// an encoding of its own, and side by side, since a file can change
+/// encoding (say, Latin-1 to UTF-8) in the very diff: removed lines are
+/// decoded as the old file and added lines as the new one. Returns the text
+/// and, per file section in order, the encoding of its new side, or the old
+/// side for a deleted file (`None` when there are no hunks, as for binary
+/// files and pure mode changes).
+pub fn decode_diff(bytes: &[u8]) -> (String, Vec<Option<&'static str>>) {
+    let mut text = String::new();
+    let mut encodings = Vec::new();
+    let (preamble, sections) = split_diff_sections(bytes);
+    text.push_str(&String::from_utf8_lossy(preamble));
+    for section in sections {
+        // Without git's markers, the hunk lines are the file's own bytes;
+        // context lines belong to both sides.
+        let side = |marker: u8| -> Vec<u8> {
+            hunk_lines(section)
+                .filter(|line| line.first() == Some(&marker) || line.first() == Some(&b' '))
+                .flat_map(|line| line.get(1..).unwrap_or_default())
+                .copied()
+                .collect()
+        };
+        let (old_side, new_side) = (side(b'-'), side(b'+'));
+        let detected = |content: &[u8]| (!content.is_empty()).then(|| detect(content, None));
+        let (old, new) = match (detected(&old_side), detected(&new_side)) {
+            (None, None) => {
+                text.push_str(&String::from_utf8_lossy(section));
+                encodings.push(None);
+                continue;
+            }
+            (Some(old), Some(new)) => (old, new),
+            (Some(only), None) | (None, Some(only)) => (only, only),
+        };
+
+        let mut in_hunks = false;
+        for line in section.split_inclusive(|&b| b == b'\n') {
+            in_hunks |= line.starts_with(b"@@");
+            if !in_hunks || line.starts_with(b"@@") {
+                text.push_str(&String::from_utf8_lossy(line));
+            } else if line.starts_with(b"-") {
+                text.push_str(&decode_hunk_line(line, old));
+            } else {
+                text.push_str(&decode_hunk_line(line, new));
+            }
+        }
+        let reported = if new_side.is_empty() { old } else { new };
+        encodings.push(Some(reported.name()));
+    }
+    (text, encodings)
+}
+
+/// Decode one hunk body line: the marker and the "\n" git split the line at
+/// stay as they are, and what is between them is decoded. Git splits UTF-16
+/// at the "\n" byte alone, so the NUL that went with it ends up at the start
+/// of the next line (little-endian) or the end of this one (big-endian); it
+/// is part of the line break, not the content.
+fn decode_hunk_line(line: &[u8], encoding: &'static Encoding) -> String {
+    let Some((&marker, rest)) = line.split_first() else {
+        return String::new();
+    };
+    if marker == b'\\' {
+        return String::from_utf8_lossy(line).into_owned();
+    }
+    let (mut content, newline) = match rest.strip_suffix(b"\n") {
+        Some(content) => (content, "\n"),
+        None => (rest, ""),
+    };
+    if content.len() % 2 == 1 {
+        if encoding == UTF_16LE {
+            content = content.strip_prefix(b"\0").unwrap_or(content);
+        } else if encoding == UTF_16BE {
+            content = content.strip_suffix(b"\0").unwrap_or(content);
+        }
+    }
+    let (decoded, _) = encoding.decode_with_bom_removal(content);
+    format!("{}{}{}", marker as char, decoded, newline)
+}
+
+/// Split unified diff output at each file header. The part before the first
+/// header (normally empty) comes back separately.
+pub fn split_diff_sections(bytes: &[u8]) -> (&[u8], Vec<&[u8]>) {
+    let starts: Vec<usize> = bytes
+        .split_inclusive(|&b| b == b'\n')
+        .scan(0, |offset, line| {
+            let start = *offset;
+            *offset += line.len();
+            Some((start, line))
+        })
+        .filter(|(_, line)| {
+            line.starts_with(b"diff --git ")
+                || line.starts_with(b"diff --cc ")
+                || line.starts_with(b"diff --combined ")
+        })
+        .map(|(start, _)| start)
+        .collect();
+
+    let Some(&first) = starts.first() else {
+        return (bytes, Vec::new());
+    };
+    let sections = starts
+        .iter()
+        .zip(starts.iter().skip(1).chain(std::iter::once(&bytes.len())))
+        .map(|(&start, &end)| &bytes[start..end])
+        .collect();
+    (&bytes[..first], sections)
+}
+
+/// The lines of a diff section from its first "@@" hunk header on, with the
+/// hunk headers themselves left out.
+fn hunk_lines(section: &[u8]) -> impl Iterator<Item = &[u8]> {
+    section
+        .split_inclusive(|&b| b == b'\n')
+        .skip_while(|line| !line.starts_with(b"@@"))
+        .filter(|line| !line.starts_with(b"@@"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_encodings() {
+        assert_eq!(detect(b"plain ascii\n", None), UTF_8);
+        assert_eq!(detect("caf\u{e9}\n".as_bytes(), None), UTF_8);
+        assert_eq!(detect(b"\xef\xbb\xbfbom\n", None), UTF_8);
+        assert_eq!(detect(b"\xff\xfeh\0i\0", None), UTF_16LE);
+        assert_eq!(detect(b"\xfe\xff\0h\0i", None), UTF_16BE);
+        assert_eq!(detect(b"h\0e\0l\0l\0o\0\n\0", None), UTF_16LE);
+        assert_eq!(
+            detect(b"caf\xe9 cr\xe8me br\xfbl\xe9e\n", None).name(),
+            "windows-1252"
+        );
+        assert_eq!(detect(b"caf\xe9\n", Some("SHIFT-JIS")).name(), "Shift_JIS");
+
+        assert!(is_utf16_text(b"\xff\xfeh\0i\0", None));
+        assert!(is_utf16_text(b"\xff\xfe\x42\x30", Some("UTF-16")));
+        assert!(!is_utf16_text(b"\xff\xfe\x00\x01", None));
+        assert!(!is_utf16_text(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", None));
+
+        let (text, encoding) = decode(b"\xff\xfeh\0i\0", None);
+        assert_eq!(text, "hi");
+        assert_eq!(encoding, UTF_16LE);
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let lines = |text: &'static [u8]| text.split_inclusive(|&b| b == b'\n');
+        assert_eq!(line_endings(lines(b"a\nb\n")), Some("lf"));
+        assert_eq!(line_endings(lines(b"a\r\nb\r\n")), Some("crlf"));
+        assert_eq!(line_endings(lines(b"a\r\nb\n")), Some("mixed"));
+        assert_eq!(line_endings(lines(b"no newline")), None);
+
+        // The last line has no line break of its own, so it isn't LF.
+        let section = b"diff --git a/f b/f\n--- a/f\n+++ b/f\n@@ -1 +1,2 @@\n-a\n+a\r\n+b\n\\ No newline at end of file\n";
+        assert_eq!(diff_line_endings(section), Some("crlf"));
+    }
+
+    #[test]
+    fn test_decode_diff_per_file() {
+        let mut diff =
+            b"diff --git a/utf8 b/utf8\n--- a/utf8\n+++ b/utf8\n@@ -1 +1 @@\n-x\n+caf\xc3\xa9\n"
+                .to_vec();
+        diff.extend_from_slice(b"diff --git a/bin b/bin\nBinary files a/bin and b/bin differ\n");
+        diff.extend_from_slice(b"diff --git a/latin b/latin\n--- a/latin\n+++ b/latin\n@@ -1 +1 @@\n-x\n+caf\xe9 cr\xe8me br\xfbl\xe9e\n");
+
+        let (text, encodings) = decode_diff(&diff);
+        assert!(text.contains("+caf\u{e9}\n"));
+        assert!(text.contains("+caf\u{e9} cr\u{e8}me br\u{fb}l\u{e9}e\n"));
+        assert!(text.contains("Binary files a/bin and b/bin differ"));
+        assert_eq!(encodings, vec![Some("UTF-8"), None, Some("windows-1252")]);
+    }
+
+    #[test]
+    fn test_decode_diff_sides_in_different_encodings() {
+        // A file converted from Latin-1 to UTF-8.
+        let diff = b"diff --git a/f b/f\n--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n \
+                     menu\n-caf\xe9 cr\xe8me br\xfbl\xe9e\n+caf\xc3\xa9 cr\xc3\xa8me br\xc3\xbbl\xc3\xa9e\n";
+        let (text, encodings) = decode_diff(diff);
+        assert!(text.ends_with(
+            "@@ -1,2 +1,2 @@\n menu\n\
+             -caf\u{e9} cr\u{e8}me br\u{fb}l\u{e9}e\n\
+             +caf\u{e9} cr\u{e8}me br\u{fb}l\u{e9}e\n"
+        ));
+        assert_eq!(encodings, vec![Some("UTF-8")]);
+
+        // A deleted file reports the encoding it had.
+        let diff = b"diff --git a/f b/f\ndeleted file mode 100644\n--- a/f\n+++ /dev/null\n\
+                     @@ -1 +0,0 @@\n-caf\xe9 cr\xe8me br\xfbl\xe9e\n";
+        let (text, encodings) = decode_diff(diff);
+        assert!(text.ends_with("-caf\u{e9} cr\u{e8}me br\u{fb}l\u{e9}e\n"));
+        assert_eq!(encodings, vec![Some("windows-1252")]);
+    }
+
+    #[test]
+    fn test_decode_diff_keeps_headers_of_utf16_files() {
+        // "one\ntwo\n" to "one\nzwei\n" in UTF-16BE, split at each "\n" byte
+        // as `git diff --text` does.
+        let mut diff = b"diff --git a/de.txt b/de.txt\n--- a/de.txt\n+++ b/de.txt\n".to_vec();
+        diff.extend_from_slice(b"@@ -1,2 +1,2 @@ context\n");
+        diff.extend_from_slice(b" \0o\0n\0e\0\n-\0t\0w\0o\0\n+\0z\0w\0e\0i\0\n");
+        let (text, encodings) = decode_diff(&diff);
+        assert_eq!(
+            text,
+            "diff --git a/de.txt b/de.txt\n--- a/de.txt\n+++ b/de.txt\n\
+             @@ -1,2 +1,2 @@ context\n one\n-two\n+zwei\n"
+        );
+        assert_eq!(encodings, vec![Some("UTF-16BE")]);
+
+        // Little-endian leaves the rest of each line break on the next line.
+        let mut diff = b"diff --git a/f b/f\n--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n".to_vec();
+        diff.extend_from_slice(b" o\0n\0e\0\n-\0t\0w\0o\0\n+\0z\0w\0e\0i\0\n");
+        let (text, encodings) = decode_diff(&diff);
+        assert!(text.ends_with("@@ -1,2 +1,2 @@\n one\n-two\n+zwei\n"));
+        assert_eq!(encodings, vec![Some("UTF-16LE")]);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::encoding;
 use base64::Engine as _;
 use serde::{Deserialize, Serialize};
 use std::fs;
@@ -11,9 +12,15 @@ pub struct FileEntry {
     pub is_dir: bool,
 }
 
-/// Read file contents
+/// Read file contents, decoded from whatever encoding they appear to be in
 pub fn read_file(path: &str) -> Result<String, String> {
-    fs::read_to_string(path).map_err(|e| format!("Failed to read file {}: {}", path, e))
+    read_file_with_encoding(path, None)
+}
+
+/// Read file contents, decoded from `declared` unless a byte order mark says otherwise
+pub fn read_file_with_encoding(path: &str, declared: Option<&str>) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Failed to read file {}: {}", path, e))?;
+    Ok(encoding::decode(&bytes, declared).0)
 }
 
 /// Read file contents as base64 (binary-safe)
@@ -29,7 +36,7 @@ pub fn list_files(dir: &Path, max_depth: usize) -> Result<Vec<String>, String> {
     // Try to use git ls-files for better performance and .gitignore support
     if dir.join(".git").exists() {
         let output = Command::new("git")
-            .arg("ls-files")
+            .args(["ls-files", "-z"])
             .current_dir(dir)
             .output()
             .ok();
@@ -37,8 +44,7 @@ pub fn list_files(dir: &Path, max_depth: usize) -> Result<Vec<String>, String> {
         if let Some(output) = output {
             if output.status.success() {
                 let stdout = String::from_utf8_lossy(&output.stdout);
-                for line in stdout.lines() {
-                    let path = line.trim();
+                for path in stdout.split('\0') {
                     if !path.is_empty() {
                         files.push(path.to_string());
                     }
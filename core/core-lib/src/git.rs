@@ -1,19 +1,305 @@
+use crate::diff::{parse_unified_diff, quote_path, select_patch_lines, DiffLineKind, FileDiff};
+use crate::encoding::{self, decode_diff, diff_line_endings, split_diff_sections};
+use crate::git_backend::{default_backend, GitBackend};
 use base64::Engine as _;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Output, Stdio};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct GitFile {
     pub path: String,
-    pub status: String, // "modified", "added", "deleted", "renamed"
+    pub status: String, // "modified", "added", "deleted", "renamed", "copied"
+    pub old_path: Option<String>, // source path for renames and copies
+    pub similarity: Option<u8>, // rename/copy similarity percentage
+    pub additions: u32,
+    pub deletions: u32,
+    pub is_binary: bool,
+    pub old_mode: Option<String>,      // e.g. "100644"; None when added
+    pub new_mode: Option<String>,      // None when deleted
+    pub file_type: Option<String>,     // "regular", "executable", "symlink", "submodule"
+    pub old_file_type: Option<String>, // differs from file_type on a type change
+    pub submodule: Option<SubmoduleChange>, // set when file_type is "submodule"
+    pub too_large: Option<u64>, // size in bytes of an untracked file whose content was left out
+    pub encoding: Option<String>, // e.g. "UTF-8", "windows-1252", "UTF-16LE"; None without text hunks
+    pub line_endings: Option<String>, // "lf", "crlf" or "mixed"
+}
+
+/// Where a submodule (or nested repository) pointer moved.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubmoduleChange {
+    pub old_commit: Option<String>, // None when the submodule was added
+    pub new_commit: Option<String>, // None when it was removed
+    pub dirty: bool,                // its worktree has uncommitted changes
+}
+
+impl SubmoduleChange {
+    /// Read the "Subproject commit <hash>" lines git writes for a gitlink.
+    fn from_file_diff(file_diff: &FileDiff) -> Self {
+        let mut change = SubmoduleChange::default();
+        for line in file_diff.hunks.iter().flat_map(|hunk| &hunk.lines) {
+            let Some(commit) = line.content.strip_prefix("Subproject commit ") else {
+                continue;
+            };
+            let commit = match commit.strip_suffix("-dirty") {
+                Some(commit) => {
+                    change.dirty = true;
+                    commit
+                }
+                None => commit,
+            };
+            match line.kind {
+                DiffLineKind::Removed => change.old_commit = Some(commit.to_string()),
+                DiffLineKind::Added => change.new_commit = Some(commit.to_string()),
+                DiffLineKind::Context => {}
+            }
+        }
+        change
+    }
+}
+
+/// What kind of entry a git tree mode describes.
+fn file_type_for_mode(mode: &str) -> &'static str {
+    match mode {
+        "100755" => "executable",
+        "120000" => "symlink",
+        "160000" => "submodule",
+        _ => "regular",
+    }
+}
+
+/// Which whitespace differences `git diff` should ignore.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WhitespaceMode {
+    #[default]
+    None,
+    AtEol,  // --ignore-space-at-eol
+    Change, // -b / --ignore-space-change
+    All,    // -w / --ignore-all-space
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffAlgorithm {
+    #[default]
+    Default, // whatever diff.algorithm is configured to (myers otherwise)
+    Myers,
+    Minimal,
+    Patience,
+    Histogram,
+}
+
+/// How to diff a merge commit. Ignored for commits with a single parent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "kebab-case")]
+pub enum MergeDiff {
+    /// Changes the merge brought into the first parent (the branch merged into).
+    #[default]
+    FirstParent,
+    /// Changes relative to one parent, numbered from 1 as in `<hash>^<n>`.
+    Parent { parent: usize },
+    /// `git show --cc`: only hunks that differ from every parent.
+    Combined,
+    /// `git show --remerge-diff`: how conflicts were resolved compared to an
+    /// automatic re-merge of the parents.
+    Remerge,
+}
+
+/// Options shared by every diff entry point. Deserializes from the sidecar's
+/// camelCase params, with every field optional.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DiffOptions {
+    /// Minimum similarity (0-100) for rename/copy detection; `None` disables it.
+    pub rename_threshold: Option<u8>,
+    /// Also look for copies (`--find-copies`); off by default, as in git.
+    pub find_copies: bool,
+    pub ignore_whitespace: WhitespaceMode,
+    pub ignore_blank_lines: bool,
+    /// Lines of context around each hunk (`-U<n>`); `None` keeps git's default of 3.
+    pub context_lines: Option<u32>,
+    pub algorithm: DiffAlgorithm,
+    /// Show the whole enclosing function as context (`--function-context`).
+    pub function_context: bool,
+    pub merge_diff: MergeDiff,
+    /// Leave submodule and nested repository changes out entirely.
+    pub ignore_submodules: bool,
+    /// Largest untracked file, in bytes, whose content is shown; bigger ones
+    /// get a placeholder. `None` shows everything.
+    pub untracked_file_limit: Option<u64>,
+    /// Total bytes of untracked content to read per diff; files past the
+    /// budget get placeholders too. `None` reads everything.
+    pub untracked_total_limit: Option<u64>,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            rename_threshold: Some(50),
+            find_copies: false,
+            ignore_whitespace: WhitespaceMode::None,
+            ignore_blank_lines: false,
+            context_lines: None,
+            algorithm: DiffAlgorithm::Default,
+            function_context: false,
+            merge_diff: MergeDiff::FirstParent,
+            ignore_submodules: false,
+            untracked_file_limit: Some(2 * 1024 * 1024),
+            untracked_total_limit: Some(32 * 1024 * 1024),
+        }
+    }
+}
+
+impl DiffOptions {
+    /// Flags for `git diff`, `git show` and `git diff-tree`.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+
+        match self.ignore_whitespace {
+            WhitespaceMode::None => {}
+            WhitespaceMode::AtEol => args.push("--ignore-space-at-eol".to_string()),
+            WhitespaceMode::Change => args.push("--ignore-space-change".to_string()),
+            WhitespaceMode::All => args.push("--ignore-all-space".to_string()),
+        }
+        if self.ignore_blank_lines {
+            args.push("--ignore-blank-lines".to_string());
+        }
+        if let Some(lines) = self.context_lines {
+            args.push(format!("--unified={}", lines));
+        }
+        match self.algorithm {
+            DiffAlgorithm::Default => {}
+            DiffAlgorithm::Myers => args.push("--diff-algorithm=myers".to_string()),
+            DiffAlgorithm::Minimal => args.push("--diff-algorithm=minimal".to_string()),
+            DiffAlgorithm::Patience => args.push("--diff-algorithm=patience".to_string()),
+            DiffAlgorithm::Histogram => args.push("--diff-algorithm=histogram".to_string()),
+        }
+        if self.function_context {
+            args.push("--function-context".to_string());
+        }
+
+        match self.rename_threshold {
+            Some(threshold) => {
+                let threshold = threshold.min(100);
+                args.push(format!("--find-renames={}%", threshold));
+                if self.find_copies {
+                    args.push(format!("--find-copies={}%", threshold));
+                }
+            }
+            None => args.push("--no-renames".to_string()),
+        }
+
+        // Pin the "Subproject commit" form so a diff.submodule setting of
+        // "log" or "diff" can't change what the parser sees.
+        args.push("--submodule=short".to_string());
+        if self.ignore_submodules {
+            args.push("--ignore-submodules=all".to_string());
+        }
+        args
+    }
+
+    /// Flags for `git status`, which only understands rename detection and
+    /// submodule filtering.
+    fn status_args(&self) -> Vec<String> {
+        let mut args = match self.rename_threshold {
+            Some(threshold) => vec![format!("--find-renames={}%", threshold.min(100))],
+            None => vec!["--no-renames".to_string()],
+        };
+        if self.ignore_submodules {
+            args.push("--ignore-submodules=all".to_string());
+        }
+        args
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GitDiffResult {
     pub diff: String,
     pub files: Vec<GitFile>,
+    #[serde(default)]
+    pub file_diffs: Vec<FileDiff>, // `diff` parsed into typed per-file records
+}
+
+impl GitDiffResult {
+    /// Build a result from raw unified diff text, parsing it into `file_diffs`
+    /// and filling in each file's line statistics, modes and line endings from
+    /// the parsed hunks and headers.
+    pub fn new(diff: String, mut files: Vec<GitFile>) -> Self {
+        let file_diffs = parse_unified_diff(&diff);
+        let (_, sections) = split_diff_sections(diff.as_bytes());
+        let line_endings: HashMap<&str, &str> = file_diffs
+            .iter()
+            .zip(&sections)
+            .filter_map(|(fd, section)| Some((fd.path(), diff_line_endings(section)?)))
+            .collect();
+
+        // A type change (say, file to symlink) is a deletion and an addition
+        // of the same path, so a path can have two records.
+        let mut by_path: HashMap<&str, Vec<&FileDiff>> = HashMap::new();
+        for fd in &file_diffs {
+            by_path.entry(fd.path()).or_default().push(fd);
+        }
+        for file in &mut files {
+            let Some(records) = by_path.get(file.path.as_str()) else {
+                continue;
+            };
+            file.additions = 0;
+            file.deletions = 0;
+            for file_diff in records {
+                let (additions, deletions) = file_diff.line_counts();
+                file.additions += additions;
+                file.deletions += deletions;
+                file.is_binary |= file_diff.is_binary;
+            }
+            file.old_mode = records.iter().find_map(|fd| fd.old_mode.clone());
+            file.new_mode = records.iter().rev().find_map(|fd| fd.new_mode.clone());
+            file.old_file_type = file
+                .old_mode
+                .as_deref()
+                .map(|mode| file_type_for_mode(mode).to_string());
+            file.file_type = file
+                .new_mode
+                .as_deref()
+                .map(|mode| file_type_for_mode(mode).to_string())
+                .or_else(|| file.old_file_type.clone());
+            if file.file_type.as_deref() == Some("submodule")
+                || file.old_file_type.as_deref() == Some("submodule")
+            {
+                let is_gitlink = |mode: &Option<String>| mode.as_deref() == Some("160000");
+                file.submodule = records
+                    .iter()
+                    .find(|fd| is_gitlink(&fd.old_mode) || is_gitlink(&fd.new_mode))
+                    .map(|fd| SubmoduleChange::from_file_diff(fd));
+            }
+            file.line_endings = line_endings
+                .get(file.path.as_str())
+                .map(|style| style.to_string());
+        }
+
+        GitDiffResult {
+            diff,
+            files,
+            file_diffs,
+        }
+    }
+
+    /// Record the encoding `decode_diff` found for each file section, in the
+    /// same order as `file_diffs`.
+    pub fn with_encodings(mut self, encodings: &[Option<&str>]) -> Self {
+        for (fd, encoding) in self.file_diffs.iter().zip(encodings) {
+            let Some(encoding) = encoding else {
+                continue;
+            };
+            if let Some(file) = self.files.iter_mut().find(|f| f.path == fd.path()) {
+                file.encoding = Some(encoding.to_string());
+            }
+        }
+        self
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -22,23 +308,114 @@ pub struct GitChangeStatus {
     pub has_unstaged: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct CommitInfo {
-    pub hash: String,       // full hash
-    pub short_hash: String, // short hash
-    pub message: String,    // commit message
-    pub author: String,     // author name
-    pub date: String,       // relative date
-    pub refs: String,       // branch/tag refs
+    pub hash: String,         // full hash
+    pub short_hash: String,   // short hash
+    pub message: String,      // commit message
+    pub author: String,       // author name
+    pub date: String,         // relative date
+    pub refs: String,         // branch/tag refs
+    pub author_email: String, // author email
+    pub date_iso: String,     // author date, strict ISO 8601
+    pub timestamp: i64,       // author date, unix seconds
+    pub parents: Vec<String>, // parent hashes
+    pub body: String,         // message after the subject line
+    pub trailers: Vec<CommitTrailer>,
+    pub signature: String, // "none", "good", "bad", "untrusted", "expired", "revoked", "unverifiable"
+}
+
+/// A "Key: value" trailer such as "Signed-off-by: ...".
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitTrailer {
+    pub key: String,
+    pub value: String,
+}
+
+/// Paging and filters for `list_commits`. Deserializes from the sidecar's
+/// camelCase params, with every field optional.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LogOptions {
+    /// Revision range to walk, e.g. "v1.0..v1.1"; defaults to HEAD.
+    pub range: Option<String>,
+    pub skip: u32,
+    pub limit: Option<u32>,
+    /// Author name or email pattern (`--author`).
+    pub author: Option<String>,
+    /// Only commits touching this file or directory.
+    pub file_path: Option<String>,
+    /// Commit message pattern (`--grep`).
+    pub grep: Option<String>,
+    /// Date bounds in any format git accepts, e.g. "2024-01-31" or "2 weeks ago".
+    pub since: Option<String>,
+    pub until: Option<String>,
+    /// Check GPG/SSH signatures (`%G?`). This runs the verifier once per
+    /// signed commit, so it is off unless asked for and `signature` is "none".
+    pub verify_signatures: bool,
+}
+
+impl LogOptions {
+    fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.skip > 0 {
+            args.push(format!("--skip={}", self.skip));
+        }
+        if let Some(limit) = self.limit {
+            args.push(format!("--max-count={}", limit));
+        }
+        if let Some(author) = &self.author {
+            args.push(format!("--author={}", author));
+        }
+        if let Some(grep) = &self.grep {
+            args.push(format!("--grep={}", grep));
+        }
+        if let Some(since) = &self.since {
+            args.push(format!("--since={}", since));
+        }
+        if let Some(until) = &self.until {
+            args.push(format!("--until={}", until));
+        }
+        args
+    }
 }
 
+/// `git log` format read by `parse_commit_record`. Each record starts with
+/// "\x1e" and fields are "\x1f"-separated; the free-form body comes last.
+/// The signature field is a constant "N" (no signature); see
+/// `SIGNED_COMMIT_LOG_FORMAT`.
+const COMMIT_LOG_FORMAT: &str = "--format=%x1e%H%x1f%h%x1f%s%x1f%an%x1f%ar%x1f%D%x1f%ae%x1f%aI%x1f%at%x1f%P%x1fN%x1f%(trailers:only,unfold)%x1f%b";
+
+/// `COMMIT_LOG_FORMAT` with signatures verified (`%G?`).
+const SIGNED_COMMIT_LOG_FORMAT: &str = "--format=%x1e%H%x1f%h%x1f%s%x1f%an%x1f%ar%x1f%D%x1f%ae%x1f%aI%x1f%at%x1f%P%x1f%G?%x1f%(trailers:only,unfold)%x1f%b";
+
+/// One commit in a file's history, with the path the file had in it.
 #[derive(Debug, Serialize, Deserialize)]
+pub struct FileHistoryEntry {
+    #[serde(flatten)]
+    pub commit: CommitInfo,
+    pub path: String,
+    pub status: String, // how this commit changed the file: "modified", "added", "renamed", ...
+    pub old_path: Option<String>, // previous path when this commit renamed or copied it
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct BranchInfo {
     pub name: String,
     pub short_hash: String,
     pub subject: String,
     pub author: String,
     pub date: String,
+    pub timestamp: i64,           // committer date, unix seconds
+    pub upstream: Option<String>, // e.g. "origin/feature"
+    pub ahead_upstream: Option<u32>,
+    pub behind_upstream: Option<u32>,
+    pub base: Option<String>, // base branch it is compared against, see `resolve_branch_base`
+    pub ahead_base: Option<u32>,
+    pub behind_base: Option<u32>,
+    pub is_local: bool,
+    pub is_remote: bool,
+    pub is_current: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -77,43 +454,21 @@ pub fn is_git_repo(dir: &Path) -> bool {
 /// Get the git root directory
 #[allow(dead_code)]
 pub fn get_git_root(dir: &Path) -> Option<PathBuf> {
-    let output = Command::new("git")
-        .arg("rev-parse")
-        .arg("--show-toplevel")
-        .current_dir(dir)
-        .output()
-        .ok()?;
-
-    if output.status.success() {
-        let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        Some(PathBuf::from(path))
-    } else {
-        None
-    }
+    default_backend().toplevel(dir)
 }
 
 /// Get the Unix timestamp of the most recent commit in a repo.
 /// Returns 0 if the repo has no commits or on error.
 pub fn last_commit_timestamp(dir: &Path) -> i64 {
-    let output = Command::new("git")
-        .args(["log", "-1", "--format=%ct"])
-        .current_dir(dir)
-        .output();
-
-    match output {
-        Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout)
-            .trim()
-            .parse::<i64>()
-            .unwrap_or(0),
-        _ => 0,
-    }
+    default_backend().head_commit_time(dir).unwrap_or(0)
 }
 
 /// Get unstaged changes
-pub fn get_unstaged_diff(dir: &Path) -> Result<GitDiffResult, String> {
+pub fn get_unstaged_diff(dir: &Path, opts: &DiffOptions) -> Result<GitDiffResult, String> {
     let diff_output = Command::new("git")
         .arg("diff")
         .arg("--no-color")
+        .args(opts.to_args())
         .current_dir(dir)
         .output()
         .map_err(|e| format!("Failed to execute git diff: {}", e))?;
@@ -122,28 +477,65 @@ pub fn get_unstaged_diff(dir: &Path) -> Result<GitDiffResult, String> {
         return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
     }
 
-    let mut diff = String::from_utf8_lossy(&diff_output.stdout).to_string();
-    let files = get_changed_files(dir, false)?;
-
-    let untracked_files = get_untracked_files(dir)?;
+    let (mut diff, mut encodings) = decode_diff(&diff_output.stdout);
+    let mut files = get_changed_files(dir, false, opts)?;
+
+    let mut untracked_files = get_untracked_files(dir)?;
+    if opts.ignore_submodules {
+        let nested: Vec<String> = untracked_files
+            .iter()
+            .filter(|file| is_nested_repo(&dir.join(file)))
+            .cloned()
+            .collect();
+        untracked_files.retain(|file| !nested.contains(file));
+        files.retain(|file| !nested.contains(&file.path));
+    }
 
-    let synthetic_diff = generate_untracked_files_diff(dir, &untracked_files)?;
-    if !synthetic_diff.is_empty() {
+    // Intent-to-add files (`git add -N`) are in the index, so `git diff`
+    // already shows them as new; never synthesize a second entry.
+    let in_diff: Vec<String> = parse_unified_diff(&diff)
+        .iter()
+        .map(|fd| fd.path().to_string())
+        .collect();
+    untracked_files.retain(|file| !in_diff.contains(file));
+
+    let declared = working_tree_encodings(dir, files.iter().map(|f| f.path.as_str()))?;
+    let untracked = generate_untracked_files_diff(dir, &untracked_files, opts)?;
+    if !untracked.diff.is_empty() {
         if !diff.is_empty() && !diff.ends_with('\n') {
             diff.push('\n');
         }
-        diff.push_str(&synthetic_diff);
+        diff.push_str(&untracked.diff);
     }
-
-    Ok(GitDiffResult { diff, files })
+    encodings.extend(untracked.encodings);
+
+    let mut result = GitDiffResult::new(diff, files).with_encodings(&encodings);
+    for file in &mut result.files {
+        if let Some((_, size)) = untracked
+            .too_large
+            .iter()
+            .find(|(path, _)| *path == file.path)
+        {
+            file.too_large = Some(*size);
+        }
+        // Git diffs a `working-tree-encoding` file in its UTF-8 repo form,
+        // but the file on disk is in the declared encoding.
+        if let (Some(_), Some(label)) = (&file.encoding, declared.get(&file.path)) {
+            if let Some(encoding) = encoding_rs::Encoding::for_label(label.as_bytes()) {
+                file.encoding = Some(encoding.name().to_string());
+            }
+        }
+    }
+    Ok(result)
 }
 
 /// Get staged changes
-pub fn get_staged_diff(dir: &Path) -> Result<GitDiffResult, String> {
+pub fn get_staged_diff(dir: &Path, opts: &DiffOptions) -> Result<GitDiffResult, String> {
     let diff_output = Command::new("git")
         .arg("diff")
         .arg("--staged")
         .arg("--no-color")
+        .args(opts.to_args())
         .current_dir(dir)
         .output()
         .map_err(|e| format!("Failed to execute git diff --staged: {}", e))?;
@@ -152,14 +544,14 @@ pub fn get_staged_diff(dir: &Path) -> Result<GitDiffResult, String> {
         return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
     }
 
-    let diff = String::from_utf8_lossy(&diff_output.stdout).to_string();
-    let files = get_changed_files(dir, true)?;
+    let (diff, encodings) = decode_diff(&diff_output.stdout);
+    let files = get_changed_files(dir, true, opts)?;
 
-    Ok(GitDiffResult { diff, files })
+    Ok(GitDiffResult::new(diff, files).with_encodings(&encodings))
 }
 
 /// Get diff against HEAD~N
-pub fn get_head_diff(dir: &Path, n: u32) -> Result<GitDiffResult, String> {
+pub fn get_head_diff(dir: &Path, n: u32, opts: &DiffOptions) -> Result<GitDiffResult, String> {
     let ref_spec = if n == 0 {
         "HEAD".to_string()
     } else {
@@ -170,6 +562,7 @@ pub fn get_head_diff(dir: &Path, n: u32) -> Result<GitDiffResult, String> {
         .arg("diff")
         .arg(&ref_spec)
         .arg("--no-color")
+        .args(opts.to_args())
         .current_dir(dir)
         .output()
         .map_err(|e| format!("Failed to execute git diff {}: {}", ref_spec, e))?;
@@ -178,28 +571,35 @@ pub fn get_head_diff(dir: &Path, n: u32) -> Result<GitDiffResult, String> {
         return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
     }
 
-    let diff = String::from_utf8_lossy(&diff_output.stdout).to_string();
+    let (diff, encodings) = decode_diff(&diff_output.stdout);
 
     // Get files changed in that commit
     let files_output = Command::new("git")
         .arg("diff-tree")
         .arg("--no-commit-id")
         .arg("--name-status")
+        .arg("-z")
         .arg("-r")
+        .args(opts.to_args())
         .arg(&ref_spec)
         .current_dir(dir)
         .output()
         .map_err(|e| format!("Failed to get changed files: {}", e))?;
 
+    if !files_output.status.success() {
+        return Err(String::from_utf8_lossy(&files_output.stderr).to_string());
+    }
+
     let files = parse_file_status(&String::from_utf8_lossy(&files_output.stdout));
 
-    Ok(GitDiffResult { diff, files })
+    Ok(GitDiffResult::new(diff, files).with_encodings(&encodings))
 }
 
 /// Get list of changed files
-fn get_changed_files(dir: &Path, staged: bool) -> Result<Vec<GitFile>, String> {
+fn get_changed_files(dir: &Path, staged: bool, opts: &DiffOptions) -> Result<Vec<GitFile>, String> {
     let output = Command::new("git")
-        .args(["status", "--porcelain", "-uall"])
+        .args(["status", "--porcelain=v2", "-z", "-uall"])
+        .args(opts.status_args())
         .current_dir(dir)
         .output()
         .map_err(|e| format!("Failed to get changed files: {}", e))?;
@@ -212,25 +612,72 @@ fn get_changed_files(dir: &Path, staged: bool) -> Result<Vec<GitFile>, String> {
     Ok(parse_porcelain_status(&stdout, staged))
 }
 
-fn has_staged_changes(porcelain_output: &str) -> bool {
-    porcelain_output.lines().any(|line| {
-        if line.len() < 3 {
-            return false;
+/// One record of `git status --porcelain=v2 -z`.
+#[derive(Debug, PartialEq, Eq)]
+struct StatusEntry {
+    index: char,    // X, with v2's "." for unchanged mapped back to ' '
+    worktree: char, // Y, likewise
+    path: String,
+    orig_path: Option<String>, // source of a rename or copy
+}
+
+/// Parse `git status --porcelain=v2 -z`. Paths are never quoted, and a
+/// rename's source path is its own NUL-terminated field, so any byte but NUL
+/// can appear in a file name. Ignored ("!") and header ("#") records are
+/// skipped.
+fn parse_status_v2(output: &str) -> Vec<StatusEntry> {
+    let mut entries = Vec::new();
+    let mut records = output.split('\0');
+
+    while let Some(record) = records.next() {
+        let Some((kind, rest)) = record.split_once(' ') else {
+            continue;
+        };
+        // Fields before the path: "1" has XY sub mH mI mW hH hI, "2" adds a
+        // rename score, "u" has three stage modes and hashes.
+        let (field_count, orig_path) = match kind {
+            "?" => {
+                entries.push(StatusEntry {
+                    index: '?',
+                    worktree: '?',
+                    // Nested repositories are listed as "dir/".
+                    path: rest.trim_end_matches('/').to_string(),
+                    orig_path: None,
+                });
+                continue;
+            }
+            "1" => (8, None),
+            "2" => (9, records.next().map(str::to_string)),
+            "u" => (10, None),
+            _ => continue,
+        };
+
+        let fields: Vec<&str> = rest.splitn(field_count, ' ').collect();
+        if fields.len() < field_count {
+            continue;
         }
-        let index_status = line.chars().next().unwrap_or(' ');
-        index_status != ' ' && index_status != '?'
-    })
+        let mut xy = fields[0].chars().map(|c| if c == '.' { ' ' } else { c });
+        entries.push(StatusEntry {
+            index: xy.next().unwrap_or(' '),
+            worktree: xy.next().unwrap_or(' '),
+            path: fields[field_count - 1].to_string(),
+            orig_path,
+        });
+    }
+
+    entries
+}
+
+fn has_staged_changes(porcelain_output: &str) -> bool {
+    parse_status_v2(porcelain_output)
+        .iter()
+        .any(|entry| entry.index != ' ' && entry.index != '?')
 }
 
 fn has_unstaged_changes(porcelain_output: &str) -> bool {
-    porcelain_output.lines().any(|line| {
-        if line.len() < 3 {
-            return false;
-        }
-        let index_status = line.chars().next().unwrap_or(' ');
-        let worktree_status = line.chars().nth(1).unwrap_or(' ');
-        worktree_status != ' ' || (index_status == '?' && worktree_status == '?')
-    })
+    parse_status_v2(porcelain_output)
+        .iter()
+        .any(|entry| entry.worktree != ' ' || entry.index == '?')
 }
 
 fn get_change_status_from_porcelain(porcelain_output: &str) -> GitChangeStatus {
@@ -242,7 +689,7 @@ fn get_change_status_from_porcelain(porcelain_output: &str) -> GitChangeStatus {
 
 pub fn get_git_change_status(dir: &Path) -> Result<GitChangeStatus, String> {
     let output = Command::new("git")
-        .args(["status", "--porcelain"])
+        .args(["status", "--porcelain=v2", "-z"])
         .current_dir(dir)
         .output()
         .map_err(|e| format!("Failed to get git change status: {}", e))?;
@@ -257,7 +704,7 @@ pub fn get_git_change_status(dir: &Path) -> Result<GitChangeStatus, String> {
 
 fn get_untracked_files(dir: &Path) -> Result<Vec<String>, String> {
     let output = Command::new("git")
-        .args(["ls-files", "--others", "--exclude-standard"])
+        .args(["ls-files", "-z", "--others", "--exclude-standard"])
         .current_dir(dir)
         .output()
         .map_err(|e| format!("Failed to list untracked files: {}", e))?;
@@ -266,98 +713,296 @@ fn get_untracked_files(dir: &Path) -> Result<Vec<String>, String> {
         return Err(String::from_utf8_lossy(&output.stderr).to_string());
     }
 
+    // Nested repositories are listed as "dir/"; report them by their path.
     Ok(String::from_utf8_lossy(&output.stdout)
-        .lines()
-        .filter(|line| !line.trim().is_empty())
-        .map(|line| line.to_string())
+        .split('\0')
+        .filter(|path| !path.is_empty())
+        .map(|path| path.trim_end_matches('/').to_string())
+        .collect())
+}
+
+/// The `working-tree-encoding` attribute of each path that has one.
+fn working_tree_encodings<'a>(
+    dir: &Path,
+    paths: impl IntoIterator<Item = &'a str>,
+) -> Result<HashMap<String, String>, String> {
+    let mut input = Vec::new();
+    for path in paths {
+        input.extend_from_slice(path.as_bytes());
+        input.push(0);
+    }
+    if input.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let output = git_with_input(
+        dir,
+        &["check-attr", "-z", "--stdin", "working-tree-encoding"],
+        input,
+    )?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+
+    // Records are "<path>\0<attribute>\0<value>\0".
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = stdout.split('\0').collect();
+    Ok(fields
+        .chunks_exact(3)
+        .filter(|record| !matches!(record[2], "unspecified" | "unset" | "set"))
+        .map(|record| (record[0].to_string(), record[2].to_string()))
         .collect())
 }
 
-fn generate_untracked_files_diff(dir: &Path, files: &[String]) -> Result<String, String> {
+/// Run git with `input` on its stdin. Written from a thread of its own so a
+/// command that answers as it reads can't fill its stdout and stall.
+fn git_with_input(dir: &Path, args: &[&str], input: Vec<u8>) -> Result<Output, String> {
+    let mut child = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to execute git {}: {}", args[0], e))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to execute git {}: {}", args[0], e))?;
+    writer
+        .join()
+        .expect("stdin writer panicked")
+        .map_err(|e| format!("Failed to write to git {}: {}", args[0], e))?;
+    Ok(output)
+}
+
+/// Read a worktree file for display, decoding it from the encoding declared
+/// in `.gitattributes` or, failing that, the one it appears to be in.
+pub fn read_worktree_file(dir: &Path, file_path: &str) -> Result<String, String> {
+    let declared = working_tree_encodings(dir, [file_path]).unwrap_or_default();
+    crate::files::read_file_with_encoding(
+        &dir.join(file_path).to_string_lossy(),
+        declared.get(file_path).map(String::as_str),
+    )
+}
+
+/// A directory inside the worktree that is its own git repository.
+fn is_nested_repo(path: &Path) -> bool {
+    path.is_dir() && path.join(".git").exists()
+}
+
+/// New-file diffs for untracked files, which git has no diff of its own for.
+struct UntrackedDiff {
+    diff: String,
+    encodings: Vec<Option<&'static str>>, // per file section, as `decode_diff` reports them
+    too_large: Vec<(String, u64)>,        // (path, size) of each file given a placeholder
+}
+
+/// Build new-file diffs for untracked files, leaving out the content of files
+/// over the size limits.
+fn generate_untracked_files_diff(
+    dir: &Path,
+    files: &[String],
+    opts: &DiffOptions,
+) -> Result<UntrackedDiff, String> {
     let mut diff = String::new();
+    let mut encodings = Vec::new();
+    let mut too_large = Vec::new();
+    let declared = working_tree_encodings(dir, files.iter().map(String::as_str))?;
+    let mut budget = opts.untracked_total_limit;
+    let track_exec_bit = default_backend()
+        .config_value(dir, "core.fileMode")
+        .is_none_or(|value| value != "false");
 
     for file in files {
         let path = dir.join(file);
 
         // Only synthetic-diff files that are untracked and exist in worktree.
-        if !path.is_file() {
+        let Ok(meta) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+
+        let (mode, bytes) = if meta.file_type().is_symlink() {
+            // Git stores a symlink as its target, not what it points to.
+            let target = fs::read_link(&path)
+                .map_err(|e| format!("Failed to read symlink '{}': {}", file, e))?;
+            ("120000", target.to_string_lossy().into_owned().into_bytes())
+        } else if is_nested_repo(&path) {
+            // What `git add` would record: a gitlink to the nested repo's HEAD.
+            if let Ok(head) = default_backend().rev_parse(&path, "HEAD") {
+                diff.push_str(&build_new_gitlink_diff(file, &head));
+                encodings.push(None);
+            }
+            continue;
+        } else if meta.is_file() {
+            let mode = if track_exec_bit && is_executable(&meta) {
+                "100755"
+            } else {
+                "100644"
+            };
+
+            // Check the size before reading so a huge file is never loaded.
+            let size = meta.len();
+            if !within_untracked_limits(size, &mut budget, opts) {
+                diff.push_str(&build_too_large_file_diff(file, mode, size));
+                encodings.push(None);
+                too_large.push((file.clone(), size));
+                continue;
+            }
+
+            let bytes = fs::read(&path)
+                .map_err(|e| format!("Failed to read untracked file '{}': {}", file, e))?;
+            (mode, bytes)
+        } else {
             continue;
+        };
+
+        // UTF-16 is full of NULs but still text.
+        let declared = declared.get(file).map(String::as_str);
+        if looks_binary(&bytes) && !encoding::is_utf16_text(&bytes, declared) {
+            diff.push_str(&build_binary_file_diff(file, mode));
+            encodings.push(None);
+        } else {
+            let (content, detected) = encoding::decode(&bytes, declared);
+            diff.push_str(&build_new_file_diff(file, mode, &content));
+            encodings.push(Some(detected.name()));
         }
+    }
+
+    Ok(UntrackedDiff {
+        diff,
+        encodings,
+        too_large,
+    })
+}
 
-        let bytes = fs::read(&path)
-            .map_err(|e| format!("Failed to read untracked file '{}': {}", file, e))?;
+/// Whether an untracked file of `size` bytes is within the limits of `opts`,
+/// taking it out of the remaining total `budget` if so.
+fn within_untracked_limits(size: u64, budget: &mut Option<u64>, opts: &DiffOptions) -> bool {
+    let over_file_limit = opts.untracked_file_limit.is_some_and(|limit| size > limit);
+    let over_budget = budget.is_some_and(|left| size > left);
+    if over_file_limit || over_budget {
+        return false;
+    }
+    *budget = budget.map(|left| left - size);
+    true
+}
 
-        match String::from_utf8(bytes) {
-            Ok(content) => diff.push_str(&build_new_file_diff(file, &content)),
-            Err(_) => diff.push_str(&build_binary_file_diff(file)),
+/// Untracked regular files a diff with `opts` would show a placeholder for.
+fn oversized_untracked_files(dir: &Path, opts: &DiffOptions) -> Result<Vec<String>, String> {
+    let mut budget = opts.untracked_total_limit;
+    let mut oversized = Vec::new();
+    for file in get_untracked_files(dir)? {
+        let Ok(meta) = fs::symlink_metadata(dir.join(&file)) else {
+            continue;
+        };
+        if meta.is_file() && !within_untracked_limits(meta.len(), &mut budget, opts) {
+            oversized.push(file);
         }
     }
+    Ok(oversized)
+}
+
+/// Git's own test, a NUL in the first 8000 bytes, plus a share of other
+/// control characters no text file has. Text that merely isn't UTF-8 (say,
+/// Latin-1) is still text.
+fn looks_binary(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(8000)];
+    if sample.contains(&0) {
+        return true;
+    }
+    let control = sample
+        .iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\n' | b'\r' | b'\t' | 0x0c | 0x1b | 0x08))
+        .count();
+    control * 10 > sample.len()
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_meta: &fs::Metadata) -> bool {
+    false
+}
 
-    Ok(diff)
+/// The "a/" and "b/" header paths of a synthetic diff, quoted like git's.
+fn diff_header_paths(file_path: &str) -> (String, String) {
+    (
+        quote_path(&format!("a/{}", file_path)),
+        quote_path(&format!("b/{}", file_path)),
+    )
 }
 
-fn build_binary_file_diff(file_path: &str) -> String {
+fn build_binary_file_diff(file_path: &str, mode: &str) -> String {
+    let (a, b) = diff_header_paths(file_path);
     format!(
-        "diff --git a/{0} b/{0}\nnew file mode 100644\nBinary file {0} added\n",
-        file_path
+        "diff --git {} {}\nnew file mode {}\nBinary file {} added\n",
+        a, b, mode, file_path
     )
 }
 
-fn build_new_file_diff(file_path: &str, content: &str) -> String {
-    let normalized_content = content.replace("\r\n", "\n");
-    let mut lines: Vec<&str> = normalized_content.lines().collect();
+fn build_too_large_file_diff(file_path: &str, mode: &str, size: u64) -> String {
+    let (a, b) = diff_header_paths(file_path);
+    format!(
+        "diff --git {} {}\nnew file mode {}\nFile {} added, too large to show ({} bytes)\n",
+        a, b, mode, file_path, size
+    )
+}
 
-    // Preserve trailing newline by adding an empty line entry so the last
-    // line still appears in unified format.
-    if normalized_content.ends_with('\n') {
-        lines.push("");
-    }
+fn build_new_gitlink_diff(file_path: &str, commit: &str) -> String {
+    let (a, b) = diff_header_paths(file_path);
+    format!(
+        "diff --git {0} {1}\nnew file mode 160000\n--- /dev/null\n+++ {1}\n@@ -0,0 +1 @@\n+Subproject commit {2}\n",
+        a, b, commit
+    )
+}
 
+fn build_new_file_diff(file_path: &str, mode: &str, content: &str) -> String {
+    // Carriage returns stay, as in git's own output, so line endings can be
+    // told apart.
+    let lines: Vec<&str> = content.split_terminator('\n').collect();
     let line_count = lines.len();
 
+    let (a, b) = diff_header_paths(file_path);
     let mut diff = String::new();
-    diff.push_str(&format!("diff --git a/{0} b/{0}\n", file_path));
-    diff.push_str("new file mode 100644\n");
+    diff.push_str(&format!("diff --git {} {}\n", a, b));
+    diff.push_str(&format!("new file mode {}\n", mode));
+    // Like git, an empty file gets no hunk at all.
+    if content.is_empty() {
+        return diff;
+    }
     diff.push_str("--- /dev/null\n");
-    diff.push_str(&format!("+++ b/{}\n", file_path));
+    diff.push_str(&format!("+++ {}\n", b));
     diff.push_str(&format!("@@ -0,0 +1,{} @@\n", line_count));
 
     for line in lines {
         diff.push_str(&format!("+{}\n", line));
     }
+    if !content.ends_with('\n') {
+        diff.push_str("\\ No newline at end of file\n");
+    }
 
     diff
 }
 
-/// Parse git status --porcelain output
-/// Format: XY filename where X=index status, Y=working tree status
+/// Parse `git status --porcelain=v2 -z` output into changed files.
 /// If staged=true, only return files with X in {M, A, D, R, C} (not ' ' or '?')
 /// If staged=false, return all changed files
 fn parse_porcelain_status(output: &str, staged: bool) -> Vec<GitFile> {
-    output
-        .lines()
-        .filter_map(|line| {
-            if line.len() < 4 {
-                return None;
-            }
-
-            let index_status = line.chars().next().unwrap_or(' ');
-            let worktree_status = line.chars().nth(1).unwrap_or(' ');
-            let raw_path = line[3..].trim();
-            // For renames/copies, porcelain shows "old -> new"; use the new path.
-            // Only split on " -> " when the status flags indicate a rename or copy,
-            // so literal filenames containing " -> " are not mangled.
-            let is_rename_or_copy =
-                matches!(index_status, 'R' | 'C') || matches!(worktree_status, 'R' | 'C');
-            let path = if is_rename_or_copy && raw_path.contains(" -> ") {
-                raw_path
-                    .rsplit(" -> ")
-                    .next()
-                    .unwrap_or(raw_path)
-                    .to_string()
-            } else {
-                raw_path.to_string()
-            };
+    parse_status_v2(output)
+        .into_iter()
+        .filter_map(|entry| {
+            let StatusEntry {
+                index: index_status,
+                worktree: worktree_status,
+                path,
+                orig_path: old_path,
+            } = entry;
 
             // Filter based on staged flag
             if staged {
@@ -375,18 +1020,20 @@ fn parse_porcelain_status(output: &str, staged: bool) -> Vec<GitFile> {
                     'A' => "added",
                     'D' => "deleted",
                     'R' => "renamed",
-                    'C' => "modified", // copied
+                    'C' => "copied",
                     _ => "modified",
                 }
             } else {
-                // Use both statuses for unstaged (prioritize worktree)
+                // Use both statuses for unstaged (prioritize renames/copies so
+                // the old path stays meaningful, then the worktree)
                 match (index_status, worktree_status) {
+                    ('R', _) | (_, 'R') => "renamed",
+                    ('C', _) | (_, 'C') => "copied",
                     (_, 'M') => "modified",
                     ('M', _) => "modified",
-                    ('A', _) => "added",
+                    ('A', _) | (_, 'A') => "added", // (_, 'A') is intent-to-add
                     (_, 'D') => "deleted",
                     ('D', _) => "deleted",
-                    ('R', _) => "renamed",
                     ('?', '?') => "added",
                     _ => "modified",
                 }
@@ -395,59 +1042,80 @@ fn parse_porcelain_status(output: &str, staged: bool) -> Vec<GitFile> {
             Some(GitFile {
                 path,
                 status: status.to_string(),
+                old_path,
+                ..Default::default()
             })
         })
         .collect()
 }
 
-/// Parse git diff-tree --name-status output
+/// Parse `--name-status -z` output from `git diff` / `git diff-tree`: a status
+/// field followed by one path, or by the old and new paths for renames
+/// (R087) and copies (C075), each NUL-terminated.
 fn parse_file_status(output: &str) -> Vec<GitFile> {
-    output
-        .lines()
-        .filter_map(|line| {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() < 2 {
-                return None;
-            }
+    let mut files = Vec::new();
+    let mut fields = output.split('\0');
 
-            let status_code = parts[0];
-            let status = if status_code.starts_with('R') {
-                "renamed"
-            } else {
-                match status_code {
-                    "M" => "modified",
-                    "A" => "added",
-                    "D" => "deleted",
-                    _ => "modified",
-                }
-            };
+    while let Some(status_code) = fields.next() {
+        let status_code = status_code.trim_start_matches('\n');
+        if status_code.is_empty() {
+            continue;
+        }
+        let status = match status_code.chars().next() {
+            Some('R') => "renamed",
+            Some('C') => "copied",
+            Some('A') => "added",
+            Some('D') => "deleted",
+            _ => "modified",
+        };
+        let Some(first) = fields.next().filter(|path| !path.is_empty()) else {
+            break;
+        };
 
-            // For renames (e.g. "R100 old.rs new.rs"), use the new path (last element)
-            let path = if status_code.starts_with('R') && parts.len() >= 3 {
-                parts.last().unwrap()
-            } else {
-                parts[1]
-            };
+        // For renames and copies (e.g. "R100\0old.rs\0new.rs"), the new path is last
+        let is_rename_or_copy = matches!(status, "renamed" | "copied");
+        let (old_path, path) = if is_rename_or_copy {
+            match fields.next() {
+                Some(new) => (Some(first.to_string()), new),
+                None => break,
+            }
+        } else {
+            (None, first)
+        };
+        let similarity = status_code
+            .get(1..)
+            .and_then(|score| score.parse::<u8>().ok());
+
+        files.push(GitFile {
+            path: path.to_string(),
+            status: status.to_string(),
+            old_path,
+            similarity: if is_rename_or_copy { similarity } else { None },
+            ..Default::default()
+        });
+    }
 
-            Some(GitFile {
-                path: path.to_string(),
-                status: status.to_string(),
-            })
-        })
-        .collect()
+    files
 }
 
 /// Get diff for a specific file
 #[allow(dead_code)]
-pub fn get_file_diff(dir: &Path, file_path: &str, staged: bool) -> Result<String, String> {
+pub fn get_file_diff(
+    dir: &Path,
+    file_path: &str,
+    staged: bool,
+    opts: &DiffOptions,
+) -> Result<String, String> {
     let mut args = vec!["diff", "--no-color"];
     if staged {
         args.push("--staged");
     }
-    args.push(file_path);
 
     let output = Command::new("git")
         .args(&args)
+        .args(opts.to_args())
+        .arg("--")
+        .arg(file_path)
         .current_dir(dir)
         .output()
         .map_err(|e| format!("Failed to get file diff: {}", e))?;
@@ -456,25 +1124,14 @@ pub fn get_file_diff(dir: &Path, file_path: &str, staged: bool) -> Result<String
         return Err(String::from_utf8_lossy(&output.stderr).to_string());
     }
 
-    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    Ok(decode_diff(&output.stdout).0)
 }
 
 /// Get file content at a specific git ref (commit, branch, index, etc.)
-/// Uses `git show <ref>:<file_path>` to retrieve the content.
+/// Equivalent to `git show <ref>:<file_path>`; `:0` reads the index.
 pub fn get_file_at_ref(dir: &Path, git_ref: &str, file_path: &str) -> Result<String, String> {
-    let ref_path = format!("{}:{}", git_ref, file_path);
-    let output = Command::new("git")
-        .arg("show")
-        .arg(&ref_path)
-        .current_dir(dir)
-        .output()
-        .map_err(|e| format!("Failed to execute git show: {}", e))?;
-
-    if !output.status.success() {
-        return Err(String::from_utf8_lossy(&output.stderr).to_string());
-    }
-
-    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    let bytes = default_backend().read_blob(dir, git_ref, file_path)?;
+    Ok(encoding::decode(&bytes, None).0)
 }
 
 /// Get file content at a specific git ref and return it as base64.
@@ -483,19 +1140,8 @@ pub fn get_file_at_ref_base64(
     git_ref: &str,
     file_path: &str,
 ) -> Result<String, String> {
-    let ref_path = format!("{}:{}", git_ref, file_path);
-    let output = Command::new("git")
-        .arg("show")
-        .arg(&ref_path)
-        .current_dir(dir)
-        .output()
-        .map_err(|e| format!("Failed to execute git show: {}", e))?;
-
-    if !output.status.success() {
-        return Err(String::from_utf8_lossy(&output.stderr).to_string());
-    }
-
-    Ok(base64::engine::general_purpose::STANDARD.encode(output.stdout))
+    let bytes = default_backend().read_blob(dir, git_ref, file_path)?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(bytes))
 }
 
 /// Get file content at a specific git ref, applying smudge filters (LFS-aware).
@@ -505,6 +1151,7 @@ pub fn get_lfs_file_at_ref(dir: &Path, git_ref: &str, file_path: &str) -> Result
     let output = Command::new("git")
         .arg("cat-file")
         .arg("--filters")
+        .arg("--end-of-options")
         .arg(&ref_path)
         .current_dir(dir)
         .output()
@@ -514,7 +1161,9 @@ pub fn get_lfs_file_at_ref(dir: &Path, git_ref: &str, file_path: &str) -> Result
         return Err(String::from_utf8_lossy(&output.stderr).to_string());
     }
 
-    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    // Filters convert back to the `working-tree-encoding`, if there is one.
+    let declared = working_tree_encodings(dir, [file_path]).unwrap_or_default();
+    Ok(encoding::decode(&output.stdout, declared.get(file_path).map(String::as_str)).0)
 }
 
 /// Get file content at a specific git ref as base64, applying smudge filters (LFS-aware).
@@ -527,6 +1176,7 @@ pub fn get_lfs_file_at_ref_base64(
     let output = Command::new("git")
         .arg("cat-file")
         .arg("--filters")
+        .arg("--end-of-options")
         .arg(&ref_path)
         .current_dir(dir)
         .output()
@@ -541,34 +1191,110 @@ pub fn get_lfs_file_at_ref_base64(
 
 /// List all files in the repository at a given ref
 pub fn list_files_at_ref(dir: &Path, git_ref: &str) -> Result<Vec<String>, String> {
-    let output = Command::new("git")
-        .arg("ls-tree")
-        .arg("-r")
-        .arg("--name-only")
-        .arg(git_ref)
+    default_backend().list_tree(dir, git_ref)
+}
+
+/// List commits reachable from HEAD (or in `opts.range`), newest first, paged
+/// and filtered by `opts`.
+pub fn list_commits(dir: &Path, opts: &LogOptions) -> Result<Vec<CommitInfo>, String> {
+    let format = if opts.verify_signatures {
+        SIGNED_COMMIT_LOG_FORMAT
+    } else {
+        COMMIT_LOG_FORMAT
+    };
+    let mut cmd = Command::new("git");
+    cmd.arg("log").arg("-z").arg(format).args(opts.to_args());
+    if let Some(range) = &opts.range {
+        // A range like "--output=x" must not be taken for an option.
+        cmd.arg("--end-of-options").arg(range);
+    }
+    if let Some(file_path) = &opts.file_path {
+        cmd.arg("--").arg(file_path);
+    }
+    let output = cmd
         .current_dir(dir)
         .output()
-        .map_err(|e| format!("Failed to execute git ls-tree: {}", e))?;
+        .map_err(|e| format!("Failed to execute git log: {}", e))?;
 
     if !output.status.success() {
         return Err(String::from_utf8_lossy(&output.stderr).to_string());
     }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
-    Ok(stdout
+    Ok(parse_commit_log(&stdout))
+}
+
+/// Parse `git log -z` output in `COMMIT_LOG_FORMAT`.
+fn parse_commit_log(output: &str) -> Vec<CommitInfo> {
+    output
+        .split('\x1e')
+        .filter_map(|record| parse_commit_record(record.split('\0').next()?))
+        .collect()
+}
+
+/// Parse one `COMMIT_LOG_FORMAT` record (without the leading "\x1e").
+fn parse_commit_record(record: &str) -> Option<CommitInfo> {
+    let fields: Vec<&str> = record.splitn(13, '\x1f').collect();
+    if fields.len() < 13 {
+        return None;
+    }
+
+    let signature = match fields[10] {
+        "G" => "good",
+        "B" => "bad",
+        "U" => "untrusted",
+        "X" | "Y" => "expired",
+        "R" => "revoked",
+        "E" => "unverifiable",
+        _ => "none",
+    };
+    let trailers = fields[11]
         .lines()
-        .filter(|l| !l.is_empty())
-        .map(|l| l.to_string())
-        .collect())
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            Some(CommitTrailer {
+                key: key.trim().to_string(),
+                value: value.trim().to_string(),
+            })
+        })
+        .collect();
+
+    Some(CommitInfo {
+        hash: fields[0].to_string(),
+        short_hash: fields[1].to_string(),
+        message: fields[2].to_string(),
+        author: fields[3].to_string(),
+        date: fields[4].to_string(),
+        refs: fields[5].to_string(),
+        author_email: fields[6].to_string(),
+        date_iso: fields[7].to_string(),
+        timestamp: fields[8].parse().unwrap_or(0),
+        parents: fields[9]
+            .split_whitespace()
+            .map(|p| p.to_string())
+            .collect(),
+        body: fields[12].trim_end().to_string(),
+        trailers,
+        signature: signature.to_string(),
+    })
 }
 
-/// List recent commits
-pub fn list_commits(dir: &Path, limit: u32) -> Result<Vec<CommitInfo>, String> {
+/// History of one file, newest first, following renames. Page through it
+/// with `skip`/`limit`; each entry's `path` can be passed to `get_file_at_ref`.
+pub fn get_file_history(
+    dir: &Path,
+    file_path: &str,
+    skip: u32,
+    limit: u32,
+) -> Result<Vec<FileHistoryEntry>, String> {
     let output = Command::new("git")
         .arg("log")
-        .arg("--oneline")
-        .arg("--format=%H|%h|%s|%an|%ar|%D")
-        .arg(format!("-{}", limit))
+        .arg("--follow")
+        .arg("-z")
+        .arg("--name-status")
+        .arg(COMMIT_LOG_FORMAT)
+        .arg("--")
+        .arg(file_path)
         .current_dir(dir)
         .output()
         .map_err(|e| format!("Failed to execute git log: {}", e))?;
@@ -577,36 +1303,44 @@ pub fn list_commits(dir: &Path, limit: u32) -> Result<Vec<CommitInfo>, String> {
         return Err(String::from_utf8_lossy(&output.stderr).to_string());
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    Ok(parse_commit_log(&stdout))
+    // --skip/--max-count count commits before --follow filters them, so page here.
+    Ok(parse_file_history(&String::from_utf8_lossy(&output.stdout))
+        .into_iter()
+        .skip(skip as usize)
+        .take(limit as usize)
+        .collect())
 }
 
-/// Parse git log output with format "%H|%h|%s|%an|%ar|%D"
-fn parse_commit_log(output: &str) -> Vec<CommitInfo> {
+/// Parse `git log -z --name-status` output: each `COMMIT_LOG_FORMAT` record
+/// ends in "\0" and is followed by "\n<status>\0<path>\0" (two paths for
+/// renames and copies).
+fn parse_file_history(output: &str) -> Vec<FileHistoryEntry> {
     output
-        .lines()
-        .filter_map(|line| {
-            let parts: Vec<&str> = line.split('|').collect();
-            if parts.len() < 5 {
-                return None;
-            }
-
-            // Format: hash|short_hash|message|author|date|refs
-            // Since message can contain pipes, we need to reconstruct it
-            // from parts[2] to parts[parts.len() - 3]
-            let message = if parts.len() > 5 {
-                parts[2..parts.len() - 3].join("|")
-            } else {
-                parts[2].to_string()
+        .split('\x1e')
+        .filter_map(|record| {
+            let (header, changes) = record.split_once('\0')?;
+            let commit = parse_commit_record(header)?;
+
+            let mut tokens = changes.trim_start_matches('\n').split('\0');
+            let code = tokens.next().filter(|c| !c.is_empty())?;
+            let status = match code.chars().next() {
+                Some('R') => "renamed",
+                Some('C') => "copied",
+                Some('A') => "added",
+                Some('D') => "deleted",
+                _ => "modified",
+            };
+            let first = tokens.next()?.to_string();
+            let (old_path, path) = match status {
+                "renamed" | "copied" => (Some(first), tokens.next()?.to_string()),
+                _ => (None, first),
             };
 
-            Some(CommitInfo {
-                hash: parts[0].to_string(),
-                short_hash: parts[1].to_string(),
-                message,
-                author: parts[parts.len() - 3].to_string(),
-                date: parts[parts.len() - 2].to_string(),
-                refs: parts[parts.len() - 1].to_string(),
+            Some(FileHistoryEntry {
+                commit,
+                path,
+                status: status.to_string(),
+                old_path,
             })
         })
         .collect()
@@ -615,29 +1349,91 @@ fn parse_commit_log(output: &str) -> Vec<CommitInfo> {
 /// List local and remote branches
 pub fn list_branches(dir: &Path) -> Result<Vec<BranchInfo>, String> {
     let output = Command::new("git")
-        .arg("branch")
-        .arg("-a")
-        .arg("--format=%(refname:short)|%(objectname:short)|%(subject)|%(authorname)|%(committerdate:relative)")
+        .arg("for-each-ref")
+        .arg("--format=%(refname:short)%1f%(objectname:short)%1f%(subject)%1f%(authorname)%1f%(committerdate:relative)%1f%(refname)%1f%(committerdate:unix)%1f%(upstream:short)%1f%(upstream:track,nobracket)%1f%(HEAD)%1f%(symref)%00")
+        .arg("refs/heads")
+        .arg("refs/remotes")
         .current_dir(dir)
         .output()
-        .map_err(|e| format!("Failed to execute git branch: {}", e))?;
+        .map_err(|e| format!("Failed to execute git for-each-ref: {}", e))?;
 
     if !output.status.success() {
         return Err(String::from_utf8_lossy(&output.stderr).to_string());
     }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
-    Ok(parse_branch_list(&stdout))
+    let mut branches = parse_branch_list(&stdout);
+
+    // Same order as resolve_base_branch, but the configured and default
+    // bases are looked up once for all branches rather than per branch.
+    let backend = default_backend();
+    let configured = configured_base_branch(backend, dir);
+    let mut default = None;
+    for branch in &mut branches {
+        let base = match &configured {
+            Some(configured) => configured.clone(),
+            None => match upstream_base_branch(backend, dir, &branch.name) {
+                Some(upstream) => upstream,
+                None => {
+                    let default =
+                        default.get_or_insert_with(|| default_base_branch(backend, dir).ok());
+                    let Some((base, _)) = default else {
+                        continue;
+                    };
+                    base.clone()
+                }
+            },
+        };
+        if base != branch.name {
+            if let Ok((ahead, behind)) = backend.ahead_behind(dir, &base, &branch.name) {
+                branch.ahead_base = u32::try_from(ahead).ok();
+                branch.behind_base = u32::try_from(behind).ok();
+            }
+        }
+        branch.base = Some(base);
+    }
+
+    Ok(branches)
 }
 
+/// Parse `for-each-ref` records of "\x1f"-separated fields, each ended by
+/// NUL: short name, short hash, subject, author, relative date, then
+/// (optionally) full ref name, unix date, upstream, upstream tracking
+/// ("ahead 1, behind 2" / "gone"), the "*" HEAD marker and symref target.
 fn parse_branch_list(output: &str) -> Vec<BranchInfo> {
     output
-        .lines()
-        .filter_map(|line| {
-            let parts: Vec<&str> = line.split('|').collect();
+        .split('\0')
+        // for-each-ref still ends every record with a newline.
+        .map(|record| record.strip_prefix('\n').unwrap_or(record))
+        .filter_map(|record| {
+            let parts: Vec<&str> = record.split('\x1f').collect();
             if parts.len() < 5 {
                 return None;
             }
+            let field = |i: usize| parts.get(i).copied().unwrap_or("");
+
+            // Skip symbolic refs such as origin/HEAD.
+            if !field(10).is_empty() {
+                return None;
+            }
+
+            let refname = field(5);
+            let upstream = Some(field(7).to_string()).filter(|u| !u.is_empty());
+            let (ahead_upstream, behind_upstream) = match (&upstream, field(8)) {
+                (None, _) | (_, "gone") => (None, None),
+                (Some(_), track) => {
+                    let mut ahead = 0;
+                    let mut behind = 0;
+                    for part in track.split(", ") {
+                        if let Some(n) = part.strip_prefix("ahead ") {
+                            ahead = n.parse().unwrap_or(0);
+                        } else if let Some(n) = part.strip_prefix("behind ") {
+                            behind = n.parse().unwrap_or(0);
+                        }
+                    }
+                    (Some(ahead), Some(behind))
+                }
+            };
 
             Some(BranchInfo {
                 name: parts[0].to_string(),
@@ -645,22 +1441,32 @@ fn parse_branch_list(output: &str) -> Vec<BranchInfo> {
                 subject: parts[2].to_string(),
                 author: parts[3].to_string(),
                 date: parts[4].to_string(),
+                timestamp: field(6).parse().unwrap_or(0),
+                upstream,
+                ahead_upstream,
+                behind_upstream,
+                is_local: refname.starts_with("refs/heads/"),
+                is_remote: refname.starts_with("refs/remotes/"),
+                is_current: field(9) == "*",
+                ..Default::default()
             })
         })
         .collect()
 }
 
+/// Parse `git worktree list --porcelain -z`: NUL-terminated attribute lines,
+/// with an empty one ending each worktree.
 fn parse_worktree_list(output: &str) -> Vec<WorktreeInfo> {
     let mut worktrees = Vec::new();
 
-    for block in output.split("\n\n").filter(|b| !b.trim().is_empty()) {
+    for block in output.split("\0\0").filter(|b| !b.is_empty()) {
         let mut path: Option<String> = None;
         let mut branch = String::from("(detached)");
         let mut commit_hash = String::new();
 
-        for line in block.lines() {
+        for line in block.split('\0') {
             if let Some(value) = line.strip_prefix("worktree ") {
-                path = Some(value.trim().to_string());
+                path = Some(value.to_string());
             } else if let Some(value) = line.strip_prefix("HEAD ") {
                 let head = value.trim();
                 commit_hash = head.chars().take(7).collect();
@@ -691,7 +1497,7 @@ fn parse_worktree_list(output: &str) -> Vec<WorktreeInfo> {
 
 pub fn list_worktrees(dir: &Path) -> Result<Vec<WorktreeInfo>, String> {
     let output = Command::new("git")
-        .args(["worktree", "list", "--porcelain"])
+        .args(["worktree", "list", "--porcelain", "-z"])
         .current_dir(dir)
         .output()
         .map_err(|e| format!("Failed to execute git worktree list: {}", e))?;
@@ -722,12 +1528,23 @@ pub fn has_worktrees(dir: &Path) -> bool {
 }
 
 /// Get diff and changed files for a specific commit by hash
-pub fn get_commit_diff(dir: &Path, hash: &str) -> Result<GitDiffResult, String> {
+pub fn get_commit_diff(
+    dir: &Path,
+    hash: &str,
+    opts: &DiffOptions,
+) -> Result<GitDiffResult, String> {
+    let parents = get_commit_parents(dir, hash)?;
+    if parents.len() > 1 {
+        return get_merge_commit_diff(dir, hash, &parents, opts);
+    }
+
     let diff_output = Command::new("git")
         .arg("show")
-        .arg(hash)
         .arg("--format=")
         .arg("--no-color")
+        .args(opts.to_args())
+        .arg("--end-of-options")
+        .arg(hash)
         .current_dir(dir)
         .output()
         .map_err(|e| format!("Failed to execute git show: {}", e))?;
@@ -736,47 +1553,88 @@ pub fn get_commit_diff(dir: &Path, hash: &str) -> Result<GitDiffResult, String>
         return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
     }
 
-    let diff = String::from_utf8_lossy(&diff_output.stdout).to_string();
+    let (diff, encodings) = decode_diff(&diff_output.stdout);
 
-    // Get changed files for this commit
+    // Get changed files for this commit (--root so the initial commit lists its files)
     let files_output = Command::new("git")
         .arg("diff-tree")
         .arg("--no-commit-id")
         .arg("--name-status")
+        .arg("-z")
         .arg("-r")
+        .arg("--root")
+        .args(opts.to_args())
+        .arg("--end-of-options")
         .arg(hash)
         .current_dir(dir)
         .output()
         .map_err(|e| format!("Failed to get changed files: {}", e))?;
 
+    if !files_output.status.success() {
+        return Err(String::from_utf8_lossy(&files_output.stderr).to_string());
+    }
+
     let files = parse_file_status(&String::from_utf8_lossy(&files_output.stdout));
 
-    Ok(GitDiffResult { diff, files })
+    Ok(GitDiffResult::new(diff, files).with_encodings(&encodings))
 }
 
-/// Get diff and changed files for an arbitrary git range (e.g. HEAD~3..HEAD)
-pub fn get_range_diff(dir: &Path, range: &str) -> Result<GitDiffResult, String> {
+/// Full hashes of a commit's parents; more than one means it is a merge.
+pub fn get_commit_parents(dir: &Path, hash: &str) -> Result<Vec<String>, String> {
+    default_backend().commit_parents(dir, hash)
+}
+
+fn get_merge_commit_diff(
+    dir: &Path,
+    hash: &str,
+    parents: &[String],
+    opts: &DiffOptions,
+) -> Result<GitDiffResult, String> {
+    let parent = match opts.merge_diff {
+        MergeDiff::FirstParent => &parents[0],
+        MergeDiff::Parent { parent } => parent
+            .checked_sub(1)
+            .and_then(|i| parents.get(i))
+            .ok_or_else(|| {
+                format!(
+                    "Commit {} has {} parents, no parent {}",
+                    hash,
+                    parents.len(),
+                    parent
+                )
+            })?,
+        MergeDiff::Combined => return get_combined_diff(dir, hash, opts),
+        MergeDiff::Remerge => return get_remerge_diff(dir, hash, opts),
+    };
+
     let diff_output = Command::new("git")
         .arg("diff")
         .arg("--no-color")
-        .arg(range)
+        .args(opts.to_args())
+        .arg("--end-of-options")
+        .arg(parent)
+        .arg(hash)
         .current_dir(dir)
         .output()
-        .map_err(|e| format!("Failed to execute git diff {}: {}", range, e))?;
+        .map_err(|e| format!("Failed to execute git diff: {}", e))?;
 
     if !diff_output.status.success() {
         return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
     }
 
-    let diff = String::from_utf8_lossy(&diff_output.stdout).to_string();
+    let (diff, encodings) = decode_diff(&diff_output.stdout);
 
     let files_output = Command::new("git")
         .arg("diff")
         .arg("--name-status")
-        .arg(range)
+        .arg("-z")
+        .args(opts.to_args())
+        .arg("--end-of-options")
+        .arg(parent)
+        .arg(hash)
         .current_dir(dir)
         .output()
-        .map_err(|e| format!("Failed to get changed files for {}: {}", range, e))?;
+        .map_err(|e| format!("Failed to get changed files: {}", e))?;
 
     if !files_output.status.success() {
         return Err(String::from_utf8_lossy(&files_output.stderr).to_string());
@@ -784,561 +1642,2695 @@ pub fn get_range_diff(dir: &Path, range: &str) -> Result<GitDiffResult, String>
 
     let files = parse_file_status(&String::from_utf8_lossy(&files_output.stdout));
 
-    Ok(GitDiffResult { diff, files })
+    Ok(GitDiffResult::new(diff, files).with_encodings(&encodings))
 }
 
-/// Get diff and changed files comparing base branch and selected branch
-/// Returns the base ref used for branch diffs (same logic as get_branch_diff).
-pub fn get_branch_base(dir: &Path, branch: &str) -> Result<String, String> {
-    let has_main = Command::new("git")
-        .arg("show-ref")
-        .arg("--verify")
-        .arg("--quiet")
-        .arg("refs/heads/main")
+fn get_combined_diff(dir: &Path, hash: &str, opts: &DiffOptions) -> Result<GitDiffResult, String> {
+    let diff_output = Command::new("git")
+        .arg("show")
+        .arg("--format=")
+        .arg("--no-color")
+        .arg("--cc")
+        .args(opts.to_args())
+        .arg("--end-of-options")
+        .arg(hash)
         .current_dir(dir)
-        .status()
-        .map(|status| status.success())
-        .unwrap_or(false);
-
-    let base = if has_main {
-        "main".to_string()
-    } else {
-        let output = Command::new("git")
-            .arg("rev-parse")
-            .arg("--abbrev-ref")
-            .arg("HEAD")
-            .current_dir(dir)
-            .output()
-            .map_err(|e| format!("Failed to resolve current branch: {}", e))?;
+        .output()
+        .map_err(|e| format!("Failed to execute git show: {}", e))?;
 
-        if !output.status.success() {
-            return Err(String::from_utf8_lossy(&output.stderr).to_string());
-        }
+    if !diff_output.status.success() {
+        return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
+    }
 
-        String::from_utf8_lossy(&output.stdout).trim().to_string()
-    };
+    let (diff, encodings) = decode_diff(&diff_output.stdout);
 
-    // Return the merge-base commit for accurate old-side resolution
-    let output = Command::new("git")
-        .args(["merge-base", &base, branch])
+    // Combined status has one column per parent ("MM\tfile"); the first wins.
+    let files_output = Command::new("git")
+        .arg("diff-tree")
+        .arg("--no-commit-id")
+        .arg("--name-status")
+        .arg("-z")
+        .arg("-r")
+        .arg("--cc")
+        .arg("--end-of-options")
+        .arg(hash)
         .current_dir(dir)
         .output()
-        .map_err(|e| format!("Failed to find merge-base: {}", e))?;
+        .map_err(|e| format!("Failed to get changed files: {}", e))?;
 
-    if output.status.success() {
-        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
-    } else {
-        // Fallback to the base branch name
-        Ok(base)
+    if !files_output.status.success() {
+        return Err(String::from_utf8_lossy(&files_output.stderr).to_string());
     }
+
+    let files = parse_file_status(&String::from_utf8_lossy(&files_output.stdout));
+
+    Ok(GitDiffResult::new(diff, files).with_encodings(&encodings))
 }
 
-pub fn get_branch_diff(dir: &Path, branch: &str) -> Result<GitDiffResult, String> {
-    // Prefer main if present, otherwise compare against current branch
-    let has_main = Command::new("git")
-        .arg("show-ref")
-        .arg("--verify")
-        .arg("--quiet")
-        .arg("refs/heads/main")
+fn get_remerge_diff(dir: &Path, hash: &str, opts: &DiffOptions) -> Result<GitDiffResult, String> {
+    let diff_output = Command::new("git")
+        .arg("show")
+        .arg("--format=")
+        .arg("--no-color")
+        .arg("--remerge-diff")
+        .args(opts.to_args())
+        .arg("--end-of-options")
+        .arg(hash)
         .current_dir(dir)
-        .status()
-        .map(|status| status.success())
-        .unwrap_or(false);
-
-    let base = if has_main {
-        "main".to_string()
-    } else {
-        let output = Command::new("git")
-            .arg("rev-parse")
-            .arg("--abbrev-ref")
-            .arg("HEAD")
-            .current_dir(dir)
-            .output()
-            .map_err(|e| format!("Failed to resolve current branch: {}", e))?;
+        .output()
+        .map_err(|e| format!("Failed to execute git show: {}", e))?;
 
-        if !output.status.success() {
-            return Err(String::from_utf8_lossy(&output.stderr).to_string());
-        }
+    if !diff_output.status.success() {
+        return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
+    }
 
-        String::from_utf8_lossy(&output.stdout).trim().to_string()
-    };
+    let (diff, encodings) = decode_diff(&diff_output.stdout);
+
+    // There is no name-status form of a remerge diff, so list what it touched.
+    let files = parse_unified_diff(&diff)
+        .iter()
+        .map(|fd| GitFile {
+            path: fd.path().to_string(),
+            status: fd.status.clone(),
+            old_path: match fd.status.as_str() {
+                "renamed" | "copied" => fd.old_path.clone(),
+                _ => None,
+            },
+            ..Default::default()
+        })
+        .collect();
 
-    let range = format!("{}...{}", base, branch);
+    Ok(GitDiffResult::new(diff, files).with_encodings(&encodings))
+}
 
+/// Get diff and changed files for an arbitrary git range (e.g. HEAD~3..HEAD)
+pub fn get_range_diff(
+    dir: &Path,
+    range: &str,
+    opts: &DiffOptions,
+) -> Result<GitDiffResult, String> {
     let diff_output = Command::new("git")
         .arg("diff")
         .arg("--no-color")
-        .arg(&range)
+        .args(opts.to_args())
+        .arg("--end-of-options")
+        .arg(range)
         .current_dir(dir)
         .output()
-        .map_err(|e| format!("Failed to execute branch diff: {}", e))?;
+        .map_err(|e| format!("Failed to execute git diff {}: {}", range, e))?;
 
     if !diff_output.status.success() {
         return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
     }
 
-    let diff = String::from_utf8_lossy(&diff_output.stdout).to_string();
+    let (diff, encodings) = decode_diff(&diff_output.stdout);
 
-    // Get changed files for this branch comparison
     let files_output = Command::new("git")
         .arg("diff")
         .arg("--name-status")
-        .arg(&range)
+        .arg("-z")
+        .args(opts.to_args())
+        .arg("--end-of-options")
+        .arg(range)
         .current_dir(dir)
         .output()
-        .map_err(|e| format!("Failed to get changed files: {}", e))?;
+        .map_err(|e| format!("Failed to get changed files for {}: {}", range, e))?;
+
+    if !files_output.status.success() {
+        return Err(String::from_utf8_lossy(&files_output.stderr).to_string());
+    }
 
     let files = parse_file_status(&String::from_utf8_lossy(&files_output.stdout));
 
-    Ok(GitDiffResult { diff, files })
+    Ok(GitDiffResult::new(diff, files).with_encodings(&encodings))
 }
 
-// =============================================================================
-// git-gud Stack Support
-// =============================================================================
-
-/// Resolve the git directory for gg state files.
-///
-/// In a normal repo: `.git/gg/`
-/// In a worktree: `.git/worktrees/<name>/gg/` (where `.git` is a file
-/// containing `gitdir: /path/to/main/.git/worktrees/<name>`)
-fn resolve_gg_dir(dir: &Path) -> Option<PathBuf> {
-    use std::fs;
+/// How one commit of the old series corresponds to the new series, as
+/// `git range-diff` pairs them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RangeDiffPair {
+    pub old_index: Option<u32>, // 1-based position in the old series
+    pub old_hash: Option<String>,
+    pub new_index: Option<u32>,
+    pub new_hash: Option<String>,
+    pub status: String, // "unchanged", "modified", "removed", "added"
+    pub subject: String,
+    pub changes: String, // range-diff's diff of the two patches, for "modified"
+}
 
-    let dot_git = dir.join(".git");
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InterdiffResult {
+    pub pairs: Vec<RangeDiffPair>,
+    /// Diff from the old series, replayed onto the new series' base, to the
+    /// new tip, so upstream changes picked up by a rebase are left out.
+    pub interdiff: GitDiffResult,
+    /// Commit or tree the old side of `interdiff` was read from; usable as a
+    /// `gitRef` for fetching old file content.
+    pub old_side: String,
+}
 
-    if dot_git.is_dir() {
-        // Normal repo: .git is a directory
-        let gg_dir = dot_git.join("gg");
-        if gg_dir.exists() {
-            return Some(gg_dir);
-        }
-        return Some(gg_dir); // Return even if not exists (for has_gg_stacks check)
+/// Compare two versions of a branch or stack. `old` and `new` are either
+/// both tips or both ranges like "main..old-tip". For tips, each series is
+/// what it adds on top of the base branch (see `resolve_base_branch`), so a
+/// stack rebased onto a newer base still pairs up commit by commit. Returns
+/// the per-commit pairing and the interdiff.
+pub fn get_interdiff(
+    dir: &Path,
+    old: &str,
+    new: &str,
+    opts: &DiffOptions,
+) -> Result<InterdiffResult, String> {
+    // range-diff has no --end-of-options to protect its positional ranges.
+    if let Some(rev) = [old, new].into_iter().find(|rev| rev.starts_with('-')) {
+        return Err(format!("Invalid revision: {}", rev));
     }
 
-    if dot_git.is_file() {
-        // Worktree: .git is a file with "gitdir: <path>"
-        if let Ok(content) = fs::read_to_string(&dot_git) {
-            if let Some(gitdir_str) = content.trim().strip_prefix("gitdir:") {
-                let gitdir = PathBuf::from(gitdir_str.trim());
-                // Resolve relative paths against the working dir
-                let gitdir = if gitdir.is_relative() {
-                    dir.join(&gitdir)
-                } else {
-                    gitdir
-                };
-                // Worktree git dir: e.g. /repo/.git/worktrees/<name>
-                // gg state lives at /repo/.git/worktrees/<name>/gg/
-                let gg_dir = gitdir.join("gg");
-                return Some(gg_dir);
+    let backend = default_backend();
+    let ((old_base, old_tip), (new_base, new_tip)) =
+        match (old.split_once(".."), new.split_once("..")) {
+            (Some((old_base, old_tip)), Some((new_base, new_tip))) => (
+                (old_base.to_string(), old_tip.to_string()),
+                (new_base.to_string(), new_tip.to_string()),
+            ),
+            _ => {
+                let tip = |rev: &str| rev.rsplit("..").next().unwrap_or(rev).to_string();
+                let (old_tip, new_tip) = (tip(old), tip(new));
+                let (old_base, new_base) = series_bases(backend, dir, &old_tip, &new_tip)?;
+                ((old_base, old_tip), (new_base, new_tip))
             }
-        }
-    }
+        };
 
-    None
-}
+    let output = Command::new("git")
+        .arg("range-diff")
+        .arg("--no-color")
+        .arg(format!("{}..{}", old_base, old_tip))
+        .arg(format!("{}..{}", new_base, new_tip))
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to execute git range-diff: {}", e))?;
 
-/// Check if git-gud stacks are available in the repository
-pub fn has_gg_stacks(dir: &Path) -> bool {
-    resolve_gg_dir(dir)
-        .map(|gg_dir| gg_dir.join("config.json").exists())
-        .unwrap_or(false)
-}
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
 
-/// Read git-gud config file
-fn read_gg_config(dir: &Path) -> Result<serde_json::Value, String> {
-    use std::fs;
-    let gg_dir = resolve_gg_dir(dir).ok_or("Not a git repository")?;
-    let config_path = gg_dir.join("config.json");
-    let content =
-        fs::read_to_string(&config_path).map_err(|e| format!("Failed to read gg config: {}", e))?;
-    serde_json::from_str(&content).map_err(|e| format!("Failed to parse git-gud config: {}", e))
-}
+    let mut pairs = parse_range_diff(&String::from_utf8_lossy(&output.stdout));
+    for pair in &mut pairs {
+        for hash in [&mut pair.old_hash, &mut pair.new_hash]
+            .into_iter()
+            .flatten()
+        {
+            *hash = backend.rev_parse(dir, hash)?;
+        }
+    }
 
-/// Get the default base branch from git-gud config
-fn get_default_base(config: &serde_json::Value) -> String {
-    config
-        .get("defaults")
-        .and_then(|d| d.get("base"))
-        .and_then(|b| b.as_str())
-        .unwrap_or("main")
-        .to_string()
-}
+    let replayed = if backend.rev_parse(dir, &old_base)? == backend.rev_parse(dir, &new_base)? {
+        old_tip
+    } else {
+        merged_tree(dir, &new_base, &old_tip)?
+    };
+    let interdiff = get_range_diff(dir, &format!("{}..{}", replayed, new_tip), opts)?;
 
-/// Get the current stack name from .git/gg/current_stack
-fn get_current_stack(dir: &Path) -> Option<String> {
-    use std::fs;
-    let gg_dir = resolve_gg_dir(dir)?;
-    let current_stack_path = gg_dir.join("current_stack");
-    fs::read_to_string(current_stack_path)
-        .ok()
-        .map(|s| s.trim().to_string())
+    Ok(InterdiffResult {
+        pairs,
+        interdiff,
+        old_side: replayed,
+    })
 }
 
-/// Extract GG-ID from commit message body
-fn extract_gg_id(commit_body: &str) -> Option<String> {
-    for line in commit_body.lines() {
-        let trimmed = line.trim();
-        if let Some(gg_id) = trimmed.strip_prefix("GG-ID:") {
-            return Some(gg_id.trim().to_string());
+/// Where the old and new series start. Each is measured from the base branch
+/// of `new`; when both start at the same place (no rebase in between), their
+/// common ancestor is used instead, so commits they share drop out.
+fn series_bases(
+    backend: &dyn GitBackend,
+    dir: &Path,
+    old: &str,
+    new: &str,
+) -> Result<(String, String), String> {
+    let fork = || backend.merge_base(dir, old, new);
+    let branch = match new {
+        "HEAD" => backend
+            .current_branch(dir)
+            .unwrap_or_else(|_| new.to_string()),
+        _ => new.to_string(),
+    };
+    let Ok((base, _)) = resolve_base_branch(backend, dir, &branch) else {
+        let fork = fork()?;
+        return Ok((fork.clone(), fork));
+    };
+
+    match (
+        backend.merge_base(dir, &base, old),
+        backend.merge_base(dir, &base, new),
+    ) {
+        (Ok(old_base), Ok(new_base))
+            if old_base != new_base && new_base != backend.rev_parse(dir, new)? =>
+        {
+            Ok((old_base, new_base))
+        }
+        _ => {
+            let fork = fork()?;
+            Ok((fork.clone(), fork))
         }
     }
-    None
 }
 
-/// Get git config value
-fn get_git_config(dir: &Path, key: &str) -> Option<String> {
+/// The tree `tip` would have after being merged onto `onto`. Conflicts are
+/// written into the tree as conflict markers rather than failing.
+fn merged_tree(dir: &Path, onto: &str, tip: &str) -> Result<String, String> {
     let output = Command::new("git")
-        .arg("config")
-        .arg(key)
+        .args(["merge-tree", "--write-tree", "--no-messages", onto, tip])
         .current_dir(dir)
         .output()
-        .ok()?;
+        .map_err(|e| format!("Failed to execute git merge-tree: {}", e))?;
 
-    if output.status.success() {
-        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
-    } else {
-        None
+    // Exit code 1 means there were conflicts; the tree is written all the same.
+    if !matches!(output.status.code(), Some(0) | Some(1)) {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
     }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string())
 }
 
-/// Parse username from branch name (e.g., "user/stack" -> "user")
-fn extract_username_from_branch(branch: &str) -> Option<String> {
-    branch.split('/').next().map(|s| s.to_string())
+/// Parse `git range-diff` output: one "1:  abc1234 ! 1:  def5678 subject"
+/// header per pair, followed by its indented diff-of-diffs.
+fn parse_range_diff(output: &str) -> Vec<RangeDiffPair> {
+    fn token(s: &str) -> Option<(&str, &str)> {
+        let s = s.trim_start();
+        let end = s.find(' ').unwrap_or(s.len());
+        (end > 0).then(|| (&s[..end], &s[end..]))
+    }
+    fn side(index: &str, hash: &str) -> (Option<u32>, Option<String>) {
+        match index.trim_end_matches(':').parse() {
+            Ok(index) => (Some(index), Some(hash.to_string())),
+            Err(_) => (None, None),
+        }
+    }
+
+    let mut pairs: Vec<RangeDiffPair> = Vec::new();
+    for line in output.lines() {
+        if let Some(body) = line.strip_prefix("    ") {
+            if let Some(pair) = pairs.last_mut() {
+                pair.changes.push_str(body);
+                pair.changes.push('\n');
+            }
+            continue;
+        }
+
+        let header = (|| {
+            let (old_index, rest) = token(line)?;
+            let (old_hash, rest) = token(rest)?;
+            let (marker, rest) = token(rest)?;
+            let (new_index, rest) = token(rest)?;
+            let (new_hash, rest) = token(rest)?;
+            Some((
+                old_index,
+                old_hash,
+                marker,
+                new_index,
+                new_hash,
+                rest.trim_start(),
+            ))
+        })();
+        let Some((old_index, old_hash, marker, new_index, new_hash, subject)) = header else {
+            continue;
+        };
+        let status = match marker {
+            "=" => "unchanged",
+            "!" => "modified",
+            "<" => "removed",
+            ">" => "added",
+            _ => continue,
+        };
+        let (old_index, old_hash) = side(old_index, old_hash);
+        let (new_index, new_hash) = side(new_index, new_hash);
+        pairs.push(RangeDiffPair {
+            old_index,
+            old_hash,
+            new_index,
+            new_hash,
+            status: status.to_string(),
+            subject: subject.to_string(),
+            changes: String::new(),
+        });
+    }
+    pairs
 }
 
-/// Check if a branch name is a stack branch (user/name without --)
-fn is_stack_branch(branch: &str) -> bool {
-    let parts: Vec<&str> = branch.split('/').collect();
-    if parts.len() != 2 {
-        return false;
+/// One entry of `git stash list`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StashInfo {
+    pub index: u32,
+    pub name: String, // "stash@{0}"
+    pub hash: String,
+    pub message: String,
+    pub branch: Option<String>, // branch the stash was made on
+    pub date: String,           // relative date
+    pub date_iso: String,
+    pub timestamp: i64,
+    pub has_untracked: bool, // stashed with --include-untracked / --all
+}
+
+/// List stashes, newest first.
+pub fn list_stashes(dir: &Path) -> Result<Vec<StashInfo>, String> {
+    let output = Command::new("git")
+        .arg("stash")
+        .arg("list")
+        .arg("-z")
+        .arg("--format=%x1e%gd%x1f%H%x1f%gs%x1f%ar%x1f%aI%x1f%at%x1f%P")
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to execute git stash list: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
     }
-    !parts[1].contains("--")
+
+    Ok(parse_stash_list(&String::from_utf8_lossy(&output.stdout)))
 }
 
-/// Extract stack name from branch name (e.g., "user/stack" -> "stack")
-fn extract_stack_name(branch: &str) -> Option<String> {
-    let parts: Vec<&str> = branch.split('/').collect();
-    if parts.len() == 2 && !parts[1].contains("--") {
-        Some(parts[1].to_string())
-    } else {
-        None
+fn parse_stash_list(output: &str) -> Vec<StashInfo> {
+    output
+        .split('\x1e')
+        .filter_map(|record| {
+            let record = record.split('\0').next()?;
+            let fields: Vec<&str> = record.split('\x1f').collect();
+            if fields.len() < 7 {
+                return None;
+            }
+            let index = fields[0]
+                .strip_prefix("stash@{")?
+                .strip_suffix('}')?
+                .parse()
+                .ok()?;
+
+            // Subjects read "On <branch>: <message>" or "WIP on <branch>: <head subject>".
+            let subject = fields[2];
+            let (branch, message) = match subject.split_once(": ") {
+                Some((prefix, message)) => {
+                    let branch = prefix
+                        .strip_prefix("WIP on ")
+                        .or_else(|| prefix.strip_prefix("On "))
+                        .map(|b| b.to_string());
+                    (branch, message)
+                }
+                None => (None, subject),
+            };
+
+            Some(StashInfo {
+                index,
+                name: fields[0].to_string(),
+                hash: fields[1].to_string(),
+                message: message.to_string(),
+                branch,
+                date: fields[3].to_string(),
+                date_iso: fields[4].to_string(),
+                timestamp: fields[5].parse().unwrap_or(0),
+                has_untracked: fields[6].split_whitespace().count() > 2,
+            })
+        })
+        .collect()
+}
+
+/// Diff of a stash entry ("stash@{1}" or just "1") against the commit it was
+/// made on, including any untracked files it saved.
+pub fn get_stash_diff(
+    dir: &Path,
+    stash: &str,
+    opts: &DiffOptions,
+) -> Result<GitDiffResult, String> {
+    let stash = match stash.parse::<u32>() {
+        Ok(index) => format!("stash@{{{}}}", index),
+        Err(_) => stash.to_string(),
+    };
+    let hash = default_backend().rev_parse(dir, &stash)?;
+    // Only what `refs/stash` or its reflog holds, not any merge lookalike.
+    let parents = get_commit_parents(dir, &hash)?;
+    if parents.len() < 2 || !list_stashes(dir)?.iter().any(|entry| entry.hash == hash) {
+        return Err(format!("{} is not a stash entry", stash));
+    }
+
+    let tracked = get_range_diff(dir, &format!("{}..{}", parents[0], hash), opts)?;
+    let mut diff = tracked.diff;
+    let mut files = tracked.files;
+
+    // The third parent is a root commit holding only the untracked files.
+    if let Some(untracked) = parents.get(2) {
+        let untracked = get_commit_diff(dir, untracked, opts)?;
+        if !diff.is_empty() && !diff.ends_with('\n') {
+            diff.push('\n');
+        }
+        diff.push_str(&untracked.diff);
+        files.extend(untracked.files);
     }
+
+    // Each part was decoded on its own; keep what it found per file.
+    let decoded: HashMap<String, String> = files
+        .iter()
+        .filter_map(|f| Some((f.path.clone(), f.encoding.clone()?)))
+        .collect();
+    let result = GitDiffResult::new(diff, files);
+    let encodings: Vec<Option<&str>> = result
+        .file_diffs
+        .iter()
+        .map(|fd| decoded.get(fd.path()).map(String::as_str))
+        .collect();
+    Ok(result.with_encodings(&encodings))
 }
 
-/// List all git-gud stacks in the repository
-pub fn list_gg_stacks(dir: &Path) -> Result<Vec<GgStackInfo>, String> {
-    let config = read_gg_config(dir)?;
-    let default_base = get_default_base(&config);
-    let current_stack_branch = get_current_stack(dir);
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagInfo {
+    pub name: String,
+    pub is_annotated: bool,
+    pub commit: String,   // commit the tag points at
+    pub date_iso: String, // tagger date, or the commit date for lightweight tags
+    pub timestamp: i64,
+    pub tagger: Option<String>,
+    pub tagger_email: Option<String>,
+    pub message: Option<String>, // annotated tags only
+}
 
-    // Get all local branches
-    let branches_output = Command::new("git")
+/// A release-to-release review: the diff between two tags and the commits in between.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagDiffResult {
+    #[serde(flatten)]
+    pub diff: GitDiffResult,
+    pub commits: Vec<CommitInfo>,
+}
+
+/// List tags, newest first.
+pub fn list_tags(dir: &Path) -> Result<Vec<TagInfo>, String> {
+    let output = Command::new("git")
         .arg("for-each-ref")
-        .arg("--format=%(refname:short)")
-        .arg("refs/heads/")
+        .arg("--sort=-creatordate")
+        .arg("--format=%1e%(refname:short)%1f%(objecttype)%1f%(objectname)%1f%(*objectname)%1f%(creatordate:iso-strict)%1f%(creatordate:unix)%1f%(taggername)%1f%(taggeremail:trim)%1f%(contents)")
+        .arg("refs/tags")
         .current_dir(dir)
         .output()
-        .map_err(|e| format!("Failed to list branches: {}", e))?;
+        .map_err(|e| format!("Failed to execute git for-each-ref: {}", e))?;
 
-    if !branches_output.status.success() {
-        return Err(String::from_utf8_lossy(&branches_output.stderr).to_string());
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
     }
 
-    let branches_str = String::from_utf8_lossy(&branches_output.stdout);
-    let mut stacks = Vec::new();
-    let mut seen_stacks = std::collections::HashSet::new();
+    Ok(parse_tag_list(&String::from_utf8_lossy(&output.stdout)))
+}
 
-    // First, get username from git config as fallback
-    let default_username =
-        get_git_config(dir, "user.name").unwrap_or_else(|| "unknown".to_string());
+fn parse_tag_list(output: &str) -> Vec<TagInfo> {
+    output
+        .split('\x1e')
+        .filter_map(|record| {
+            let fields: Vec<&str> = record.splitn(9, '\x1f').collect();
+            if fields.len() < 9 {
+                return None;
+            }
+            let is_annotated = fields[1] == "tag";
+            let non_empty = |s: &str| Some(s.trim().to_string()).filter(|s| !s.is_empty());
+
+            Some(TagInfo {
+                name: fields[0].to_string(),
+                is_annotated,
+                // Annotated tags peel to the tagged commit; lightweight ones are it.
+                commit: if is_annotated { fields[3] } else { fields[2] }.to_string(),
+                date_iso: fields[4].to_string(),
+                timestamp: fields[5].parse().unwrap_or(0),
+                tagger: non_empty(fields[6]),
+                tagger_email: non_empty(fields[7]),
+                message: if is_annotated {
+                    non_empty(fields[8])
+                } else {
+                    None
+                },
+            })
+        })
+        .collect()
+}
 
-    // Find all stack branches
-    for branch in branches_str.lines() {
-        if !is_stack_branch(branch) {
-            continue;
+/// Diff between two tags plus the commits reachable from `to` but not `from`.
+pub fn get_tag_diff(
+    dir: &Path,
+    from: &str,
+    to: &str,
+    opts: &DiffOptions,
+) -> Result<TagDiffResult, String> {
+    let backend = default_backend();
+    for tag in [from, to] {
+        if !backend.ref_exists(dir, &format!("refs/tags/{}", tag)) {
+            return Err(format!("Unknown tag: {}", tag));
         }
+    }
 
-        let Some(stack_name) = extract_stack_name(branch) else {
-            continue;
-        };
+    let range = format!("refs/tags/{}..refs/tags/{}", from, to);
+    let diff = get_range_diff(dir, &range, opts)?;
+    let commits = list_commits(
+        dir,
+        &LogOptions {
+            range: Some(range),
+            ..Default::default()
+        },
+    )?;
+
+    Ok(TagDiffResult { diff, commits })
+}
 
-        if seen_stacks.contains(&stack_name) {
-            continue;
-        }
-        seen_stacks.insert(stack_name.clone());
+/// Git config key holding a repo's preferred base branch.
+pub const BASE_BRANCH_CONFIG_KEY: &str = "air.baseBranch";
 
-        let username =
-            extract_username_from_branch(branch).unwrap_or_else(|| default_username.clone());
+/// Branch names tried, locally and then on origin, when nothing else decides.
+const COMMON_BASE_BRANCHES: [&str; 4] = ["main", "master", "develop", "trunk"];
 
-        // Get base for this stack
-        let base = config
-            .get("stacks")
-            .and_then(|s| s.get(&stack_name))
-            .and_then(|s| s.get("base"))
-            .and_then(|b| b.as_str())
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| default_base.clone());
+/// The base a branch is compared against and why it was chosen.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BranchBase {
+    pub base: String,               // e.g. "main" or "origin/develop"
+    pub merge_base: Option<String>, // merge-base of base and the branch
+    pub reason: String, // "configured", "upstream", "origin-head", "common-name", "current-branch"
+}
 
-        // Count commits in stack
-        let count_output = Command::new("git")
-            .arg("rev-list")
-            .arg("--count")
-            .arg(format!("{}..{}", base, branch))
-            .current_dir(dir)
-            .output()
-            .map_err(|e| format!("Failed to count commits: {}", e))?;
+/// Pick the ref that branch diffs for `branch` compare against, trying in
+/// order: the `air.baseBranch` config, the branch's upstream (unless it just
+/// tracks its own remote copy), `origin/HEAD`, common names, and finally the
+/// currently checked-out branch.
+fn resolve_base_branch(
+    backend: &dyn GitBackend,
+    dir: &Path,
+    branch: &str,
+) -> Result<(String, &'static str), String> {
+    if let Some(configured) = configured_base_branch(backend, dir) {
+        return Ok((configured, "configured"));
+    }
+    if let Some(upstream) = upstream_base_branch(backend, dir, branch) {
+        return Ok((upstream, "upstream"));
+    }
+    default_base_branch(backend, dir)
+}
+
+/// The `air.baseBranch` config, if it names something that exists.
+fn configured_base_branch(backend: &dyn GitBackend, dir: &Path) -> Option<String> {
+    backend
+        .config_value(dir, BASE_BRANCH_CONFIG_KEY)
+        .filter(|configured| backend.rev_parse(dir, configured).is_ok())
+}
 
-        let commit_count = if count_output.status.success() {
-            String::from_utf8_lossy(&count_output.stdout)
-                .trim()
-                .parse::<usize>()
-                .unwrap_or(0)
+/// The upstream `branch` tracks, unless that is just its own remote copy.
+fn upstream_base_branch(backend: &dyn GitBackend, dir: &Path, branch: &str) -> Option<String> {
+    let short_branch = branch.strip_prefix("refs/heads/").unwrap_or(branch);
+    let upstream_merge = backend.config_value(dir, &format!("branch.{}.merge", short_branch));
+    let upstream_remote = backend.config_value(dir, &format!("branch.{}.remote", short_branch));
+    if let (Some(merge), Some(remote)) = (upstream_merge, upstream_remote) {
+        let name = merge.strip_prefix("refs/heads/").unwrap_or(&merge);
+        let (upstream, refname) = if remote == "." {
+            (name.to_string(), format!("refs/heads/{}", name))
         } else {
-            0
+            (
+                format!("{}/{}", remote, name),
+                format!("refs/remotes/{}/{}", remote, name),
+            )
         };
+        if name != short_branch && backend.ref_exists(dir, &refname) {
+            return Some(upstream);
+        }
+    }
+    None
+}
 
-        let is_current = current_stack_branch
-            .as_ref()
-            .map(|cs| cs == branch)
-            .unwrap_or(false);
+/// The base for branches with no configured base or upstream of their own:
+/// `origin/HEAD`, common names, and finally the currently checked-out branch.
+fn default_base_branch(
+    backend: &dyn GitBackend,
+    dir: &Path,
+) -> Result<(String, &'static str), String> {
+    if let Some(target) = backend.symbolic_target(dir, "refs/remotes/origin/HEAD") {
+        if backend.ref_exists(dir, &target) {
+            let short = target.strip_prefix("refs/remotes/").unwrap_or(&target);
+            return Ok((short.to_string(), "origin-head"));
+        }
+    }
 
-        stacks.push(GgStackInfo {
-            name: stack_name,
-            base,
-            commit_count,
-            is_current,
-            username,
-        });
+    for name in COMMON_BASE_BRANCHES {
+        if backend.ref_exists(dir, &format!("refs/heads/{}", name)) {
+            return Ok((name.to_string(), "common-name"));
+        }
+    }
+    for name in COMMON_BASE_BRANCHES {
+        if backend.ref_exists(dir, &format!("refs/remotes/origin/{}", name)) {
+            return Ok((format!("origin/{}", name), "common-name"));
+        }
     }
 
-    // Sort by name
-    stacks.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok((backend.current_branch(dir)?, "current-branch"))
+}
 
-    Ok(stacks)
+/// Resolve the base for `branch`, with its merge-base and the reason it was picked.
+pub fn resolve_branch_base(dir: &Path, branch: &str) -> Result<BranchBase, String> {
+    let backend = default_backend();
+    let (base, reason) = resolve_base_branch(backend, dir, branch)?;
+    Ok(BranchBase {
+        merge_base: backend.merge_base(dir, &base, branch).ok(),
+        base,
+        reason: reason.to_string(),
+    })
 }
 
-/// Get entries (commits) for a specific git-gud stack
-pub fn get_gg_stack_entries(dir: &Path, stack_name: &str) -> Result<Vec<GgStackEntry>, String> {
-    let config = read_gg_config(dir)?;
-    let default_base = get_default_base(&config);
+/// Set (or with `None`, clear) the repo's preferred base branch.
+pub fn set_base_branch(dir: &Path, base: Option<&str>) -> Result<(), String> {
+    let mut cmd = Command::new("git");
+    cmd.arg("config").arg("--local");
+    match base {
+        Some(base) => cmd.arg(BASE_BRANCH_CONFIG_KEY).arg(base),
+        None => cmd.arg("--unset").arg(BASE_BRANCH_CONFIG_KEY),
+    };
+    let output = cmd
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to execute git config: {}", e))?;
 
-    // Get base for this stack
-    let base = config
-        .get("stacks")
-        .and_then(|s| s.get(stack_name))
-        .and_then(|s| s.get("base"))
-        .and_then(|b| b.as_str())
-        .map(|s| s.to_string())
-        .unwrap_or(default_base);
+    // Exit code 5 means the key was already unset.
+    let already_unset = base.is_none() && output.status.code() == Some(5);
+    if !output.status.success() && !already_unset {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    Ok(())
+}
 
-    // Find the stack branch
-    let branches_output = Command::new("git")
-        .arg("for-each-ref")
-        .arg("--format=%(refname:short)")
-        .arg("refs/heads/")
+/// Returns the base ref used for branch diffs (same logic as get_branch_diff).
+pub fn get_branch_base(dir: &Path, branch: &str) -> Result<String, String> {
+    get_branch_base_with(default_backend(), dir, branch)
+}
+
+fn get_branch_base_with(
+    backend: &dyn GitBackend,
+    dir: &Path,
+    branch: &str,
+) -> Result<String, String> {
+    let (base, _) = resolve_base_branch(backend, dir, branch)?;
+
+    // Return the merge-base commit for accurate old-side resolution,
+    // falling back to the base branch name.
+    Ok(backend.merge_base(dir, &base, branch).unwrap_or(base))
+}
+
+/// Get diff and changed files comparing base branch and selected branch
+pub fn get_branch_diff(
+    dir: &Path,
+    branch: &str,
+    opts: &DiffOptions,
+) -> Result<GitDiffResult, String> {
+    let (base, _) = resolve_base_branch(default_backend(), dir, branch)?;
+
+    let range = format!("{}...{}", base, branch);
+
+    let diff_output = Command::new("git")
+        .arg("diff")
+        .arg("--no-color")
+        .args(opts.to_args())
+        .arg("--end-of-options")
+        .arg(&range)
         .current_dir(dir)
         .output()
-        .map_err(|e| format!("Failed to list branches: {}", e))?;
+        .map_err(|e| format!("Failed to execute branch diff: {}", e))?;
 
-    let branches_str = String::from_utf8_lossy(&branches_output.stdout);
-    let stack_branch = branches_str
-        .lines()
-        .find(|b| is_stack_branch(b) && extract_stack_name(b).as_deref() == Some(stack_name))
-        .ok_or_else(|| format!("Stack branch not found for: {}", stack_name))?;
+    if !diff_output.status.success() {
+        return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
+    }
 
-    // Get commits in the stack (from base to HEAD, in order)
-    let log_output = Command::new("git")
-        .arg("log")
-        .arg("--reverse")
-        .arg("--format=%H|%h|%s|%b")
-        .arg(format!("{}..{}", base, stack_branch))
+    let (diff, encodings) = decode_diff(&diff_output.stdout);
+
+    // Get changed files for this branch comparison
+    let files_output = Command::new("git")
+        .arg("diff")
+        .arg("--name-status")
+        .arg("-z")
+        .args(opts.to_args())
+        .arg("--end-of-options")
+        .arg(&range)
         .current_dir(dir)
         .output()
-        .map_err(|e| format!("Failed to get stack commits: {}", e))?;
+        .map_err(|e| format!("Failed to get changed files: {}", e))?;
+
+    if !files_output.status.success() {
+        return Err(String::from_utf8_lossy(&files_output.stderr).to_string());
+    }
+
+    let files = parse_file_status(&String::from_utf8_lossy(&files_output.stdout));
+
+    Ok(GitDiffResult::new(diff, files).with_encodings(&encodings))
+}
+
+// =============================================================================
+// git-gud Stack Support
+// =============================================================================
+
+/// Resolve the git directory for gg state files.
+///
+/// In a normal repo: `.git/gg/`
+/// In a worktree: `.git/worktrees/<name>/gg/` (where `.git` is a file
+/// containing `gitdir: /path/to/main/.git/worktrees/<name>`)
+fn resolve_gg_dir(dir: &Path) -> Option<PathBuf> {
+    use std::fs;
+
+    let dot_git = dir.join(".git");
+
+    if dot_git.is_dir() {
+        // Normal repo: .git is a directory
+        let gg_dir = dot_git.join("gg");
+        if gg_dir.exists() {
+            return Some(gg_dir);
+        }
+        return Some(gg_dir); // Return even if not exists (for has_gg_stacks check)
+    }
+
+    if dot_git.is_file() {
+        // Worktree: .git is a file with "gitdir: <path>"
+        if let Ok(content) = fs::read_to_string(&dot_git) {
+            if let Some(gitdir_str) = content.trim().strip_prefix("gitdir:") {
+                let gitdir = PathBuf::from(gitdir_str.trim());
+                // Resolve relative paths against the working dir
+                let gitdir = if gitdir.is_relative() {
+                    dir.join(&gitdir)
+                } else {
+                    gitdir
+                };
+                // Worktree git dir: e.g. /repo/.git/worktrees/<name>
+                // gg state lives at /repo/.git/worktrees/<name>/gg/
+                let gg_dir = gitdir.join("gg");
+                return Some(gg_dir);
+            }
+        }
+    }
+
+    None
+}
+
+/// Check if git-gud stacks are available in the repository
+pub fn has_gg_stacks(dir: &Path) -> bool {
+    resolve_gg_dir(dir)
+        .map(|gg_dir| gg_dir.join("config.json").exists())
+        .unwrap_or(false)
+}
+
+/// Read git-gud config file
+fn read_gg_config(dir: &Path) -> Result<serde_json::Value, String> {
+    use std::fs;
+    let gg_dir = resolve_gg_dir(dir).ok_or("Not a git repository")?;
+    let config_path = gg_dir.join("config.json");
+    let content =
+        fs::read_to_string(&config_path).map_err(|e| format!("Failed to read gg config: {}", e))?;
+    serde_json::from_str(&content).map_err(|e| format!("Failed to parse git-gud config: {}", e))
+}
+
+/// Get the default base branch from git-gud config
+fn get_default_base(config: &serde_json::Value) -> String {
+    config
+        .get("defaults")
+        .and_then(|d| d.get("base"))
+        .and_then(|b| b.as_str())
+        .unwrap_or("main")
+        .to_string()
+}
+
+/// Get the current stack name from .git/gg/current_stack
+fn get_current_stack(dir: &Path) -> Option<String> {
+    use std::fs;
+    let gg_dir = resolve_gg_dir(dir)?;
+    let current_stack_path = gg_dir.join("current_stack");
+    fs::read_to_string(current_stack_path)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+/// Extract GG-ID from commit message body
+fn extract_gg_id(commit_body: &str) -> Option<String> {
+    for line in commit_body.lines() {
+        let trimmed = line.trim();
+        if let Some(gg_id) = trimmed.strip_prefix("GG-ID:") {
+            return Some(gg_id.trim().to_string());
+        }
+    }
+    None
+}
+
+/// Parse username from branch name (e.g., "user/stack" -> "user")
+fn extract_username_from_branch(branch: &str) -> Option<String> {
+    branch.split('/').next().map(|s| s.to_string())
+}
+
+/// Check if a branch name is a stack branch (user/name without --)
+fn is_stack_branch(branch: &str) -> bool {
+    let parts: Vec<&str> = branch.split('/').collect();
+    if parts.len() != 2 {
+        return false;
+    }
+    !parts[1].contains("--")
+}
+
+/// Extract stack name from branch name (e.g., "user/stack" -> "stack")
+fn extract_stack_name(branch: &str) -> Option<String> {
+    let parts: Vec<&str> = branch.split('/').collect();
+    if parts.len() == 2 && !parts[1].contains("--") {
+        Some(parts[1].to_string())
+    } else {
+        None
+    }
+}
+
+/// Find the local branch backing a git-gud stack (e.g. "user/stack").
+fn find_stack_branch(
+    backend: &dyn GitBackend,
+    dir: &Path,
+    stack_name: &str,
+) -> Result<Option<String>, String> {
+    Ok(backend
+        .local_branches(dir)?
+        .into_iter()
+        .find(|b| is_stack_branch(b) && extract_stack_name(b).as_deref() == Some(stack_name)))
+}
+
+/// List all git-gud stacks in the repository
+pub fn list_gg_stacks(dir: &Path) -> Result<Vec<GgStackInfo>, String> {
+    let backend = default_backend();
+    let config = read_gg_config(dir)?;
+    let default_base = get_default_base(&config);
+    let current_stack_branch = get_current_stack(dir);
+
+    // Get all local branches
+    let branches = backend.local_branches(dir)?;
+    let mut stacks = Vec::new();
+    let mut seen_stacks = std::collections::HashSet::new();
+
+    // First, get username from git config as fallback
+    let default_username = backend
+        .config_value(dir, "user.name")
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // Find all stack branches
+    for branch in &branches {
+        if !is_stack_branch(branch) {
+            continue;
+        }
+
+        let Some(stack_name) = extract_stack_name(branch) else {
+            continue;
+        };
+
+        if seen_stacks.contains(&stack_name) {
+            continue;
+        }
+        seen_stacks.insert(stack_name.clone());
+
+        let username =
+            extract_username_from_branch(branch).unwrap_or_else(|| default_username.clone());
+
+        // Get base for this stack
+        let base = config
+            .get("stacks")
+            .and_then(|s| s.get(&stack_name))
+            .and_then(|s| s.get("base"))
+            .and_then(|b| b.as_str())
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| default_base.clone());
+
+        // Count commits in stack
+        let commit_count = backend.count_commits(dir, &base, branch).unwrap_or(0);
+
+        let is_current = current_stack_branch
+            .as_ref()
+            .map(|cs| cs == branch)
+            .unwrap_or(false);
+
+        stacks.push(GgStackInfo {
+            name: stack_name,
+            base,
+            commit_count,
+            is_current,
+            username,
+        });
+    }
+
+    // Sort by name
+    stacks.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(stacks)
+}
+
+/// Get entries (commits) for a specific git-gud stack
+pub fn get_gg_stack_entries(dir: &Path, stack_name: &str) -> Result<Vec<GgStackEntry>, String> {
+    let config = read_gg_config(dir)?;
+    let default_base = get_default_base(&config);
+
+    // Get base for this stack
+    let base = config
+        .get("stacks")
+        .and_then(|s| s.get(stack_name))
+        .and_then(|s| s.get("base"))
+        .and_then(|b| b.as_str())
+        .map(|s| s.to_string())
+        .unwrap_or(default_base);
+
+    // Find the stack branch
+    let stack_branch = find_stack_branch(default_backend(), dir, stack_name)?
+        .ok_or_else(|| format!("Stack branch not found for: {}", stack_name))?;
+
+    // Get commits in the stack (from base to HEAD, in order)
+    let log_output = Command::new("git")
+        .arg("log")
+        .arg("--reverse")
+        .arg("-z")
+        .arg("--format=%H%x1f%h%x1f%s%x1f%b")
+        .arg("--end-of-options")
+        .arg(format!("{}..{}", base, stack_branch))
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to get stack commits: {}", e))?;
+
+    if !log_output.status.success() {
+        return Err(String::from_utf8_lossy(&log_output.stderr).to_string());
+    }
+
+    let log_str = String::from_utf8_lossy(&log_output.stdout);
+    let mut entries = parse_stack_log(&log_str);
+
+    // Get MR numbers from config
+    let mrs = config
+        .get("stacks")
+        .and_then(|s| s.get(stack_name))
+        .and_then(|s| s.get("mrs"));
+    for entry in &mut entries {
+        entry.mr_number = entry
+            .gg_id
+            .as_ref()
+            .and_then(|id| mrs.and_then(|mrs| mrs.get(id)))
+            .and_then(|n| n.as_u64());
+    }
+
+    Ok(entries)
+}
+
+/// Parse `git log -z --format=%H%x1f%h%x1f%s%x1f%b` into stack entries in
+/// log order. Bodies may hold blank lines and any punctuation.
+fn parse_stack_log(output: &str) -> Vec<GgStackEntry> {
+    output
+        .split('\0')
+        .filter(|record| !record.trim().is_empty())
+        .filter_map(|record| {
+            let fields: Vec<&str> = record.splitn(4, '\x1f').collect();
+            if fields.len() < 3 {
+                return None;
+            }
+            Some((fields[0], fields[1], fields[2], fields.get(3).copied()))
+        })
+        .enumerate()
+        .map(|(position, (hash, short_hash, title, body))| GgStackEntry {
+            hash: hash.to_string(),
+            short_hash: short_hash.to_string(),
+            title: title.to_string(),
+            gg_id: body.and_then(extract_gg_id),
+            mr_number: None,
+            position,
+        })
+        .collect()
+}
+
+/// Get the merge-base between two arbitrary refs
+pub fn get_merge_base_refs(dir: &Path, ref1: &str, ref2: &str) -> Result<String, String> {
+    default_backend().merge_base(dir, ref1, ref2)
+}
+
+/// Get the base ref for a git-gud stack
+#[derive(Clone, serde::Serialize)]
+pub struct GgStackBaseInfo {
+    pub base: String,
+    pub branch: String,
+}
+
+pub fn get_gg_stack_base(dir: &Path, stack_name: &str) -> Result<GgStackBaseInfo, String> {
+    let config = read_gg_config(dir)?;
+    let default_base = get_default_base(&config);
+
+    let base = config
+        .get("stacks")
+        .and_then(|s| s.get(stack_name))
+        .and_then(|s| s.get("base"))
+        .and_then(|b| b.as_str())
+        .map(|s| s.to_string())
+        .unwrap_or(default_base);
+
+    // Find the stack branch name
+    let branch = find_stack_branch(default_backend(), dir, stack_name)?
+        .unwrap_or_else(|| stack_name.to_string());
+
+    Ok(GgStackBaseInfo { base, branch })
+}
+
+/// Get diff for entire git-gud stack (base..stack-head)
+pub fn get_gg_stack_diff(
+    dir: &Path,
+    stack_name: &str,
+    opts: &DiffOptions,
+) -> Result<String, String> {
+    let config = read_gg_config(dir)?;
+    let default_base = get_default_base(&config);
+
+    // Get base for this stack
+    let base = config
+        .get("stacks")
+        .and_then(|s| s.get(stack_name))
+        .and_then(|s| s.get("base"))
+        .and_then(|b| b.as_str())
+        .map(|s| s.to_string())
+        .unwrap_or(default_base);
+
+    // Find the stack branch
+    let stack_branch = find_stack_branch(default_backend(), dir, stack_name)?
+        .ok_or_else(|| format!("Stack branch not found for: {}", stack_name))?;
+
+    // Get diff
+    let diff_output = Command::new("git")
+        .arg("diff")
+        .arg("--no-color")
+        .args(opts.to_args())
+        .arg("--end-of-options")
+        .arg(format!("{}..{}", base, stack_branch))
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to get stack diff: {}", e))?;
+
+    if !diff_output.status.success() {
+        return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
+    }
+
+    Ok(decode_diff(&diff_output.stdout).0)
+}
+
+/// Get diff for a single commit in a git-gud stack
+pub fn get_gg_entry_diff(
+    dir: &Path,
+    _stack_name: &str,
+    hash: &str,
+    opts: &DiffOptions,
+) -> Result<String, String> {
+    // Use git show to get the diff for a single commit
+    let diff_output = Command::new("git")
+        .arg("show")
+        .arg("--format=")
+        .arg("--no-color")
+        .args(opts.to_args())
+        .arg("--end-of-options")
+        .arg(hash)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to get entry diff: {}", e))?;
+
+    if !diff_output.status.success() {
+        return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
+    }
+
+    Ok(decode_diff(&diff_output.stdout).0)
+}
+
+// =============================================================================
+// Blame
+// =============================================================================
+
+/// Who last touched one line, from `git blame`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BlameLine {
+    pub line: u32, // line number in the blamed version
+    pub hash: String,
+    pub author: String,
+    pub author_email: String,
+    pub timestamp: i64, // author time, unix seconds
+    pub summary: String,
+    pub content: String,
+    pub uncommitted: bool, // line only exists in the working tree
+}
+
+/// Blame lines `start..=end` (1-based) of `file_path`. With no ref the working
+/// tree version is blamed, and locally modified lines come back `uncommitted`.
+/// An `end` past the last line is clamped to it.
+pub fn blame_range(
+    dir: &Path,
+    git_ref: Option<&str>,
+    file_path: &str,
+    start: u32,
+    end: u32,
+) -> Result<Vec<BlameLine>, String> {
+    if start == 0 || end < start {
+        return Err(format!("Invalid line range: {}-{}", start, end));
+    }
+    // blame has no --end-of-options to protect its revision.
+    if let Some(rev) = git_ref.filter(|rev| rev.starts_with('-')) {
+        return Err(format!("Invalid revision: {}", rev));
+    }
+
+    let mut cmd = Command::new("git");
+    cmd.arg("blame")
+        .arg("--line-porcelain")
+        .arg(format!("-L{},{}", start, end));
+    if let Some(git_ref) = git_ref.filter(|r| !r.is_empty()) {
+        cmd.arg(git_ref);
+    }
+    let output = cmd
+        .arg("--")
+        .arg(file_path)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to execute git blame: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+
+    Ok(parse_blame_porcelain(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Parse `git blame --line-porcelain`, where every line repeats its commit info.
+fn parse_blame_porcelain(output: &str) -> Vec<BlameLine> {
+    let mut lines = Vec::new();
+    let mut current: Option<BlameLine> = None;
+
+    for row in output.lines() {
+        // The line itself ends each record.
+        if let Some(content) = row.strip_prefix('\t') {
+            if let Some(mut line) = current.take() {
+                line.content = content.to_string();
+                lines.push(line);
+            }
+            continue;
+        }
+
+        let Some(line) = current.as_mut() else {
+            // "<hash> <original line> <final line> [<group size>]"
+            let mut parts = row.split(' ');
+            let hash = parts.next().unwrap_or_default();
+            let final_line = parts.nth(1).and_then(|n| n.parse().ok());
+            if let Some(final_line) = final_line {
+                current = Some(BlameLine {
+                    line: final_line,
+                    hash: hash.to_string(),
+                    uncommitted: hash.bytes().all(|b| b == b'0'),
+                    ..Default::default()
+                });
+            }
+            continue;
+        };
+
+        let (key, value) = row.split_once(' ').unwrap_or((row, ""));
+        match key {
+            "author" => line.author = value.to_string(),
+            "author-mail" => {
+                line.author_email = value
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            }
+            "author-time" => line.timestamp = value.parse().unwrap_or(0),
+            "summary" => line.summary = value.to_string(),
+            _ => {}
+        }
+    }
+
+    lines
+}
+
+// =============================================================================
+// In-progress Operations and Conflicts
+// =============================================================================
+
+/// The operation a repository is in the middle of, if any, and what is
+/// still conflicted.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RepoState {
+    pub operation: Option<String>, // "merge", "rebase", "am", "cherry-pick", "revert"
+    pub operation_head: Option<String>, // commit being merged/applied/picked/reverted
+    pub rebase_branch: Option<String>, // branch being rebased, e.g. "feature"
+    pub rebase_step: Option<u32>,
+    pub rebase_total: Option<u32>,
+    pub conflicted_files: Vec<ConflictedFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConflictedFile {
+    pub path: String,
+    pub conflict: String, // "both-modified", "both-added", "deleted-by-us", ...
+}
+
+/// The three sides of a conflicted file, read from index stages 1-3. A side
+/// is `None` when it does not exist (e.g. the file was deleted by "them").
+/// During a rebase "ours" is the branch being rebased onto.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConflictVersions {
+    pub path: String,
+    pub base: Option<String>,
+    pub ours: Option<String>,
+    pub theirs: Option<String>,
+    pub is_binary: bool,
+    pub diff: GitDiffResult, // ours to theirs, where the two sides disagree
+}
+
+/// Report the merge/rebase/cherry-pick/revert in progress and the conflicted files.
+pub fn get_repo_state(dir: &Path) -> Result<RepoState, String> {
+    let git_dir = default_backend()
+        .git_dir(dir)
+        .ok_or_else(|| "Not a git repository".to_string())?;
+    let read = |name: &str| {
+        fs::read_to_string(git_dir.join(name))
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+    };
+
+    let mut state = RepoState::default();
+    let rebase_dir = ["rebase-merge", "rebase-apply"]
+        .into_iter()
+        .find(|d| git_dir.join(d).is_dir());
+
+    if let Some(rebase_dir) = rebase_dir {
+        let is_am = git_dir.join(rebase_dir).join("applying").exists();
+        state.operation = Some(if is_am { "am" } else { "rebase" }.to_string());
+        state.operation_head = read("REBASE_HEAD");
+        state.rebase_branch = read(&format!("{}/head-name", rebase_dir)).map(|name| {
+            name.strip_prefix("refs/heads/")
+                .unwrap_or(&name)
+                .to_string()
+        });
+        let (step, total) = if rebase_dir == "rebase-merge" {
+            ("msgnum", "end")
+        } else {
+            ("next", "last")
+        };
+        state.rebase_step = read(&format!("{}/{}", rebase_dir, step)).and_then(|n| n.parse().ok());
+        state.rebase_total =
+            read(&format!("{}/{}", rebase_dir, total)).and_then(|n| n.parse().ok());
+    } else {
+        for (head, operation) in [
+            ("MERGE_HEAD", "merge"),
+            ("CHERRY_PICK_HEAD", "cherry-pick"),
+            ("REVERT_HEAD", "revert"),
+        ] {
+            if let Some(hash) = read(head) {
+                state.operation = Some(operation.to_string());
+                // An octopus merge lists one head per line; report the first.
+                state.operation_head = hash.lines().next().map(|h| h.to_string());
+                break;
+            }
+        }
+    }
+
+    state.conflicted_files = get_conflicted_files(dir)?;
+    Ok(state)
+}
+
+/// Unmerged paths from `git ls-files -u`, classified by which stages exist.
+fn get_conflicted_files(dir: &Path) -> Result<Vec<ConflictedFile>, String> {
+    let output = Command::new("git")
+        .args(["ls-files", "-u", "-z"])
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to list conflicted files: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+
+    Ok(parse_unmerged_entries(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Parse NUL-separated "<mode> <hash> <stage>\t<path>" entries.
+fn parse_unmerged_entries(output: &str) -> Vec<ConflictedFile> {
+    let mut stages: Vec<(String, [bool; 3])> = Vec::new();
+    for entry in output.split('\0').filter(|e| !e.is_empty()) {
+        let Some((info, path)) = entry.split_once('\t') else {
+            continue;
+        };
+        let Some(stage) = info
+            .split_whitespace()
+            .nth(2)
+            .and_then(|s| s.parse::<usize>().ok())
+            .filter(|s| (1..=3).contains(s))
+        else {
+            continue;
+        };
+        // Stages of the same path are listed together.
+        match stages.last_mut() {
+            Some((last, seen)) if last == path => seen[stage - 1] = true,
+            _ => {
+                let mut seen = [false; 3];
+                seen[stage - 1] = true;
+                stages.push((path.to_string(), seen));
+            }
+        }
+    }
+
+    stages
+        .into_iter()
+        .map(|(path, seen)| {
+            let conflict = match seen {
+                [true, true, true] => "both-modified",
+                [false, true, true] => "both-added",
+                [true, true, false] => "deleted-by-them",
+                [true, false, true] => "deleted-by-us",
+                [false, true, false] => "added-by-us",
+                [false, false, true] => "added-by-them",
+                _ => "both-deleted",
+            };
+            ConflictedFile {
+                path,
+                conflict: conflict.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Read the base, ours and theirs versions of a conflicted file from the
+/// index, and diff ours against theirs.
+pub fn get_conflict_versions(
+    dir: &Path,
+    file_path: &str,
+    opts: &DiffOptions,
+) -> Result<ConflictVersions, String> {
+    let backend = default_backend();
+    let sides: Vec<Option<Vec<u8>>> = (1..=3)
+        .map(|stage| {
+            backend
+                .read_blob(dir, &format!(":{}", stage), file_path)
+                .ok()
+        })
+        .collect();
+
+    if sides.iter().all(|side| side.is_none()) {
+        return Err(format!("{} is not conflicted", file_path));
+    }
+
+    let is_binary = sides
+        .iter()
+        .flatten()
+        .any(|bytes| bytes.contains(&0) && !encoding::is_utf16_text(bytes, None));
+    let diff = get_conflict_diff(dir, file_path, is_binary, opts)?;
+    let mut text = sides
+        .into_iter()
+        .map(|side| side.map(|bytes| encoding::decode(&bytes, None).0));
+
+    Ok(ConflictVersions {
+        path: file_path.to_string(),
+        base: text.next().flatten(),
+        ours: text.next().flatten(),
+        theirs: text.next().flatten(),
+        is_binary,
+        diff,
+    })
+}
+
+/// Diff of the "ours" stage of a conflicted file against "theirs": git's own
+/// hunks between the two blobs, under a header naming the file. A missing
+/// side is diffed as an empty file, so it reads as an addition or deletion.
+fn get_conflict_diff(
+    dir: &Path,
+    file_path: &str,
+    is_binary: bool,
+    opts: &DiffOptions,
+) -> Result<GitDiffResult, String> {
+    let output = Command::new("git")
+        .args([
+            "--literal-pathspecs",
+            "ls-files",
+            "-u",
+            "-z",
+            "--",
+            file_path,
+        ])
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to list conflicted files: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    // "<mode> <hash> <stage>\t<path>" for each stage there is.
+    let listing = String::from_utf8_lossy(&output.stdout);
+    let stage = |wanted: &str| {
+        listing.split('\0').find_map(|entry| {
+            let (info, _) = entry.split_once('\t')?;
+            let fields: Vec<&str> = info.split_whitespace().collect();
+            (fields.get(2) == Some(&wanted)).then(|| (fields[0].to_string(), fields[1].to_string()))
+        })
+    };
+    let (ours, theirs) = (stage("2"), stage("3"));
+
+    let (a, b) = diff_header_paths(file_path);
+    let mut diff = format!("diff --git {} {}\n", a, b);
+    let status = match (&ours, &theirs) {
+        (None, Some((mode, _))) => {
+            diff.push_str(&format!("new file mode {}\n", mode));
+            "added"
+        }
+        (Some((mode, _)), None) => {
+            diff.push_str(&format!("deleted file mode {}\n", mode));
+            "deleted"
+        }
+        (Some((old_mode, _)), Some((new_mode, _))) if old_mode != new_mode => {
+            diff.push_str(&format!("old mode {}\nnew mode {}\n", old_mode, new_mode));
+            "modified"
+        }
+        _ => "modified",
+    };
+    let old_name = if ours.is_some() {
+        a.as_str()
+    } else {
+        "/dev/null"
+    };
+    let new_name = if theirs.is_some() {
+        b.as_str()
+    } else {
+        "/dev/null"
+    };
+
+    let mut encodings = Vec::new();
+    if is_binary {
+        diff.push_str(&format!(
+            "Binary files {} and {} differ\n",
+            old_name, new_name
+        ));
+    } else {
+        let empty = || -> Result<String, String> {
+            let output = git_with_input(dir, &["hash-object", "-w", "--stdin"], Vec::new())?;
+            if !output.status.success() {
+                return Err(String::from_utf8_lossy(&output.stderr).to_string());
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        };
+        let blob = |side: &Option<(String, String)>| match side {
+            Some((_, hash)) => Ok(hash.clone()),
+            None => empty(),
+        };
+        let output = Command::new("git")
+            .arg("diff")
+            .arg("--no-color")
+            .arg("--no-ext-diff")
+            .args(opts.to_args())
+            .arg("--end-of-options")
+            .arg(blob(&ours)?)
+            .arg(blob(&theirs)?)
+            .current_dir(dir)
+            .output()
+            .map_err(|e| format!("Failed to execute git diff: {}", e))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).to_string());
+        }
+
+        // Keep git's hunks; its header names the blobs, not the file.
+        let (text, found) = decode_diff(&output.stdout);
+        if let Some(start) = text.find("\n@@ ") {
+            diff.push_str(&format!("--- {}\n+++ {}\n", old_name, new_name));
+            diff.push_str(&text[start + 1..]);
+        }
+        encodings = found;
+    }
+
+    let files = vec![GitFile {
+        path: file_path.to_string(),
+        status: status.to_string(),
+        ..Default::default()
+    }];
+    Ok(GitDiffResult::new(diff, files).with_encodings(&encodings))
+}
+
+// =============================================================================
+// Submodules
+// =============================================================================
+
+/// A submodule pointer change expanded into the submodule's own history.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubmoduleDiff {
+    pub path: String,
+    pub commits: Vec<CommitInfo>, // commits in old..new, newest first
+    pub diff: GitDiffResult,      // paths are relative to the submodule
+}
+
+/// Expand a submodule (or nested repository) that moved from `old_commit` to
+/// `new_commit`, as reported in `GitFile::submodule`. The submodule has to be
+/// checked out and have both commits.
+pub fn get_submodule_diff(
+    dir: &Path,
+    submodule_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+    opts: &DiffOptions,
+) -> Result<SubmoduleDiff, String> {
+    let sub_dir = dir.join(submodule_path);
+    if !is_nested_repo(&sub_dir) {
+        return Err(format!("Submodule {} is not checked out", submodule_path));
+    }
+
+    let range = format!("{}..{}", old_commit, new_commit);
+    let commits = list_commits(
+        &sub_dir,
+        &LogOptions {
+            range: Some(range.clone()),
+            ..Default::default()
+        },
+    )?;
+    let diff = get_range_diff(&sub_dir, &range, opts)?;
+
+    Ok(SubmoduleDiff {
+        path: submodule_path.to_string(),
+        commits,
+        diff,
+    })
+}
+
+// =============================================================================
+// Review snapshots
+// =============================================================================
+
+/// Private ref namespace for reviewed states, one ref per branch. Refs live in
+/// the repo's own ref store, so the repo half of the key comes for free.
+const REVIEWED_REF_PREFIX: &str = "refs/air/reviewed/";
+
+/// Identity for snapshot commits, so they can be written without user config.
+const SNAPSHOT_IDENTITY: [(&str, &str); 4] = [
+    ("GIT_AUTHOR_NAME", "air"),
+    ("GIT_AUTHOR_EMAIL", "air@localhost"),
+    ("GIT_COMMITTER_NAME", "air"),
+    ("GIT_COMMITTER_EMAIL", "air@localhost"),
+];
+
+/// The state of the repo when feedback was last submitted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReviewSnapshot {
+    pub ref_name: String,
+    pub commit: String,       // snapshot commit, laid out like a stash entry
+    pub tree: String,         // worktree contents, untracked files included
+    pub head: Option<String>, // None on an unborn branch
+    pub timestamp: i64,
+}
+
+/// Detached HEADs get a ref per worktree, since refs are shared between
+/// worktrees while each has a HEAD of its own.
+const DETACHED_REVIEWED_REF_PREFIX: &str = "refs/air/reviewed-detached/";
+
+fn reviewed_ref(dir: &Path) -> String {
+    let backend = default_backend();
+    match backend.symbolic_target(dir, "HEAD") {
+        Some(target) => format!(
+            "{}{}",
+            REVIEWED_REF_PREFIX,
+            target.strip_prefix("refs/heads/").unwrap_or(&target)
+        ),
+        None => {
+            let worktree = backend.toplevel(dir).unwrap_or_else(|| dir.to_path_buf());
+            let key = backend
+                .hash_blob(dir, worktree.to_string_lossy().as_bytes())
+                .map(|hash| hash[..16].to_string())
+                .unwrap_or_else(|_| "HEAD".to_string());
+            format!("{}{}", DETACHED_REVIEWED_REF_PREFIX, key)
+        }
+    }
+}
+
+/// Freeze the index and worktree into git objects, laid out like a stash
+/// entry: a worktree commit (untracked files included, ignored files left
+/// out) whose parents are HEAD and a commit of the index. On an unborn branch
+/// the index commit is the only parent. Untracked files over the size limits
+/// of `opts` are left out too, as a diff would leave out their content.
+/// Works on a copy of the index, so nothing the user has staged is touched.
+/// Returns the worktree commit.
+fn snapshot_worktree(dir: &Path, message: &str, opts: &DiffOptions) -> Result<String, String> {
+    let git_dir = default_backend()
+        .git_dir(dir)
+        .ok_or_else(|| format!("Not a git repository: {}", dir.display()))?;
+    let index = git_dir.join(format!("air-snapshot-index-{}", std::process::id()));
+
+    let real_index = git_dir.join("index");
+    if real_index.exists() {
+        fs::copy(&real_index, &index).map_err(|e| format!("Failed to copy index: {}", e))?;
+        // Git trusts the cached stat data of entries older than the index
+        // file itself; a fresh mtime on the copy would hide a file rewritten
+        // at the same size within the same second.
+        let modified = fs::metadata(&real_index).and_then(|meta| meta.modified());
+        fs::File::options()
+            .write(true)
+            .open(&index)
+            .and_then(|file| file.set_modified(modified?))
+            .map_err(|e| format!("Failed to copy index: {}", e))?;
+    }
+    let oversized = oversized_untracked_files(dir, opts)?;
+    let result = write_snapshot_commit(dir, &index, message, &oversized);
+    let _ = fs::remove_file(&index);
+    result
+}
+
+fn write_snapshot_commit(
+    dir: &Path,
+    index: &Path,
+    message: &str,
+    excluded: &[String],
+) -> Result<String, String> {
+    let run_with_input = |args: &[&str], input: &[u8]| -> Result<String, String> {
+        let mut child = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .env("GIT_INDEX_FILE", index)
+            .envs(SNAPSHOT_IDENTITY)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to execute git {}: {}", args[0], e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(input)
+                .map_err(|e| format!("Failed to write to git {}: {}", args[0], e))?;
+        }
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("Failed to execute git {}: {}", args[0], e))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).to_string());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let run = |args: &[&str]| run_with_input(args, &[]);
+
+    let commit = |tree: &str, parents: &[&str], message: &str| {
+        let mut args = vec!["commit-tree", tree, "-m", message];
+        for parent in parents {
+            args.extend(["-p", parent]);
+        }
+        run(&args)
+    };
+
+    let head = default_backend().rev_parse(dir, "HEAD^{commit}").ok();
+    let head_parents: Vec<&str> = head.iter().map(String::as_str).collect();
+
+    // An index with unresolved conflicts can't be written as a tree; treat
+    // it as matching HEAD, like `git diff --staged` mostly does.
+    let index_tree = match run(&["write-tree"]) {
+        Ok(tree) => tree,
+        Err(_) => match &head {
+            Some(head) => run(&["rev-parse", &format!("{}^{{tree}}", head)])?,
+            None => run(&["mktree"])?,
+        },
+    };
+    let index = commit(&index_tree, &head_parents, "air: index")?;
+
+    // The whole tree but the excluded files, read from stdin so any number
+    // of them fits.
+    let mut pathspecs = b":/\0".to_vec();
+    for path in excluded {
+        pathspecs.extend_from_slice(format!(":(exclude,literal){}\0", path).as_bytes());
+    }
+    run_with_input(
+        &["add", "-A", "--pathspec-from-file=-", "--pathspec-file-nul"],
+        &pathspecs,
+    )?;
+    let tree = run(&["write-tree"])?;
+    let mut parents = head_parents;
+    parents.push(&index);
+    commit(&tree, &parents, message)
+}
+
+/// Split a snapshot commit into the HEAD it was taken on and its index commit.
+fn snapshot_parents(dir: &Path, snapshot: &str) -> Result<(Option<String>, String), String> {
+    let mut parents = default_backend().commit_parents(dir, snapshot)?;
+    let index = parents
+        .pop()
+        .ok_or_else(|| format!("{} is not a worktree snapshot", snapshot))?;
+    Ok((parents.pop(), index))
+}
+
+/// Record the reviewed state of the checked-out branch: the review session
+/// the reviewer was shown, when there was one, or else the current worktree.
+/// Edits made while the session was open weren't reviewed, so they show up
+/// in the next diff since review.
+pub fn record_review_snapshot(dir: &Path, session: Option<&str>) -> Result<ReviewSnapshot, String> {
+    let ref_name = reviewed_ref(dir);
+    let commit = match session {
+        Some(session) => get_review_session(dir, session)?.id,
+        None => snapshot_worktree(dir, "air: reviewed state", &DiffOptions::default())?,
+    };
+
+    let output = Command::new("git")
+        .args(["update-ref", "-m", "air: feedback submitted"])
+        .arg(&ref_name)
+        .arg(&commit)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to execute git update-ref: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+
+    get_review_snapshot(dir)?.ok_or_else(|| format!("Failed to read back {}", ref_name))
+}
+
+/// The last reviewed state of the checked-out branch, if feedback was ever
+/// submitted on it.
+pub fn get_review_snapshot(dir: &Path) -> Result<Option<ReviewSnapshot>, String> {
+    let ref_name = reviewed_ref(dir);
+    if !default_backend().ref_exists(dir, &ref_name) {
+        return Ok(None);
+    }
+
+    let output = Command::new("git")
+        .args(["log", "-1", "--format=%H%x1f%T%x1f%ct"])
+        .arg(&ref_name)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to read {}: {}", ref_name, e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = stdout.trim_end().split('\x1f').collect();
+    if fields.len() < 3 {
+        return Err(format!("Unexpected snapshot format for {}", ref_name));
+    }
+    let (head, _) = snapshot_parents(dir, fields[0])?;
+    Ok(Some(ReviewSnapshot {
+        commit: fields[0].to_string(),
+        tree: fields[1].to_string(),
+        head,
+        timestamp: fields[2].parse().unwrap_or(0),
+        ref_name,
+    }))
+}
+
+/// Diff from the last reviewed state to the current worktree, untracked files
+/// included, so only what changed since feedback was submitted shows up.
+pub fn get_diff_since_review(dir: &Path, opts: &DiffOptions) -> Result<GitDiffResult, String> {
+    let snapshot = get_review_snapshot(dir)?
+        .ok_or_else(|| "No review has been submitted on this branch yet".to_string())?;
+    // Taken with the same limits as the reviewed state, so files left out of
+    // both don't show up as removed.
+    let current = snapshot_worktree(dir, "air: current state", &DiffOptions::default())?;
+    get_range_diff(dir, &format!("{}..{}", snapshot.commit, current), opts)
+}
+
+/// A frozen view of the index and worktree that every diff and file read of a
+/// review session is served from, so edits made while reviewing don't make
+/// the file list, diff and expanded context disagree. The objects are
+/// unreferenced; `git gc` keeps them for its prune window (two weeks by
+/// default), far longer than a session.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReviewSession {
+    pub id: String, // worktree snapshot commit; pass back as the `session` param
+    pub head: Option<String>,
+    pub index: String, // commit of the index as it was at open time
+    pub timestamp: i64,
+}
+
+/// Freeze the current index and worktree for a review session. Untracked
+/// files over the size limits of `opts` are left out of it.
+pub fn open_review_session(dir: &Path, opts: &DiffOptions) -> Result<ReviewSession, String> {
+    let id = snapshot_worktree(dir, "air: review session", opts)?;
+    get_review_session(dir, &id)
+}
+
+/// Look up a session opened earlier by its id.
+pub fn get_review_session(dir: &Path, session: &str) -> Result<ReviewSession, String> {
+    let id = default_backend().rev_parse(dir, &format!("{}^{{commit}}", session))?;
+    let (head, index) = snapshot_parents(dir, &id)?;
+    let output = Command::new("git")
+        .args(["log", "-1", "--format=%ct"])
+        .arg(&id)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to read session {}: {}", session, e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    Ok(ReviewSession {
+        timestamp: String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .map_err(|e| format!("Failed to read session {}: {}", session, e))?,
+        id,
+        head,
+        index,
+    })
+}
+
+/// What `git_ref` pointed at when the session was opened: the index (":0")
+/// is the session's index commit and HEAD the commit it was opened on. Other
+/// refs come back as they are.
+pub fn resolve_session_ref(dir: &Path, session: &str, git_ref: &str) -> Result<String, String> {
+    let session = get_review_session(dir, session)?;
+    Ok(match (git_ref, session.head) {
+        (":0", _) => session.index,
+        ("HEAD", Some(head)) => head,
+        _ => git_ref.to_string(),
+    })
+}
+
+/// The session's unstaged changes: index to worktree, with untracked files
+/// showing up as added.
+pub fn get_session_unstaged_diff(
+    dir: &Path,
+    session: &str,
+    opts: &DiffOptions,
+) -> Result<GitDiffResult, String> {
+    let session = get_review_session(dir, session)?;
+    get_range_diff(dir, &format!("{}..{}", session.index, session.id), opts)
+}
+
+/// The session's staged changes: HEAD to index.
+pub fn get_session_staged_diff(
+    dir: &Path,
+    session: &str,
+    opts: &DiffOptions,
+) -> Result<GitDiffResult, String> {
+    let session = get_review_session(dir, session)?;
+    // The index commit's only parent is HEAD (none on an unborn branch).
+    get_commit_diff(dir, &session.index, opts)
+}
+
+/// Whether the session has staged and unstaged changes, as of open time.
+pub fn get_session_change_status(dir: &Path, session: &str) -> Result<GitChangeStatus, String> {
+    let session = get_review_session(dir, session)?;
+    let backend = default_backend();
+    let tree = |rev: &str| backend.rev_parse(dir, &format!("{}^{{tree}}", rev));
+    let index_tree = tree(&session.index)?;
+    let head_tree = match &session.head {
+        Some(head) => Some(tree(head)?),
+        None => None,
+    };
+    Ok(GitChangeStatus {
+        has_staged: head_tree.as_deref() != Some(index_tree.as_str()),
+        has_unstaged: tree(&session.id)? != index_tree,
+    })
+}
+
+// =============================================================================
+// Partial staging
+// =============================================================================
+
+/// An inclusive range of line numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// The changes in one file to stage or unstage. Removed lines are picked by
+/// their old line number and added lines by their new one, so a whole hunk
+/// is just its old and new ranges.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PatchSelection {
+    pub file_path: String,
+    pub old_lines: Vec<LineRange>,
+    pub new_lines: Vec<LineRange>,
+    /// Blob ids from the "index" line of the diff the selection was made on.
+    /// When given, a file that has changed since is a conflict.
+    pub old_blob: Option<String>,
+    pub new_blob: Option<String>,
+}
+
+impl PatchSelection {
+    fn takes(ranges: &[LineRange], line: u32) -> bool {
+        ranges
+            .iter()
+            .any(|range| (range.start..=range.end).contains(&line))
+    }
+}
+
+/// Copy the selected worktree changes into the index.
+pub fn stage_selection(dir: &Path, selections: &[PatchSelection]) -> Result<(), String> {
+    let patch = selection_patch(dir, selections, false, false)?;
+    apply_patch(dir, patch, &["--cached"], "the index")
+}
+
+/// Take the selected changes back out of the index, leaving the worktree
+/// as it is.
+pub fn unstage_selection(dir: &Path, selections: &[PatchSelection]) -> Result<(), String> {
+    let patch = selection_patch(dir, selections, true, true)?;
+    apply_patch(dir, patch, &["--cached", "--reverse"], "the index")
+}
+
+/// Cut each file's staged or unstaged diff down to its selection, ready to
+/// be applied forwards or in `reverse`. Files are combined into one patch
+/// so that either every file's part lands or none does.
+fn selection_patch(
+    dir: &Path,
+    selections: &[PatchSelection],
+    staged: bool,
+    reverse: bool,
+) -> Result<Vec<u8>, String> {
+    let mut patch = Vec::new();
+    for selection in selections {
+        let file_patch = file_patch(dir, &selection.file_path, staged)?;
+        check_selection_base(selection, &file_patch, staged)?;
+        let part = select_patch_lines(
+            &file_patch,
+            |line| PatchSelection::takes(&selection.old_lines, line),
+            |line| PatchSelection::takes(&selection.new_lines, line),
+            reverse,
+        )
+        .ok_or_else(|| format!("No changes selected in {}", selection.file_path))?;
+        patch.extend(part);
+    }
+    Ok(patch)
+}
+
+/// `git apply` a patch to the index or worktree. A patch that no longer
+/// fits, which `git apply --check` turns down with exit status 1, is
+/// reported as a conflict with `target`; any other failure (128 for a patch
+/// it can't read) is passed on as git reported it.
+fn apply_patch(dir: &Path, patch: Vec<u8>, flags: &[&str], target: &str) -> Result<(), String> {
+    if patch.is_empty() {
+        return Ok(());
+    }
+    let mut args = vec!["apply", "--whitespace=nowarn"];
+    args.extend(flags);
+
+    let mut check = args.clone();
+    check.push("--check");
+    let output = git_with_input(dir, &check, patch.clone())?;
+    if output.status.code() == Some(1) {
+        return Err(format!(
+            "Conflict: {} changed underneath the selection: {}",
+            target,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let output = if output.status.success() {
+        git_with_input(dir, &args, patch)?
+    } else {
+        output
+    };
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(())
+}
+
+/// The current diff of one file, index to worktree or HEAD to index, in a
+/// shape `git apply` takes back whatever the user's diff config says.
+/// Untracked files are diffed against /dev/null.
+fn file_patch(dir: &Path, file_path: &str, staged: bool) -> Result<Vec<u8>, String> {
+    let tracked = staged || {
+        let output = Command::new("git")
+            .args(["--literal-pathspecs", "ls-files", "-z", "--", file_path])
+            .current_dir(dir)
+            .output()
+            .map_err(|e| format!("Failed to execute git ls-files: {}", e))?;
+        !output.stdout.is_empty()
+    };
+
+    let mut cmd = Command::new("git");
+    cmd.args([
+        "--literal-pathspecs",
+        "diff",
+        "--no-color",
+        "--no-ext-diff",
+        "--no-textconv",
+        "--no-renames",
+        "--binary",
+        "--full-index",
+        "--src-prefix=a/",
+        "--dst-prefix=b/",
+    ]);
+    if staged {
+        cmd.arg("--cached");
+    }
+    if tracked {
+        cmd.args(["--", file_path]);
+    } else {
+        cmd.args(["--no-index", "--", "/dev/null", file_path]);
+    }
+    let output = cmd
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to execute git diff: {}", e))?;
+
+    // `--no-index` exits 1 when the files differ, as they always do here.
+    let ok = output.status.success() || (!tracked && output.status.code() == Some(1));
+    if !ok {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    Ok(output.stdout)
+}
+
+/// Refuse a selection made on a diff the file no longer has.
+fn check_selection_base(
+    selection: &PatchSelection,
+    file_patch: &[u8],
+    staged: bool,
+) -> Result<(), String> {
+    let Some(current) = parse_unified_diff(&String::from_utf8_lossy(file_patch))
+        .into_iter()
+        .next()
+    else {
+        return Err(format!(
+            "Conflict: {} has no {} changes any more",
+            selection.file_path,
+            if staged { "staged" } else { "unstaged" }
+        ));
+    };
+
+    // Ids are abbreviated, possibly to different lengths.
+    let same = |expected: &Option<String>, actual: &Option<String>| match (expected, actual) {
+        (None, _) => true,
+        (Some(expected), Some(actual)) => {
+            expected.starts_with(actual.as_str()) || actual.starts_with(expected.as_str())
+        }
+        (Some(_), None) => false,
+    };
+    if !same(&selection.old_blob, &current.old_blob)
+        || !same(&selection.new_blob, &current.new_blob)
+    {
+        return Err(format!(
+            "Conflict: {} changed since the diff was shown",
+            selection.file_path
+        ));
+    }
+    Ok(())
+}
+
+// =============================================================================
+// Discarding changes
+// =============================================================================
+
+/// Private ref holding a backup of each discard; its reflog is the stack
+/// "undo last discard" pops, the way `refs/stash` works.
+const DISCARD_REF: &str = "refs/air/discarded";
+
+/// A backup taken before changes were thrown away.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiscardBackup {
+    pub ref_name: String,
+    pub commit: String,     // worktree after the discard; its parent is `snapshot`
+    pub snapshot: String,   // worktree before, laid out like a stash entry
+    pub paths: Vec<String>, // files the discard touched
+    pub timestamp: i64,
+}
+
+/// Throw away the selected unstaged changes, reverting them in the worktree.
+/// Selected lines of an untracked file are removed from it, and selecting
+/// all of them deletes the file.
+pub fn discard_selection(
+    dir: &Path,
+    selections: &[PatchSelection],
+) -> Result<DiscardBackup, String> {
+    let patch = selection_patch(dir, selections, false, true)?;
+    let paths: Vec<String> = selections.iter().map(|s| s.file_path.clone()).collect();
+    with_discard_backup(dir, &paths, || {
+        apply_patch(dir, patch, &["--reverse"], "the worktree")
+    })
+}
+
+/// Throw away all unstaged changes to whole files: tracked files go back to
+/// their staged (or committed) content and untracked files are deleted.
+pub fn discard_files(dir: &Path, paths: &[String]) -> Result<DiscardBackup, String> {
+    let untracked = get_untracked_files(dir)?;
+    let (untracked, tracked): (Vec<&String>, Vec<&String>) =
+        paths.iter().partition(|path| untracked.contains(path));
+    // A snapshot only records a nested repository's HEAD, so it couldn't be
+    // brought back.
+    if let Some(nested) = untracked
+        .iter()
+        .find(|path| is_nested_repo(&dir.join(path)))
+    {
+        return Err(format!(
+            "{} is a nested repository; not discarding it",
+            nested
+        ));
+    }
+
+    with_discard_backup(dir, paths, || {
+        if !tracked.is_empty() {
+            let output = Command::new("git")
+                .args(["checkout-index", "--force", "--"])
+                .args(&tracked)
+                .current_dir(dir)
+                .output()
+                .map_err(|e| format!("Failed to execute git checkout-index: {}", e))?;
+            if !output.status.success() {
+                return Err(String::from_utf8_lossy(&output.stderr).to_string());
+            }
+        }
+        for path in &untracked {
+            fs::remove_file(dir.join(path))
+                .map_err(|e| format!("Failed to delete {}: {}", path, e))?;
+        }
+        Ok(())
+    })
+}
+
+/// Snapshot the worktree and record it in the backup ref before running
+/// `discard`, so nothing it throws away is ever unreferenced, then move the
+/// backup on to what the worktree became. That happens even when `discard`
+/// fails partway, so undoing puts back whatever it did get to.
+fn with_discard_backup(
+    dir: &Path,
+    paths: &[String],
+    discard: impl FnOnce() -> Result<(), String>,
+) -> Result<DiscardBackup, String> {
+    // A backup keeps everything, however large.
+    let everything = DiffOptions {
+        untracked_file_limit: None,
+        untracked_total_limit: None,
+        ..Default::default()
+    };
+    let mut message = String::from("air: discard\n\n");
+    for path in paths {
+        message.push_str(&quote_path(path));
+        message.push('\n');
+    }
+
+    let before = snapshot_worktree(dir, "air: before discard", &everything)?;
+    let pending = discard_commit(dir, &before, &before, &message)?;
+    update_discard_ref(dir, &["--create-reflog", DISCARD_REF, &pending])?;
+
+    let discarded = discard();
+
+    let after = snapshot_worktree(dir, "air: after discard", &everything)?;
+    let commit = discard_commit(dir, &after, &before, &message)?;
+    update_discard_ref(dir, &[DISCARD_REF, &commit, &pending])?;
+    // The pending entry was only ever a stand-in for this one.
+    let output = Command::new("git")
+        .args(["reflog", "delete", "--rewrite"])
+        .arg(format!("{}@{{1}}", DISCARD_REF))
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to execute git reflog: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+
+    discarded.map_err(|e| format!("{} (the discarded content is kept in {})", e, DISCARD_REF))?;
+    get_last_discard(dir)?.ok_or_else(|| format!("Failed to read back {}", DISCARD_REF))
+}
+
+/// A backup commit: the worktree as of `snapshot`'s tree, on top of the
+/// `before` snapshot.
+fn discard_commit(
+    dir: &Path,
+    snapshot: &str,
+    before: &str,
+    message: &str,
+) -> Result<String, String> {
+    let tree = format!("{}^{{tree}}", snapshot);
+    let output = Command::new("git")
+        .args(["commit-tree", &tree, "-p", before, "-m", message])
+        .current_dir(dir)
+        .envs(SNAPSHOT_IDENTITY)
+        .output()
+        .map_err(|e| format!("Failed to execute git commit-tree: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn update_discard_ref(dir: &Path, args: &[&str]) -> Result<(), String> {
+    let output = Command::new("git")
+        .args(["update-ref", "-m", "air: discard"])
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to execute git update-ref: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    Ok(())
+}
+
+/// The most recent discard that hasn't been undone, if any.
+pub fn get_last_discard(dir: &Path) -> Result<Option<DiscardBackup>, String> {
+    if !default_backend().ref_exists(dir, DISCARD_REF) {
+        return Ok(None);
+    }
+
+    let output = Command::new("git")
+        .args(["log", "-1", "--format=%H%x1f%P%x1f%ct%x1f%b", DISCARD_REF])
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to read {}: {}", DISCARD_REF, e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = stdout.splitn(4, '\x1f').collect();
+    if fields.len() < 4 {
+        return Err(format!("Unexpected backup format for {}", DISCARD_REF));
+    }
+    Ok(Some(DiscardBackup {
+        ref_name: DISCARD_REF.to_string(),
+        commit: fields[0].to_string(),
+        snapshot: fields[1].to_string(),
+        timestamp: fields[2].parse().unwrap_or(0),
+        paths: fields[3]
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(crate::diff::unquote_path)
+            .collect(),
+    }))
+}
+
+/// Put back what the last discard threw away and drop its backup. Only the
+/// discarded changes are reapplied, so later edits to the same files
+/// survive; if they overlap, nothing is changed and the backup is kept.
+/// Returns the restored backup, or `None` when there was nothing to undo.
+pub fn undo_last_discard(dir: &Path) -> Result<Option<DiscardBackup>, String> {
+    let Some(backup) = get_last_discard(dir)? else {
+        return Ok(None);
+    };
+
+    let output = Command::new("git")
+        .args([
+            "--literal-pathspecs",
+            "diff",
+            "--binary",
+            "--no-color",
+            "--no-ext-diff",
+            "--no-textconv",
+            "--no-renames",
+            "--src-prefix=a/",
+            "--dst-prefix=b/",
+            &backup.commit,
+            &backup.snapshot,
+            "--",
+        ])
+        .args(&backup.paths)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to execute git diff: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    apply_patch(dir, output.stdout, &[], "the worktree")
+        .map_err(|e| format!("{} (the discarded content is kept in {})", e, DISCARD_REF))?;
+
+    // Pop the backup, deleting the ref along with its last entry.
+    let output = Command::new("git")
+        .args(["reflog", "show", "--format=%H", DISCARD_REF])
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to read the {} reflog: {}", DISCARD_REF, e))?;
+    let entries = String::from_utf8_lossy(&output.stdout).lines().count();
+    let mut pop = Command::new("git");
+    if entries > 1 {
+        pop.args(["reflog", "delete", "--updateref", "--rewrite"])
+            .arg(format!("{}@{{0}}", DISCARD_REF));
+    } else {
+        pop.args(["update-ref", "-d", DISCARD_REF]);
+    }
+    let output = pop
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Failed to drop {}: {}", DISCARD_REF, e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+
+    Ok(Some(backup))
+}
+
+// =============================================================================
+// Committing
+// =============================================================================
+
+/// What to commit. Deserializes from the sidecar's camelCase params.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CommitOptions {
+    /// May be empty when amending, to keep the commit's message.
+    pub message: String,
+    /// Commit these files as they are in the worktree, untracked ones
+    /// included, instead of what is staged. The rest of the index is left
+    /// alone, as with `git commit -- <files>`.
+    pub files: Vec<String>,
+    pub amend: bool,
+    /// Added as a "Reviewed-by:" trailer, e.g. "Jane Doe <jane@example.com>".
+    pub reviewed_by: Option<String>,
+    /// The review session the commit was approved from. Nothing is
+    /// committed if what would be has changed since it was opened.
+    pub session: Option<String>,
+}
 
-    if !log_output.status.success() {
-        return Err(String::from_utf8_lossy(&log_output.stderr).to_string());
-    }
+/// How a commit attempt ended. A hook turning the commit down is an outcome
+/// the reviewer acts on, not a failure of the app, so it isn't an `Err`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum CommitOutcome {
+    Committed {
+        commit: Box<CommitInfo>,
+    },
+    HookFailed {
+        hook: String, // "pre-commit", "prepare-commit-msg" or "commit-msg"
+        exit_code: Option<i32>,
+        output: String, // what the hook printed, stdout then stderr
+    },
+}
 
-    let log_str = String::from_utf8_lossy(&log_output.stdout);
-    let mut entries = Vec::new();
+/// Commit the staged changes, or just `opts.files`, running the repo's
+/// commit hooks. With `opts.session`, only if they're still as reviewed.
+pub fn create_commit(dir: &Path, opts: &CommitOptions) -> Result<CommitOutcome, String> {
+    let git_dir = default_backend()
+        .git_dir(dir)
+        .ok_or_else(|| format!("Not a git repository: {}", dir.display()))?;
+    let message_file = git_dir.join(format!("air-commit-msg-{}", std::process::id()));
+    let index_file = git_dir.join(format!("air-commit-index-{}", std::process::id()));
+
+    if let Some(session) = &opts.session {
+        check_unchanged_since_session(dir, opts, session)?;
+    }
+    let result = commit_with_hooks(dir, opts, &message_file, &index_file);
+    let _ = fs::remove_file(&message_file);
+    let _ = fs::remove_file(&index_file);
+    result
+}
 
-    // Parse commits
-    let commits_raw = log_str.split("\n\n");
-    for (position, commit_block) in commits_raw.enumerate() {
-        if commit_block.trim().is_empty() {
-            continue;
+/// Run the commit hooks ourselves, so a failure can be pinned on the hook
+/// that failed: pre-commit, prepare-commit-msg and commit-msg
+/// before committing, in git's order, and post-commit (plus post-rewrite
+/// after an amend) once the commit is made. `git commit` itself runs with
+/// hooks turned off so none of them runs twice.
+fn commit_with_hooks(
+    dir: &Path,
+    opts: &CommitOptions,
+    message_file: &Path,
+    index_file: &Path,
+) -> Result<CommitOutcome, String> {
+    let run = |args: &[&str], index: Option<&Path>| -> Result<String, String> {
+        let mut cmd = Command::new("git");
+        cmd.args(args).current_dir(dir);
+        if let Some(index) = index {
+            cmd.env("GIT_INDEX_FILE", index);
         }
-
-        let lines: Vec<&str> = commit_block.lines().collect();
-        if lines.is_empty() {
-            continue;
+        let output = cmd
+            .output()
+            .map_err(|e| format!("Failed to execute git {}: {}", args[0], e))?;
+        if !output.status.success() {
+            // `git commit` says "nothing to commit" on stdout.
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let reason = if stderr.trim().is_empty() {
+                stdout
+            } else {
+                stderr
+            };
+            return Err(reason.trim().to_string());
         }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    };
 
-        let header_parts: Vec<&str> = lines[0].split('|').collect();
-        if header_parts.len() < 3 {
-            continue;
+    let message = if opts.message.trim().is_empty() {
+        if !opts.amend {
+            return Err("Commit message is empty".to_string());
         }
+        run(&["log", "-1", "--format=%B", "HEAD"], None)?
+    } else {
+        opts.message.clone()
+    };
+    // interpret-trailers runs the trailer into a last line with no newline.
+    fs::write(message_file, format!("{}\n", message.trim_end()))
+        .map_err(|e| format!("Failed to write commit message: {}", e))?;
+    let message_path = message_file.to_string_lossy();
+    if let Some(reviewer) = &opts.reviewed_by {
+        let trailer = format!("Reviewed-by: {}", reviewer);
+        run(
+            &[
+                "interpret-trailers",
+                "--in-place",
+                "--if-exists",
+                "addIfDifferent",
+                "--trailer",
+                &trailer,
+                &message_path,
+            ],
+            None,
+        )?;
+    }
 
-        let hash = header_parts[0].to_string();
-        let short_hash = header_parts[1].to_string();
-        let title = header_parts[2].to_string();
-
-        // Extract body (everything after the header line)
-        let body = lines[1..].join("\n");
-        let gg_id = extract_gg_id(&body);
-
-        // Get MR number from config
-        let mr_number = if let Some(ref id) = gg_id {
-            config
-                .get("stacks")
-                .and_then(|s| s.get(stack_name))
-                .and_then(|s| s.get("mrs"))
-                .and_then(|mrs| mrs.get(id))
-                .and_then(|n| n.as_u64())
+    // pre-commit checks the index the commit is made from. For chosen files
+    // that's HEAD plus their worktree content, which is what `git commit
+    // --only` builds too.
+    let mut hook_index = None;
+    let mut new = Vec::new();
+    if !opts.files.is_empty() {
+        let untracked = get_untracked_files(dir)?;
+        new = opts
+            .files
+            .iter()
+            .filter(|file| untracked.contains(file))
+            .map(String::as_str)
+            .collect();
+
+        let base = if default_backend().rev_parse(dir, "HEAD").is_ok() {
+            "HEAD"
         } else {
-            None
+            "--empty"
         };
+        run(&["read-tree", base], Some(index_file))?;
+        let files: Vec<&str> = opts.files.iter().map(String::as_str).collect();
+        run(
+            &[&["add", "-A", "--"], files.as_slice()].concat(),
+            Some(index_file),
+        )?;
+        hook_index = Some(index_file);
+    }
+    if let Some(failure) = run_commit_hook(dir, "pre-commit", &[], hook_index)? {
+        return Ok(failure);
+    }
+    let prepare_args = [message_path.as_ref(), "message"];
+    if let Some(failure) = run_commit_hook(dir, "prepare-commit-msg", &prepare_args, hook_index)? {
+        return Ok(failure);
+    }
+    if let Some(failure) = run_commit_hook(dir, "commit-msg", &[&message_path], None)? {
+        return Ok(failure);
+    }
 
-        entries.push(GgStackEntry {
-            hash,
-            short_hash,
-            title,
-            gg_id,
-            mr_number,
-            position,
-        });
+    // A hooks directory that doesn't exist, so `git commit` runs none.
+    let no_hooks = format!(
+        "core.hooksPath={}",
+        message_file.with_file_name("air-no-hooks").display()
+    );
+    let mut args = vec!["-c", &no_hooks, "commit", "--file", &message_path];
+    if opts.amend {
+        args.push("--amend");
+    }
+    if !opts.files.is_empty() {
+        args.push("--only");
+        args.push("--");
+        args.extend(opts.files.iter().map(String::as_str));
+    }
+    let amended = if opts.amend {
+        Some(default_backend().rev_parse(dir, "HEAD")?)
+    } else {
+        None
+    };
+    // `git commit -- <paths>` only takes paths the index knows about;
+    // intent-to-add records an untracked one without staging content. The
+    // commit stages it for real, and a failed one gets the entry taken out
+    // again, so the index is only ever changed by a commit that happened.
+    if !new.is_empty() {
+        run(&[&["add", "-N", "--"], new.as_slice()].concat(), None)?;
+    }
+    if let Err(e) = run(&args, None) {
+        if !new.is_empty() {
+            let _ = run(
+                &[
+                    &["rm", "--cached", "--quiet", "--force", "--"],
+                    new.as_slice(),
+                ]
+                .concat(),
+                None,
+            );
+        }
+        return Err(e);
     }
 
-    Ok(entries)
+    let head = LogOptions {
+        range: Some("HEAD".to_string()),
+        limit: Some(1),
+        ..Default::default()
+    };
+    let commit = list_commits(dir, &head)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| "Failed to read back the new commit".to_string())?;
+    // As with git, what these say doesn't change the outcome.
+    let _ = run_commit_hook(dir, "post-commit", &[], None);
+    if let Some(old) = amended {
+        let rewritten = format!("{} {}\n", old, commit.hash);
+        let _ = run_hook(dir, "post-rewrite", &["amend"], None, rewritten.as_bytes());
+    }
+    Ok(CommitOutcome::Committed {
+        commit: Box::new(commit),
+    })
 }
 
-/// Get the merge-base between two arbitrary refs
-pub fn get_merge_base_refs(dir: &Path, ref1: &str, ref2: &str) -> Result<String, String> {
-    let output = Command::new("git")
-        .args(["merge-base", ref1, ref2])
-        .current_dir(dir)
-        .output()
-        .map_err(|e| format!("Failed to find merge-base: {}", e))?;
-
+/// Run one commit hook, if the repo has it. `None` means it passed.
+fn run_commit_hook(
+    dir: &Path,
+    hook: &str,
+    args: &[&str],
+    index: Option<&Path>,
+) -> Result<Option<CommitOutcome>, String> {
+    let Some(output) = run_hook(dir, hook, args, index, b"")? else {
+        return Ok(None);
+    };
     if output.status.success() {
-        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
-    } else {
-        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+        return Ok(None);
     }
-}
 
-/// Get the base ref for a git-gud stack
-#[derive(Clone, serde::Serialize)]
-pub struct GgStackBaseInfo {
-    pub base: String,
-    pub branch: String,
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok(Some(CommitOutcome::HookFailed {
+        hook: hook.to_string(),
+        exit_code: output.status.code(),
+        output: text,
+    }))
 }
 
-pub fn get_gg_stack_base(dir: &Path, stack_name: &str) -> Result<GgStackBaseInfo, String> {
-    let config = read_gg_config(dir)?;
-    let default_base = get_default_base(&config);
-
-    let base = config
-        .get("stacks")
-        .and_then(|s| s.get(stack_name))
-        .and_then(|s| s.get("base"))
-        .and_then(|b| b.as_str())
-        .map(|s| s.to_string())
-        .unwrap_or(default_base);
-
-    // Find the stack branch name
-    let branches_output = Command::new("git")
-        .arg("for-each-ref")
-        .arg("--format=%(refname:short)")
-        .arg("refs/heads/")
+/// Run a hook the way git does, from the top of the worktree with `input`
+/// on its stdin, and return what it did; `None` when the repo doesn't have
+/// it. Hooks are found and run here rather than through `git hook run`,
+/// which needs git 2.36 and can't feed a hook its stdin.
+fn run_hook(
+    dir: &Path,
+    hook: &str,
+    args: &[&str],
+    index: Option<&Path>,
+    input: &[u8],
+) -> Result<Option<Output>, String> {
+    let Some(path) = find_hook(dir, hook) else {
+        return Ok(None);
+    };
+    let mut cmd = Command::new(&path);
+    cmd.args(args)
         .current_dir(dir)
-        .output()
-        .map_err(|e| format!("Failed to list branches: {}", e))?;
-
-    let branches_str = String::from_utf8_lossy(&branches_output.stdout);
-    let branch = branches_str
-        .lines()
-        .find(|b| is_stack_branch(b) && extract_stack_name(b).as_deref() == Some(stack_name))
-        .unwrap_or(stack_name)
-        .to_string();
-
-    Ok(GgStackBaseInfo { base, branch })
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(index) = index {
+        cmd.env("GIT_INDEX_FILE", index);
+    }
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| format!("Failed to run the {} hook: {}", hook, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A hook that doesn't read its stdin closes it early; that's fine.
+        let _ = stdin.write_all(input);
+    }
+    child
+        .wait_with_output()
+        .map(Some)
+        .map_err(|e| format!("Failed to run the {} hook: {}", hook, e))
 }
 
-/// Get diff for entire git-gud stack (base..stack-head)
-pub fn get_gg_stack_diff(dir: &Path, stack_name: &str) -> Result<String, String> {
-    let config = read_gg_config(dir)?;
-    let default_base = get_default_base(&config);
-
-    // Get base for this stack
-    let base = config
-        .get("stacks")
-        .and_then(|s| s.get(stack_name))
-        .and_then(|s| s.get("base"))
-        .and_then(|b| b.as_str())
-        .map(|s| s.to_string())
-        .unwrap_or(default_base);
-
-    // Find the stack branch
-    let branches_output = Command::new("git")
-        .arg("for-each-ref")
-        .arg("--format=%(refname:short)")
-        .arg("refs/heads/")
-        .current_dir(dir)
-        .output()
-        .map_err(|e| format!("Failed to list branches: {}", e))?;
-
-    let branches_str = String::from_utf8_lossy(&branches_output.stdout);
-    let stack_branch = branches_str
-        .lines()
-        .find(|b| is_stack_branch(b) && extract_stack_name(b).as_deref() == Some(stack_name))
-        .ok_or_else(|| format!("Stack branch not found for: {}", stack_name))?;
-
-    // Get diff
-    let diff_output = Command::new("git")
-        .arg("diff")
-        .arg("--no-color")
-        .arg(format!("{}..{}", base, stack_branch))
+/// Where git would look for `hook`, `core.hooksPath` included, if there's
+/// something there it would run.
+fn find_hook(dir: &Path, hook: &str) -> Option<PathBuf> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--git-path", &format!("hooks/{}", hook)])
         .current_dir(dir)
         .output()
-        .map_err(|e| format!("Failed to get stack diff: {}", e))?;
-
-    if !diff_output.status.success() {
-        return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
+        .ok()?;
+    if !output.status.success() {
+        return None;
     }
-
-    Ok(String::from_utf8_lossy(&diff_output.stdout).to_string())
+    let path = dir.join(String::from_utf8_lossy(&output.stdout).trim());
+    let meta = fs::metadata(&path).ok()?;
+    // Git skips a hook that isn't executable.
+    (meta.is_file() && (cfg!(not(unix)) || is_executable(&meta))).then_some(path)
 }
 
-/// Get diff for a single commit in a git-gud stack
-pub fn get_gg_entry_diff(dir: &Path, _stack_name: &str, hash: &str) -> Result<String, String> {
-    // Use git show to get the diff for a single commit
-    let diff_output = Command::new("git")
-        .arg("show")
-        .arg(hash)
-        .arg("--format=")
-        .arg("--no-color")
+/// Refuse to commit anything the reviewer wasn't shown: what the commit
+/// would take, the index or the chosen files in the worktree, must still be
+/// as the review session froze it.
+fn check_unchanged_since_session(
+    dir: &Path,
+    opts: &CommitOptions,
+    session: &str,
+) -> Result<(), String> {
+    let session = get_review_session(dir, session)?;
+    let mut cmd = Command::new("git");
+    cmd.args(["--literal-pathspecs", "diff", "--name-only", "--no-renames"]);
+    if opts.files.is_empty() {
+        cmd.args(["--cached", &session.index]);
+    } else {
+        // Untracked files count too, so compare against a fresh snapshot.
+        let everything = DiffOptions {
+            untracked_file_limit: None,
+            untracked_total_limit: None,
+            ..Default::default()
+        };
+        let current = snapshot_worktree(dir, "air: commit check", &everything)?;
+        cmd.args([&session.id, &current, "--"]).args(&opts.files);
+    }
+    let output = cmd
         .current_dir(dir)
         .output()
-        .map_err(|e| format!("Failed to get entry diff: {}", e))?;
-
-    if !diff_output.status.success() {
-        return Err(String::from_utf8_lossy(&diff_output.stderr).to_string());
+        .map_err(|e| format!("Failed to execute git diff: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
     }
 
-    Ok(String::from_utf8_lossy(&diff_output.stdout).to_string())
+    let changed: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect();
+    if !changed.is_empty() {
+        return Err(format!(
+            "Changed since the review was opened, so not committed: {}",
+            changed.join(", ")
+        ));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::TestRepo;
+
+    /// Re-encode v1-style "XY path" (or "XY old -> new" for renames and
+    /// copies) lines as `git status --porcelain=v2 -z` records.
+    fn status_v2(v1: &str) -> String {
+        let hash = "0".repeat(40);
+        v1.lines()
+            .map(|line| {
+                let (xy, path) = (&line[..2], &line[3..]);
+                let v2_xy = xy.replace(' ', ".");
+                match xy {
+                    "??" => format!("? {}\0", path),
+                    _ if xy.starts_with(['R', 'C']) => {
+                        let (old, new) = path.split_once(" -> ").unwrap();
+                        format!(
+                            "2 {} N... 100644 100644 100644 {h} {h} {}100 {}\0{}\0",
+                            v2_xy,
+                            &xy[..1],
+                            new,
+                            old,
+                            h = hash
+                        )
+                    }
+                    _ => format!(
+                        "1 {} N... 100644 100644 100644 {h} {h} {}\0",
+                        v2_xy,
+                        path,
+                        h = hash
+                    ),
+                }
+            })
+            .collect()
+    }
 
     #[test]
     fn test_parse_porcelain_status_modified() {
         let output = " M src/main.rs\n";
-        let files = parse_porcelain_status(output, false);
+        let files = parse_porcelain_status(&status_v2(output), false);
 
         assert_eq!(files.len(), 1);
         assert_eq!(files[0].path, "src/main.rs");
@@ -1348,7 +4340,7 @@ mod tests {
     #[test]
     fn test_parse_porcelain_status_added() {
         let output = "A  src/new.rs\n";
-        let files = parse_porcelain_status(output, false);
+        let files = parse_porcelain_status(&status_v2(output), false);
 
         assert_eq!(files.len(), 1);
         assert_eq!(files[0].path, "src/new.rs");
@@ -1358,7 +4350,7 @@ mod tests {
     #[test]
     fn test_parse_porcelain_status_deleted() {
         let output = " D src/old.rs\n";
-        let files = parse_porcelain_status(output, false);
+        let files = parse_porcelain_status(&status_v2(output), false);
 
         assert_eq!(files.len(), 1);
         assert_eq!(files[0].path, "src/old.rs");
@@ -1368,7 +4360,7 @@ mod tests {
     #[test]
     fn test_parse_porcelain_status_multiple() {
         let output = " M src/main.rs\nA  src/new.rs\n D src/old.rs\n";
-        let files = parse_porcelain_status(output, false);
+        let files = parse_porcelain_status(&status_v2(output), false);
 
         assert_eq!(files.len(), 3);
         assert_eq!(files[0].status, "modified");
@@ -1379,7 +4371,7 @@ mod tests {
     #[test]
     fn test_parse_porcelain_status_untracked_as_added() {
         let output = "?? src/untracked.rs\n";
-        let files = parse_porcelain_status(output, false);
+        let files = parse_porcelain_status(&status_v2(output), false);
 
         assert_eq!(files.len(), 1);
         assert_eq!(files[0].path, "src/untracked.rs");
@@ -1389,7 +4381,7 @@ mod tests {
     #[test]
     fn test_parse_porcelain_status_empty() {
         let output = "";
-        let files = parse_porcelain_status(output, false);
+        let files = parse_porcelain_status(&status_v2(output), false);
 
         assert_eq!(files.len(), 0);
     }
@@ -1397,7 +4389,7 @@ mod tests {
     #[test]
     fn test_parse_porcelain_status_modified_both() {
         let output = "MM src/file.rs\n";
-        let files = parse_porcelain_status(output, false);
+        let files = parse_porcelain_status(&status_v2(output), false);
 
         assert_eq!(files.len(), 1);
         assert_eq!(files[0].status, "modified");
@@ -1406,7 +4398,7 @@ mod tests {
     #[test]
     fn test_parse_porcelain_status_staged_only() {
         let output = "M  src/staged.rs\n M src/unstaged.rs\n";
-        let files = parse_porcelain_status(output, true);
+        let files = parse_porcelain_status(&status_v2(output), true);
 
         // Only the staged file (M with space after) should be returned
         assert_eq!(files.len(), 1);
@@ -1417,7 +4409,7 @@ mod tests {
     #[test]
     fn test_parse_porcelain_status_unstaged_all() {
         let output = "M  src/staged.rs\n M src/unstaged.rs\n";
-        let files = parse_porcelain_status(output, false);
+        let files = parse_porcelain_status(&status_v2(output), false);
 
         // Both files should be returned for unstaged
         assert_eq!(files.len(), 2);
@@ -1426,47 +4418,70 @@ mod tests {
     #[test]
     fn test_parse_porcelain_status_renamed_uses_new_path() {
         let output = "R  src/old.rs -> src/new.rs\n";
-        let files = parse_porcelain_status(output, false);
+        let files = parse_porcelain_status(&status_v2(output), false);
 
         assert_eq!(files.len(), 1);
         assert_eq!(files[0].path, "src/new.rs");
         assert_eq!(files[0].status, "renamed");
+        assert_eq!(files[0].old_path.as_deref(), Some("src/old.rs"));
+    }
+
+    #[test]
+    fn test_parse_porcelain_status_copied() {
+        let output = "C  src/a.rs -> src/b.rs\n";
+        let files = parse_porcelain_status(&status_v2(output), true);
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "src/b.rs");
+        assert_eq!(files[0].status, "copied");
+        assert_eq!(files[0].old_path.as_deref(), Some("src/a.rs"));
     }
 
     #[test]
     fn test_parse_porcelain_status_arrow_in_filename_not_treated_as_rename() {
         // A modified file whose name literally contains " -> " should not be split
         let output = "M  a -> b.txt\n";
-        let files = parse_porcelain_status(output, false);
+        let files = parse_porcelain_status(&status_v2(output), false);
 
         assert_eq!(files.len(), 1);
         assert_eq!(files[0].path, "a -> b.txt");
         assert_eq!(files[0].status, "modified");
+        assert_eq!(files[0].old_path, None);
     }
 
     #[test]
     fn test_build_new_file_diff() {
-        let diff = build_new_file_diff("src/new.rs", "line 1\nline 2\n");
+        let diff = build_new_file_diff("src/new.rs", "100644", "line 1\nline 2\n");
 
         assert!(diff.contains("diff --git a/src/new.rs b/src/new.rs"));
         assert!(diff.contains("new file mode 100644"));
         assert!(diff.contains("--- /dev/null"));
         assert!(diff.contains("+++ b/src/new.rs"));
-        assert!(diff.contains("@@ -0,0 +1,3 @@"));
-        assert!(diff.contains("+line 1\n+line 2\n+\n"));
+        assert!(diff.contains("@@ -0,0 +1,2 @@"));
+        assert!(diff.ends_with("+line 1\n+line 2\n"));
     }
 
     #[test]
     fn test_build_new_file_diff_without_trailing_newline() {
-        let diff = build_new_file_diff("README.md", "hello");
+        let diff = build_new_file_diff("README.md", "100644", "hello");
 
         assert!(diff.contains("@@ -0,0 +1,1 @@"));
-        assert!(diff.contains("+hello\n"));
+        assert!(diff.ends_with("+hello\n\\ No newline at end of file\n"));
+    }
+
+    #[test]
+    fn test_build_new_file_diff_for_empty_file() {
+        let diff = build_new_file_diff("empty.txt", "100644", "");
+
+        assert_eq!(
+            diff,
+            "diff --git a/empty.txt b/empty.txt\nnew file mode 100644\n"
+        );
     }
 
     #[test]
     fn test_build_binary_file_diff() {
-        let diff = build_binary_file_diff("assets/logo.png");
+        let diff = build_binary_file_diff("assets/logo.png", "100644");
 
         assert!(diff.contains("diff --git a/assets/logo.png b/assets/logo.png"));
         assert!(diff.contains("new file mode 100644"));
@@ -1475,67 +4490,718 @@ mod tests {
     }
 
     #[test]
-    fn test_parse_file_status_modified() {
-        let output = "M\tsrc/main.rs\n";
-        let files = parse_file_status(output);
+    fn test_parse_file_status_modified() {
+        let output = "M\0src/main.rs\0";
+        let files = parse_file_status(output);
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "src/main.rs");
+        assert_eq!(files[0].status, "modified");
+    }
+
+    #[test]
+    fn test_parse_file_status_added() {
+        let output = "A\0src/new.rs\0";
+        let files = parse_file_status(output);
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "src/new.rs");
+        assert_eq!(files[0].status, "added");
+    }
+
+    #[test]
+    fn test_parse_file_status_deleted() {
+        let output = "D\0src/old.rs\0";
+        let files = parse_file_status(output);
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "src/old.rs");
+        assert_eq!(files[0].status, "deleted");
+    }
+
+    #[test]
+    fn test_parse_file_status_multiple() {
+        let output = "M\0src/main.rs\0A\0src/new.rs\0D\0src/old.rs\0";
+        let files = parse_file_status(output);
+
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].status, "modified");
+        assert_eq!(files[1].status, "added");
+        assert_eq!(files[2].status, "deleted");
+    }
+
+    #[test]
+    fn test_parse_file_status_empty() {
+        let output = "";
+        let files = parse_file_status(output);
+
+        assert_eq!(files.len(), 0);
+    }
+
+    #[test]
+    fn test_parse_file_status_renamed() {
+        let output = "R100\0src/old.rs\0src/new.rs\0";
+        let files = parse_file_status(output);
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "src/new.rs");
+        assert_eq!(files[0].status, "renamed");
+        assert_eq!(files[0].old_path.as_deref(), Some("src/old.rs"));
+        assert_eq!(files[0].similarity, Some(100));
+    }
+
+    #[test]
+    fn test_parse_file_status_copied() {
+        let output = "C075\0src/a.rs\0src/b.rs\0";
+        let files = parse_file_status(output);
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "src/b.rs");
+        assert_eq!(files[0].status, "copied");
+        assert_eq!(files[0].old_path.as_deref(), Some("src/a.rs"));
+        assert_eq!(files[0].similarity, Some(75));
+    }
+
+    #[test]
+    fn test_parse_file_status_path_with_spaces() {
+        let output = "M\0docs/read me.md\0R090\0old name.rs\0new name.rs\0";
+        let files = parse_file_status(output);
+
+        assert_eq!(files[0].path, "docs/read me.md");
+        assert_eq!(files[1].path, "new name.rs");
+        assert_eq!(files[1].old_path.as_deref(), Some("old name.rs"));
+    }
+
+    #[test]
+    fn test_diff_options_rename_args() {
+        assert_eq!(
+            DiffOptions::default().to_args(),
+            vec!["--find-renames=50%", "--submodule=short"]
+        );
+
+        let opts = DiffOptions {
+            rename_threshold: Some(70),
+            find_copies: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            opts.to_args(),
+            vec![
+                "--find-renames=70%",
+                "--find-copies=70%",
+                "--submodule=short"
+            ]
+        );
+
+        let opts = DiffOptions {
+            rename_threshold: None,
+            ..Default::default()
+        };
+        assert_eq!(opts.to_args(), vec!["--no-renames", "--submodule=short"]);
+        assert_eq!(opts.status_args(), vec!["--no-renames"]);
+    }
+
+    #[test]
+    fn test_diff_options_formatting_args() {
+        let opts = DiffOptions {
+            rename_threshold: None,
+            ignore_whitespace: WhitespaceMode::All,
+            ignore_blank_lines: true,
+            context_lines: Some(10),
+            algorithm: DiffAlgorithm::Histogram,
+            function_context: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            opts.to_args(),
+            vec![
+                "--ignore-all-space",
+                "--ignore-blank-lines",
+                "--unified=10",
+                "--diff-algorithm=histogram",
+                "--function-context",
+                "--no-renames",
+                "--submodule=short",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_options_deserialize_from_camel_case_params() {
+        let params = serde_json::json!({
+            "path": "/repo",
+            "ignoreWhitespace": "at-eol",
+            "contextLines": 0,
+            "algorithm": "patience",
+        });
+        let opts: DiffOptions = serde_json::from_value(params).unwrap();
+
+        assert_eq!(opts.ignore_whitespace, WhitespaceMode::AtEol);
+        assert_eq!(opts.context_lines, Some(0));
+        assert_eq!(opts.algorithm, DiffAlgorithm::Patience);
+        assert_eq!(opts.rename_threshold, Some(50));
+        assert!(!opts.function_context);
+    }
+
+    #[test]
+    fn test_unstaged_diff_ignores_reindent() {
+        let repo = TestRepo::new("whitespace");
+        repo.write("a.py", "if x:\n  call()\n");
+        repo.commit_all("initial");
+        repo.write("a.py", "if x:\n    call()\n");
+
+        let result = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        assert_eq!(result.file_diffs[0].hunks.len(), 1);
+
+        let opts = DiffOptions {
+            ignore_whitespace: WhitespaceMode::All,
+            ..Default::default()
+        };
+        let result = get_unstaged_diff(repo.dir(), &opts).unwrap();
+        assert!(result.file_diffs.iter().all(|f| f.hunks.is_empty()));
+    }
+
+    #[test]
+    fn test_unstaged_diff_reports_line_stats() {
+        let repo = TestRepo::new("numstat");
+        repo.write("a.txt", "one\ntwo\nthree\n");
+        repo.write("gone.txt", "bye\nbye\n");
+        repo.commit_all("initial");
+        repo.write("a.txt", "one\n2\nthree\nfour\n");
+        fs::remove_file(repo.dir().join("gone.txt")).unwrap();
+        repo.write("new.txt", "fresh\n");
+        repo.write_bytes("blob.bin", &[0xff, 0xfe, 0x00, 0x01]);
+
+        let result = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        let stats = |path: &str| {
+            let file = result.files.iter().find(|f| f.path == path).unwrap();
+            (file.additions, file.deletions, file.is_binary)
+        };
+
+        assert_eq!(stats("a.txt"), (2, 1, false));
+        assert_eq!(stats("gone.txt"), (0, 2, false));
+        assert_eq!(stats("new.txt"), (1, 0, false));
+        assert_eq!(stats("blob.bin"), (0, 0, true));
+    }
+
+    #[test]
+    fn test_commit_diff_reports_rename_source() {
+        let repo = TestRepo::new("rename");
+        repo.write("old.txt", "one\ntwo\nthree\nfour\n");
+        repo.commit_all("initial");
+        repo.git(&["mv", "old.txt", "new.txt"]);
+        repo.write("new.txt", "one\ntwo\nthree\nfour!\n");
+        let hash = repo.commit_all("rename");
+
+        let result = get_commit_diff(repo.dir(), &hash, &DiffOptions::default()).unwrap();
+        assert_eq!(result.files.len(), 1);
+        assert_eq!(result.files[0].status, "renamed");
+        assert_eq!(result.files[0].path, "new.txt");
+        assert_eq!(result.files[0].old_path.as_deref(), Some("old.txt"));
+        assert!(result.files[0].similarity.unwrap() >= 50);
+        assert_eq!(result.file_diffs[0].old_path.as_deref(), Some("old.txt"));
+
+        let strict = DiffOptions {
+            rename_threshold: Some(100),
+            ..Default::default()
+        };
+        let result = get_commit_diff(repo.dir(), &hash, &strict).unwrap();
+        assert_eq!(result.files.len(), 2);
+        assert!(result.files.iter().all(|f| f.old_path.is_none()));
+    }
+
+    /// main and feature both edit line 2 of conflict.txt; feature also adds
+    /// feature.txt. The merge resolves the conflict by hand.
+    fn conflicted_merge() -> (TestRepo, String) {
+        let repo = TestRepo::new("merge");
+        repo.write("conflict.txt", "a\nb\nc\n");
+        repo.commit_all("initial");
+        repo.git(&["checkout", "-q", "-b", "feature"]);
+        repo.write("conflict.txt", "a\nB\nc\n");
+        repo.write("feature.txt", "feature\n");
+        repo.commit_all("feature");
+        repo.git(&["checkout", "-q", "main"]);
+        repo.write("conflict.txt", "a\nbb\nc\n");
+        repo.commit_all("main");
+        let _ = Command::new("git")
+            .args(["merge", "-q", "feature"])
+            .current_dir(repo.dir())
+            .output();
+        repo.write("conflict.txt", "a\nresolved\nc\n");
+        let hash = repo.commit_all("merge feature");
+        (repo, hash)
+    }
+
+    fn merge_opts(merge_diff: MergeDiff) -> DiffOptions {
+        DiffOptions {
+            merge_diff,
+            ..Default::default()
+        }
+    }
+
+    fn file_paths(result: &GitDiffResult) -> Vec<&str> {
+        result.files.iter().map(|f| f.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_commit_parents() {
+        let (repo, hash) = conflicted_merge();
+        let parents = get_commit_parents(repo.dir(), &hash).unwrap();
+        assert_eq!(parents.len(), 2);
+        assert_eq!(parents[1], repo.git(&["rev-parse", "feature"]));
+        assert_eq!(
+            get_commit_parents(repo.dir(), &parents[1]).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_merge_diff_against_parents() {
+        let (repo, hash) = conflicted_merge();
+
+        let first = get_commit_diff(repo.dir(), &hash, &DiffOptions::default()).unwrap();
+        assert_eq!(file_paths(&first), vec!["conflict.txt", "feature.txt"]);
+        assert!(first.diff.contains("-bb\n+resolved"));
+
+        let second = get_commit_diff(
+            repo.dir(),
+            &hash,
+            &merge_opts(MergeDiff::Parent { parent: 2 }),
+        )
+        .unwrap();
+        assert_eq!(file_paths(&second), vec!["conflict.txt"]);
+        assert!(second.diff.contains("-B\n+resolved"));
+
+        let missing = get_commit_diff(
+            repo.dir(),
+            &hash,
+            &merge_opts(MergeDiff::Parent { parent: 3 }),
+        );
+        assert!(missing.unwrap_err().contains("no parent 3"));
+    }
+
+    #[test]
+    fn test_merge_combined_and_remerge_diffs() {
+        let (repo, hash) = conflicted_merge();
+
+        let combined =
+            get_commit_diff(repo.dir(), &hash, &merge_opts(MergeDiff::Combined)).unwrap();
+        assert_eq!(file_paths(&combined), vec!["conflict.txt"]);
+        assert_eq!(combined.files[0].status, "modified");
+        assert_eq!(combined.file_diffs[0].path(), "conflict.txt");
+        assert_eq!(combined.files[0].additions, 1);
+        assert_eq!(combined.files[0].deletions, 2);
+
+        let remerge = get_commit_diff(repo.dir(), &hash, &merge_opts(MergeDiff::Remerge)).unwrap();
+        assert_eq!(file_paths(&remerge), vec!["conflict.txt"]);
+        assert!(remerge.diff.contains("+resolved"));
+        assert!(remerge.diff.contains("-<<<<<<<"));
+    }
+
+    #[test]
+    fn test_merge_diff_deserializes() {
+        let opts: DiffOptions = serde_json::from_value(
+            serde_json::json!({ "mergeDiff": { "mode": "parent", "parent": 2 } }),
+        )
+        .unwrap();
+        assert_eq!(opts.merge_diff, MergeDiff::Parent { parent: 2 });
+        let opts: DiffOptions =
+            serde_json::from_value(serde_json::json!({ "mergeDiff": { "mode": "remerge" } }))
+                .unwrap();
+        assert_eq!(opts.merge_diff, MergeDiff::Remerge);
+        assert_eq!(DiffOptions::default().merge_diff, MergeDiff::FirstParent);
+    }
 
-        assert_eq!(files.len(), 1);
-        assert_eq!(files[0].path, "src/main.rs");
-        assert_eq!(files[0].status, "modified");
+    #[test]
+    fn test_blame_range_at_ref_and_worktree() {
+        let repo = TestRepo::new("blame");
+        repo.write("f.txt", "one\ntwo\nthree\n");
+        let first = repo.commit_all("add f");
+        repo.write("f.txt", "one\nTWO\nthree\n");
+        let second = repo.commit_all("shout two");
+        repo.write("f.txt", "one\nTWO\nthree!\n");
+
+        let blame = blame_range(repo.dir(), Some("HEAD"), "f.txt", 2, 3).unwrap();
+        assert_eq!(blame.len(), 2);
+        assert_eq!(blame[0].line, 2);
+        assert_eq!(blame[0].hash, second);
+        assert_eq!(blame[0].summary, "shout two");
+        assert_eq!(blame[0].content, "TWO");
+        assert_eq!(blame[0].author, "Test User");
+        assert_eq!(blame[0].author_email, "test@example.com");
+        assert_eq!(blame[0].timestamp, 1704067200);
+        assert_eq!(blame[1].hash, first);
+
+        let at_first = blame_range(repo.dir(), Some(&first), "f.txt", 2, 2).unwrap();
+        assert_eq!(at_first[0].content, "two");
+
+        let worktree = blame_range(repo.dir(), None, "f.txt", 1, 3).unwrap();
+        assert_eq!(worktree[0].hash, first);
+        assert!(!worktree[1].uncommitted);
+        assert!(worktree[2].uncommitted);
+        assert_eq!(worktree[2].content, "three!");
+
+        assert!(blame_range(repo.dir(), None, "f.txt", 3, 2).is_err());
+        assert_eq!(
+            blame_range(repo.dir(), None, "f.txt", 2, 9).unwrap().len(),
+            2
+        );
     }
 
     #[test]
-    fn test_parse_file_status_added() {
-        let output = "A\tsrc/new.rs\n";
-        let files = parse_file_status(output);
+    fn test_file_history_follows_renames() {
+        let repo = TestRepo::new("history");
+        repo.write("old.txt", "one\ntwo\nthree\nfour\n");
+        repo.write("other.txt", "other\n");
+        repo.commit_all("add old");
+        repo.write("old.txt", "one\ntwo\nthree\nfour\nfive\n");
+        repo.commit_all("edit | old");
+        repo.write("other.txt", "other!\n");
+        repo.commit_all("unrelated");
+        repo.git(&["mv", "old.txt", "new.txt"]);
+        let renamed = repo.commit_all("rename");
+
+        let history = get_file_history(repo.dir(), "new.txt", 0, 10).unwrap();
+        let paths: Vec<_> = history.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["new.txt", "old.txt", "old.txt"]);
+        assert_eq!(history[0].commit.hash, renamed);
+        assert_eq!(history[0].status, "renamed");
+        assert_eq!(history[0].old_path.as_deref(), Some("old.txt"));
+        assert_eq!(history[1].commit.message, "edit | old");
+        assert_eq!(history[2].status, "added");
+
+        let page = get_file_history(repo.dir(), "new.txt", 1, 1).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].commit.message, "edit | old");
+        assert!(get_file_history(repo.dir(), "new.txt", 3, 10)
+            .unwrap()
+            .is_empty());
+    }
 
-        assert_eq!(files.len(), 1);
-        assert_eq!(files[0].path, "src/new.rs");
-        assert_eq!(files[0].status, "added");
+    #[test]
+    fn test_interdiff_leaves_out_upstream_changes_after_rebase() {
+        let repo = TestRepo::new("interdiff-rebase");
+        repo.write("base.txt", "base\n");
+        repo.commit_all("base");
+        repo.git(&["checkout", "-q", "-b", "stack"]);
+        repo.write("one.txt", "1\n");
+        repo.commit_all("one");
+        let numbers: String = (1..=20).map(|n| format!("{}\n", n)).collect();
+        repo.write("two.txt", &numbers);
+        let old_tip = repo.commit_all("two");
+
+        repo.git(&["checkout", "-q", "main"]);
+        repo.write("upstream.txt", "upstream\n");
+        repo.commit_all("upstream");
+        repo.git(&["checkout", "-q", "stack"]);
+        repo.git(&["rebase", "-q", "main"]);
+        repo.write("two.txt", &format!("{}extra\n", numbers));
+        repo.git(&["commit", "-q", "-a", "--amend", "--no-edit"]);
+
+        let result = get_interdiff(repo.dir(), &old_tip, "HEAD", &DiffOptions::default()).unwrap();
+        let statuses: Vec<_> = result.pairs.iter().map(|p| p.status.as_str()).collect();
+        assert_eq!(statuses, vec!["unchanged", "modified"]);
+
+        let paths: Vec<_> = result
+            .interdiff
+            .files
+            .iter()
+            .map(|f| f.path.as_str())
+            .collect();
+        assert_eq!(paths, vec!["two.txt"]);
+        assert_eq!(result.interdiff.files[0].additions, 1);
+        assert_eq!(
+            get_file_at_ref(repo.dir(), &result.old_side, "upstream.txt").unwrap(),
+            "upstream\n"
+        );
     }
 
     #[test]
-    fn test_parse_file_status_deleted() {
-        let output = "D\tsrc/old.rs\n";
-        let files = parse_file_status(output);
+    fn test_interdiff_pairs_rewritten_commits() {
+        let repo = TestRepo::new("interdiff");
+        repo.write("base.txt", "base\n");
+        repo.commit_all("base");
+        repo.git(&["checkout", "-q", "-b", "stack"]);
+        let numbers: String = (1..=20).map(|n| format!("{}\n", n)).collect();
+        repo.write("one.txt", &numbers);
+        repo.commit_all("one");
+        repo.write("two.txt", &numbers);
+        repo.commit_all("two");
+        repo.write("three.txt", "3\n");
+        let old_tip = repo.commit_all("three");
+
+        repo.git(&["reset", "-q", "--hard", "HEAD~2"]);
+        repo.write("two.txt", &format!("{}extra\n", numbers));
+        repo.commit_all("two");
+        repo.write("four.txt", "4\n");
+        let new_tip = repo.commit_all("four");
+
+        let result = get_interdiff(repo.dir(), &old_tip, "HEAD", &DiffOptions::default()).unwrap();
+        let statuses: Vec<_> = result.pairs.iter().map(|p| p.status.as_str()).collect();
+        assert_eq!(statuses, vec!["modified", "removed", "added"]);
+        assert_eq!(result.pairs[0].subject, "two");
+        assert_eq!(result.pairs[0].old_index, Some(1));
+        assert_eq!(
+            result.pairs[0].new_hash,
+            Some(repo.git(&["rev-parse", "HEAD~1"]))
+        );
+        assert!(result.pairs[0].changes.contains("++extra"));
+        assert_eq!(result.pairs[1].new_hash, None);
+        assert_eq!(result.pairs[2].new_hash.as_deref(), Some(new_tip.as_str()));
+
+        let paths: Vec<_> = result
+            .interdiff
+            .files
+            .iter()
+            .map(|f| f.path.as_str())
+            .collect();
+        assert_eq!(paths, vec!["four.txt", "three.txt", "two.txt"]);
+
+        let ranges = get_interdiff(
+            repo.dir(),
+            &format!("main..{}", old_tip),
+            "main..HEAD",
+            &DiffOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(ranges.pairs[0].status, "unchanged");
+        assert_eq!(ranges.pairs.len(), 4);
+    }
 
-        assert_eq!(files.len(), 1);
-        assert_eq!(files[0].path, "src/old.rs");
-        assert_eq!(files[0].status, "deleted");
+    #[test]
+    fn test_stash_list_and_diff() {
+        let repo = TestRepo::new("stash");
+        repo.write("a.txt", "a\n");
+        repo.commit_all("initial");
+        repo.write("a.txt", "a\nchanged\n");
+        repo.write("new.txt", "new\n");
+        repo.write_bytes("latin.txt", b"caf\xe9 cr\xe8me br\xfbl\xe9e\n");
+        repo.git(&[
+            "stash",
+            "push",
+            "-q",
+            "--include-untracked",
+            "-m",
+            "save work",
+        ]);
+        repo.git(&["checkout", "-q", "-b", "other"]);
+        repo.write("a.txt", "a\nother\n");
+        repo.git(&["stash", "-q"]);
+
+        let stashes = list_stashes(repo.dir()).unwrap();
+        assert_eq!(stashes.len(), 2);
+        assert_eq!(stashes[0].name, "stash@{0}");
+        assert_eq!(stashes[0].branch.as_deref(), Some("other"));
+        assert!(!stashes[0].has_untracked);
+        assert_eq!(stashes[1].index, 1);
+        assert_eq!(stashes[1].message, "save work");
+        assert_eq!(stashes[1].branch.as_deref(), Some("main"));
+        assert_eq!(stashes[1].timestamp, 1704067200);
+        assert!(stashes[1].has_untracked);
+
+        let result = get_stash_diff(repo.dir(), "1", &DiffOptions::default()).unwrap();
+        let files: Vec<_> = result
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.status.as_str()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("a.txt", "modified"),
+                ("latin.txt", "added"),
+                ("new.txt", "added")
+            ]
+        );
+        assert!(result.diff.contains("+changed"));
+        assert!(result
+            .diff
+            .contains("+caf\u{e9} cr\u{e8}me br\u{fb}l\u{e9}e\n"));
+        assert_eq!(result.file_diffs.len(), 3);
+        assert_eq!(result.files[1].encoding.as_deref(), Some("windows-1252"));
+
+        let latest = get_stash_diff(repo.dir(), "stash@{0}", &DiffOptions::default()).unwrap();
+        assert_eq!(latest.files.len(), 1);
+        assert!(latest.diff.contains("+other"));
+        assert!(get_stash_diff(repo.dir(), "HEAD", &DiffOptions::default()).is_err());
+
+        // A merge has two parents too, but isn't a stash entry.
+        repo.git(&["checkout", "-q", "-b", "side"]);
+        repo.write("side.txt", "side\n");
+        repo.commit_all("side");
+        repo.git(&["checkout", "-q", "other"]);
+        repo.git(&["merge", "-q", "--no-ff", "-m", "merge", "side"]);
+        let err = get_stash_diff(repo.dir(), "HEAD", &DiffOptions::default()).unwrap_err();
+        assert!(err.contains("not a stash entry"), "{}", err);
     }
 
     #[test]
-    fn test_parse_file_status_multiple() {
-        let output = "M\tsrc/main.rs\nA\tsrc/new.rs\nD\tsrc/old.rs\n";
-        let files = parse_file_status(output);
+    fn test_parse_stash_list_without_branch() {
+        let output = "\x1estash@{0}\x1fabc\x1fautostash\x1f1 day ago\x1f2024-01-01T00:00:00+00:00\x1f1704067200\x1fp1 p2\0";
+        let stashes = parse_stash_list(output);
+        assert_eq!(stashes.len(), 1);
+        assert_eq!(stashes[0].branch, None);
+        assert_eq!(stashes[0].message, "autostash");
+    }
 
-        assert_eq!(files.len(), 3);
-        assert_eq!(files[0].status, "modified");
-        assert_eq!(files[1].status, "added");
-        assert_eq!(files[2].status, "deleted");
+    #[test]
+    fn test_list_tags_and_tag_diff() {
+        let repo = TestRepo::new("tags");
+        repo.write("a.txt", "a\n");
+        let first = repo.commit_all("initial");
+        repo.git(&["tag", "-a", "v1", "-m", "Release 1\n\nNotes."]);
+        repo.write("a.txt", "a\nb\n");
+        repo.commit_all("add b");
+        repo.write("c.txt", "c\n");
+        let last = repo.commit_all("add c");
+        repo.git(&["tag", "v2"]);
+
+        let tags = list_tags(repo.dir()).unwrap();
+        assert_eq!(tags.len(), 2);
+        let v1 = tags.iter().find(|t| t.name == "v1").unwrap();
+        assert!(v1.is_annotated);
+        assert_eq!(v1.commit, first);
+        assert_eq!(v1.tagger.as_deref(), Some("Test User"));
+        assert_eq!(v1.tagger_email.as_deref(), Some("test@example.com"));
+        assert_eq!(v1.message.as_deref(), Some("Release 1\n\nNotes."));
+        assert_eq!(v1.timestamp, 1704067200);
+        let v2 = tags.iter().find(|t| t.name == "v2").unwrap();
+        assert!(!v2.is_annotated);
+        assert_eq!(v2.commit, last);
+        assert_eq!(v2.tagger, None);
+        assert_eq!(v2.message, None);
+
+        let result = get_tag_diff(repo.dir(), "v1", "v2", &DiffOptions::default()).unwrap();
+        let messages: Vec<_> = result.commits.iter().map(|c| c.message.as_str()).collect();
+        assert_eq!(messages, vec!["add c", "add b"]);
+        assert_eq!(result.diff.files.len(), 2);
+        assert!(get_tag_diff(repo.dir(), "v1", "v3", &DiffOptions::default()).is_err());
     }
 
     #[test]
-    fn test_parse_file_status_empty() {
-        let output = "";
-        let files = parse_file_status(output);
+    fn test_repo_state_clean() {
+        let repo = TestRepo::new("state");
+        repo.write("a.txt", "a\n");
+        repo.commit_all("initial");
+
+        let state = get_repo_state(repo.dir()).unwrap();
+        assert!(state.operation.is_none());
+        assert!(state.conflicted_files.is_empty());
+    }
 
-        assert_eq!(files.len(), 0);
+    #[test]
+    fn test_repo_state_conflicted_merge() {
+        let (repo, _) = conflicted_merge();
+        repo.git(&["reset", "-q", "--hard", "HEAD^1"]);
+        let feature = repo.git(&["rev-parse", "feature"]);
+        let _ = Command::new("git")
+            .args(["merge", "-q", "feature"])
+            .current_dir(repo.dir())
+            .output();
+
+        let state = get_repo_state(repo.dir()).unwrap();
+        assert_eq!(state.operation.as_deref(), Some("merge"));
+        assert_eq!(state.operation_head, Some(feature));
+        assert_eq!(state.conflicted_files.len(), 1);
+        assert_eq!(state.conflicted_files[0].path, "conflict.txt");
+        assert_eq!(state.conflicted_files[0].conflict, "both-modified");
+
+        let opts = DiffOptions::default();
+        let versions = get_conflict_versions(repo.dir(), "conflict.txt", &opts).unwrap();
+        assert_eq!(versions.base.as_deref(), Some("a\nb\nc\n"));
+        assert_eq!(versions.ours.as_deref(), Some("a\nbb\nc\n"));
+        assert_eq!(versions.theirs.as_deref(), Some("a\nB\nc\n"));
+        assert!(!versions.is_binary);
+        assert_eq!(
+            versions.diff.diff,
+            "diff --git a/conflict.txt b/conflict.txt\n--- a/conflict.txt\n+++ b/conflict.txt\n\
+             @@ -1,3 +1,3 @@\n a\n-bb\n+B\n c\n"
+        );
+        assert_eq!(versions.diff.files[0].status, "modified");
+        assert_eq!(versions.diff.files[0].additions, 1);
+        assert!(get_conflict_versions(repo.dir(), "feature.txt", &opts).is_err());
     }
 
     #[test]
-    fn test_parse_file_status_renamed() {
-        let output = "R100\tsrc/old.rs\tsrc/new.rs\n";
-        let files = parse_file_status(output);
+    fn test_repo_state_conflicted_rebase() {
+        let (repo, _) = conflicted_merge();
+        repo.git(&["reset", "-q", "--hard", "HEAD^1"]);
+        repo.git(&["checkout", "-q", "feature"]);
+        let _ = Command::new("git")
+            .args(["rebase", "-q", "main"])
+            .current_dir(repo.dir())
+            .output();
+
+        let state = get_repo_state(repo.dir()).unwrap();
+        assert_eq!(state.operation.as_deref(), Some("rebase"));
+        assert_eq!(state.rebase_branch.as_deref(), Some("feature"));
+        assert_eq!(state.rebase_step, Some(1));
+        assert_eq!(state.rebase_total, Some(1));
+        assert_eq!(state.conflicted_files[0].path, "conflict.txt");
+    }
 
-        assert_eq!(files.len(), 1);
-        assert_eq!(files[0].path, "src/new.rs");
-        assert_eq!(files[0].status, "renamed");
+    #[test]
+    fn test_conflict_diff_of_deletions_encodings_and_whitespace() {
+        let repo = TestRepo::new("conflict-diff");
+        repo.write("gone.txt", "x\n");
+        repo.write("ws.txt", "a\nb\n");
+        repo.commit_all("initial");
+        repo.git(&["checkout", "-q", "-b", "feature"]);
+        repo.git(&["rm", "-q", "gone.txt"]);
+        repo.write_bytes("ws.txt", b"a \ncaf\xe9\n");
+        repo.commit_all("feature");
+        repo.git(&["checkout", "-q", "main"]);
+        repo.write("gone.txt", "x2\n");
+        repo.write("ws.txt", "a\nb2\n");
+        repo.commit_all("main");
+        let _ = Command::new("git")
+            .args(["merge", "-q", "feature"])
+            .current_dir(repo.dir())
+            .output();
+
+        let opts = DiffOptions::default();
+        let gone = get_conflict_versions(repo.dir(), "gone.txt", &opts).unwrap();
+        assert_eq!(
+            gone.diff.diff,
+            "diff --git a/gone.txt b/gone.txt\ndeleted file mode 100644\n\
+             --- a/gone.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-x2\n"
+        );
+        assert_eq!(gone.diff.files[0].status, "deleted");
+
+        let opts = DiffOptions {
+            ignore_whitespace: WhitespaceMode::All,
+            ..Default::default()
+        };
+        let ws = get_conflict_versions(repo.dir(), "ws.txt", &opts).unwrap();
+        // "a" against "a " only differs in whitespace, so it's context.
+        assert!(
+            ws.diff
+                .diff
+                .ends_with("@@ -1,2 +1,2 @@\n a \n-b2\n+caf\u{e9}\n"),
+            "{}",
+            ws.diff.diff
+        );
+        assert_eq!(ws.diff.files[0].encoding.as_deref(), Some("windows-1252"));
+    }
+
+    #[test]
+    fn test_parse_unmerged_entries_kinds() {
+        let output = concat!(
+            "100644 aaa 1\tgone.txt\0",
+            "100644 bbb 2\tgone.txt\0",
+            "100644 ccc 2\tnew.txt\0",
+            "100644 ddd 3\tnew.txt\0",
+        );
+        let files = parse_unmerged_entries(output);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].conflict, "deleted-by-them");
+        assert_eq!(files[1].path, "new.txt");
+        assert_eq!(files[1].conflict, "both-added");
     }
 
     #[test]
     fn test_parse_file_status_invalid_line() {
-        let output = "M\n";
+        let output = "M\0";
         let files = parse_file_status(output);
 
         assert_eq!(files.len(), 0);
@@ -1566,11 +5232,42 @@ mod tests {
         assert!(!is_git_repo(&temp_dir));
     }
 
+    /// A `git log -z` record in `COMMIT_LOG_FORMAT`: hash, short hash, subject,
+    /// author, relative date and refs, with the remaining fields filled in.
+    fn log_record(hash: &str, short: &str, subject: &str, author: &str, refs: &str) -> String {
+        [
+            hash,
+            short,
+            subject,
+            author,
+            "2 days ago",
+            refs,
+            "a@example.com",
+            "2024-01-01T00:00:00+00:00",
+            "1704067200",
+            "",
+            "N",
+            "",
+            "",
+        ]
+        .iter()
+        .fold(String::new(), |mut out, field| {
+            out.push(if out.is_empty() { '\x1e' } else { '\x1f' });
+            out.push_str(field);
+            out
+        }) + "\0"
+    }
+
     #[test]
     fn test_parse_commit_log_single() {
-        let output =
-            "abc123def456|abc123d|Initial commit|John Doe|2 days ago|HEAD -> main, origin/main\n";
-        let commits = parse_commit_log(output);
+        let output = log_record(
+            "abc123def456",
+            "abc123d",
+            "Initial commit",
+            "John Doe",
+            "HEAD -> main, origin/main",
+        );
+        let commits = parse_commit_log(&output);
 
         assert_eq!(commits.len(), 1);
         assert_eq!(commits[0].hash, "abc123def456");
@@ -1579,12 +5276,17 @@ mod tests {
         assert_eq!(commits[0].author, "John Doe");
         assert_eq!(commits[0].date, "2 days ago");
         assert_eq!(commits[0].refs, "HEAD -> main, origin/main");
+        assert_eq!(commits[0].author_email, "a@example.com");
+        assert_eq!(commits[0].timestamp, 1704067200);
+        assert_eq!(commits[0].signature, "none");
+        assert!(commits[0].parents.is_empty());
     }
 
     #[test]
     fn test_parse_commit_log_multiple() {
-        let output = "abc123|abc1|First commit|Alice|1 day ago|HEAD -> main\ndef456|def4|Second commit|Bob|2 days ago|\n";
-        let commits = parse_commit_log(output);
+        let output = log_record("abc123", "abc1", "First commit", "Alice", "HEAD -> main")
+            + &log_record("def456", "def4", "Second commit", "Bob", "");
+        let commits = parse_commit_log(&output);
 
         assert_eq!(commits.len(), 2);
         assert_eq!(commits[0].hash, "abc123");
@@ -1597,8 +5299,8 @@ mod tests {
 
     #[test]
     fn test_parse_commit_log_no_refs() {
-        let output = "abc123|abc1|Commit without refs|Alice|1 day ago|\n";
-        let commits = parse_commit_log(output);
+        let output = log_record("abc123", "abc1", "Commit without refs", "Alice", "");
+        let commits = parse_commit_log(&output);
 
         assert_eq!(commits.len(), 1);
         assert_eq!(commits[0].refs, "");
@@ -1614,7 +5316,7 @@ mod tests {
 
     #[test]
     fn test_parse_commit_log_invalid_line() {
-        let output = "invalid|line\n";
+        let output = "\x1einvalid\x1fline\0";
         let commits = parse_commit_log(output);
 
         assert_eq!(commits.len(), 0);
@@ -1622,19 +5324,149 @@ mod tests {
 
     #[test]
     fn test_parse_commit_log_message_with_pipe() {
-        let output = "abc123|abc1|Fix: update config | add tests|Alice|1 day ago|main\n";
-        let commits = parse_commit_log(output);
+        let output = log_record(
+            "abc123",
+            "abc1",
+            "Fix: update config | add tests",
+            "Alice",
+            "main",
+        );
+        let commits = parse_commit_log(&output);
 
         assert_eq!(commits.len(), 1);
         assert_eq!(commits[0].message, "Fix: update config | add tests");
         assert_eq!(commits[0].author, "Alice");
-        assert_eq!(commits[0].date, "1 day ago");
+        assert_eq!(commits[0].date, "2 days ago");
         assert_eq!(commits[0].refs, "main");
     }
 
+    #[test]
+    fn test_ranges_are_never_read_as_options() {
+        let repo = TestRepo::new("log-options");
+        repo.write("a.txt", "a\n");
+        repo.commit_all("add a");
+        let out = repo.dir().join("out.txt");
+        let range = format!("--output={}", out.display());
+
+        let opts = LogOptions {
+            range: Some(range.clone()),
+            ..Default::default()
+        };
+        assert!(list_commits(repo.dir(), &opts).is_err());
+        let diff_opts = DiffOptions::default();
+        assert!(get_range_diff(repo.dir(), &range, &diff_opts).is_err());
+        assert!(get_commit_diff(repo.dir(), &range, &diff_opts).is_err());
+        assert!(get_gg_entry_diff(repo.dir(), "stack", &range, &diff_opts).is_err());
+        assert!(get_branch_diff(repo.dir(), &range, &diff_opts).is_err());
+        assert!(get_merge_base_refs(repo.dir(), &range, "HEAD").is_err());
+        assert!(get_lfs_file_at_ref(repo.dir(), &range, "a.txt").is_err());
+        assert!(list_files_at_ref(repo.dir(), &range).is_err());
+        assert!(blame_range(repo.dir(), Some(&range), "a.txt", 1, 1).is_err());
+        assert!(!out.exists());
+
+        let opts = LogOptions {
+            verify_signatures: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            list_commits(repo.dir(), &opts).unwrap()[0].signature,
+            "none"
+        );
+    }
+
+    #[test]
+    fn test_list_commits_details_and_filters() {
+        let repo = TestRepo::new("log");
+        repo.write("a.txt", "a\n");
+        let first = repo.commit_all("add a");
+        repo.write("b.txt", "b\n");
+        repo.git(&["add", "b.txt"]);
+        repo.git(&[
+            "commit",
+            "-q",
+            "--author=Other Person <other@example.com>",
+            "-m",
+            "add b\n\nLonger explanation.\n\nReviewed-by: Alice <alice@example.com>",
+        ]);
+        repo.write("a.txt", "a\na\n");
+        repo.commit_all("fix a");
+
+        let all = list_commits(repo.dir(), &LogOptions::default()).unwrap();
+        assert_eq!(all.len(), 3);
+        let b = &all[1];
+        assert_eq!(b.message, "add b");
+        assert_eq!(b.author_email, "other@example.com");
+        assert_eq!(b.timestamp, 1704067200);
+        assert_eq!(b.date_iso, "2024-01-01T00:00:00+00:00");
+        assert_eq!(b.parents, vec![first.clone()]);
+        assert!(b.body.starts_with("Longer explanation."));
+        assert_eq!(
+            b.trailers,
+            vec![CommitTrailer {
+                key: "Reviewed-by".to_string(),
+                value: "Alice <alice@example.com>".to_string(),
+            }]
+        );
+        assert_eq!(b.signature, "none");
+
+        let page = list_commits(
+            repo.dir(),
+            &LogOptions {
+                skip: 1,
+                limit: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].message, "add b");
+
+        let filtered = |opts: LogOptions| -> Vec<String> {
+            list_commits(repo.dir(), &opts)
+                .unwrap()
+                .into_iter()
+                .map(|c| c.message)
+                .collect()
+        };
+        assert_eq!(
+            filtered(LogOptions {
+                author: Some("other@".to_string()),
+                ..Default::default()
+            }),
+            vec!["add b"]
+        );
+        assert_eq!(
+            filtered(LogOptions {
+                file_path: Some("a.txt".to_string()),
+                ..Default::default()
+            }),
+            vec!["fix a", "add a"]
+        );
+        assert_eq!(
+            filtered(LogOptions {
+                grep: Some("^fix".to_string()),
+                ..Default::default()
+            }),
+            vec!["fix a"]
+        );
+        assert!(filtered(LogOptions {
+            since: Some("2024-06-01".to_string()),
+            ..Default::default()
+        })
+        .is_empty());
+        assert_eq!(
+            filtered(LogOptions {
+                until: Some("2024-06-01".to_string()),
+                ..Default::default()
+            })
+            .len(),
+            3
+        );
+    }
+
     #[test]
     fn test_parse_branch_list_single() {
-        let output = "main|abc1234|Initial commit|Alice|2 days ago\n";
+        let output = "main\x1fabc1234\x1fInitial commit\x1fAlice\x1f2 days ago\0";
         let branches = parse_branch_list(output);
 
         assert_eq!(branches.len(), 1);
@@ -1647,7 +5479,7 @@ mod tests {
 
     #[test]
     fn test_parse_branch_list_multiple() {
-        let output = "main|abc1234|Main work|Alice|1 day ago\norigin/main|def5678|Remote main|Bob|3 days ago\n";
+        let output = "main\x1fabc1234\x1fMain work\x1fAlice\x1f1 day ago\0origin/main\x1fdef5678\x1fRemote main\x1fBob\x1f3 days ago\0";
         let branches = parse_branch_list(output);
 
         assert_eq!(branches.len(), 2);
@@ -1663,13 +5495,74 @@ mod tests {
 
     #[test]
     fn test_parse_branch_list_invalid_line() {
-        let branches = parse_branch_list("main|abc123\n");
+        let branches = parse_branch_list("main\x1fabc123\0");
         assert_eq!(branches.len(), 0);
     }
 
+    #[test]
+    fn test_parse_branch_list_tracking_fields() {
+        let output = concat!(
+            "main\x1fabc\x1fs\x1fA\x1fnow\x1frefs/heads/main\x1f1704067200\x1forigin/main\x1fahead 2, behind 1\x1f*\x1f\0",
+            "old\x1fabc\x1fs\x1fA\x1fnow\x1frefs/heads/old\x1f0\x1forigin/old\x1fgone\x1f \x1f\0",
+            "origin\x1fabc\x1fs\x1fA\x1fnow\x1frefs/remotes/origin/HEAD\x1f0\x1f\x1f\x1f \x1frefs/remotes/origin/main\0",
+            "origin/main\x1fabc\x1fs\x1fA\x1fnow\x1frefs/remotes/origin/main\x1f0\x1f\x1f\x1f \x1f\0",
+        );
+        let branches = parse_branch_list(output);
+
+        assert_eq!(branches.len(), 3);
+        assert!(branches[0].is_current && branches[0].is_local);
+        assert_eq!(branches[0].timestamp, 1704067200);
+        assert_eq!(branches[0].upstream.as_deref(), Some("origin/main"));
+        assert_eq!(
+            (branches[0].ahead_upstream, branches[0].behind_upstream),
+            (Some(2), Some(1))
+        );
+        assert_eq!(branches[1].ahead_upstream, None);
+        assert!(!branches[1].is_current);
+        assert!(branches[2].is_remote && !branches[2].is_local);
+        assert_eq!(branches[2].upstream, None);
+    }
+
+    #[test]
+    fn test_list_branches_counts_against_upstream_and_base() {
+        let repo = TestRepo::new("branches");
+        repo.write("a.txt", "a\n");
+        repo.commit_all("initial");
+        repo.git(&["checkout", "-q", "-b", "feature"]);
+        repo.commit_all("one");
+        repo.git(&["remote", "add", "origin", "/nonexistent"]);
+        repo.git(&["update-ref", "refs/remotes/origin/feature", "HEAD"]);
+        repo.git(&["branch", "-q", "--set-upstream-to=origin/feature"]);
+        repo.commit_all("two");
+        repo.git(&["checkout", "-q", "main"]);
+        repo.commit_all("main moved on");
+
+        let branches = list_branches(repo.dir()).unwrap();
+        let names: Vec<_> = branches.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, vec!["feature", "main", "origin/feature"]);
+
+        let feature = &branches[0];
+        assert!(!feature.is_current);
+        assert_eq!(feature.upstream.as_deref(), Some("origin/feature"));
+        assert_eq!(
+            (feature.ahead_upstream, feature.behind_upstream),
+            (Some(1), Some(0))
+        );
+        assert_eq!(feature.base.as_deref(), Some("main"));
+        assert_eq!(
+            (feature.ahead_base, feature.behind_base),
+            (Some(2), Some(1))
+        );
+
+        let main = &branches[1];
+        assert!(main.is_current && main.is_local);
+        assert_eq!(main.ahead_base, None);
+        assert!(branches[2].is_remote);
+    }
+
     #[test]
     fn test_parse_worktree_list_with_branches() {
-        let output = "worktree /repo/main\nHEAD abc1234def5678\nbranch refs/heads/main\n\nworktree /repo/wt1\nHEAD def5678abc1234\nbranch refs/heads/feature-x\n";
+        let output = "worktree /repo/main\0HEAD abc1234def5678\0branch refs/heads/main\0\0worktree /repo/wt1\0HEAD def5678abc1234\0branch refs/heads/feature-x\0";
         let worktrees = parse_worktree_list(output);
 
         assert_eq!(worktrees.len(), 2);
@@ -1688,7 +5581,7 @@ mod tests {
 
     #[test]
     fn test_parse_worktree_list_detached() {
-        let output = "worktree /repo/main\nHEAD abc1234def5678\nbranch refs/heads/main\n\nworktree /repo/wt-detached\nHEAD 1234567890abcd\ndetached\n";
+        let output = "worktree /repo/main\0HEAD abc1234def5678\0branch refs/heads/main\0\0worktree /repo/wt-detached\0HEAD 1234567890abcd\0detached\0";
         let worktrees = parse_worktree_list(output);
 
         assert_eq!(worktrees.len(), 2);
@@ -1699,7 +5592,7 @@ mod tests {
 
     #[test]
     fn test_parse_worktree_list_ignores_invalid_blocks() {
-        let output = "HEAD abc1234def5678\nbranch refs/heads/main\n\nworktree /repo/wt1\nHEAD def5678abc1234\nbranch refs/heads/feature-x\n";
+        let output = "HEAD abc1234def5678\0branch refs/heads/main\0\0worktree /repo/wt1\0HEAD def5678abc1234\0branch refs/heads/feature-x\0";
         let worktrees = parse_worktree_list(output);
 
         assert_eq!(worktrees.len(), 1);
@@ -1709,30 +5602,223 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_has_staged_changes() {
-        assert_eq!(has_staged_changes("M  src/main.rs\n"), true);
-        assert_eq!(has_staged_changes(" M src/main.rs\n"), false);
-        assert_eq!(has_staged_changes("?? src/new.rs\n"), false);
-        assert_eq!(has_staged_changes(""), false);
-        assert_eq!(has_staged_changes("MM src/main.rs\n"), true);
-        assert_eq!(has_staged_changes("A  src/new.rs\n"), true);
-        assert_eq!(has_staged_changes("D  src/old.rs\n"), true);
+        assert_eq!(has_staged_changes(&status_v2("M  src/main.rs\n")), true);
+        assert_eq!(has_staged_changes(&status_v2(" M src/main.rs\n")), false);
+        assert_eq!(has_staged_changes(&status_v2("?? src/new.rs\n")), false);
+        assert_eq!(has_staged_changes(&status_v2("")), false);
+        assert_eq!(has_staged_changes(&status_v2("MM src/main.rs\n")), true);
+        assert_eq!(has_staged_changes(&status_v2("A  src/new.rs\n")), true);
+        assert_eq!(has_staged_changes(&status_v2("D  src/old.rs\n")), true);
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_has_unstaged_changes() {
-        assert_eq!(has_unstaged_changes(" M src/main.rs\n"), true);
-        assert_eq!(has_unstaged_changes("M  src/main.rs\n"), false);
-        assert_eq!(has_unstaged_changes("?? src/new.rs\n"), true);
-        assert_eq!(has_unstaged_changes(""), false);
-        assert_eq!(has_unstaged_changes("MM src/main.rs\n"), true);
-        assert_eq!(has_unstaged_changes(" D src/old.rs\n"), true);
+        assert_eq!(has_unstaged_changes(&status_v2(" M src/main.rs\n")), true);
+        assert_eq!(has_unstaged_changes(&status_v2("M  src/main.rs\n")), false);
+        assert_eq!(has_unstaged_changes(&status_v2("?? src/new.rs\n")), true);
+        assert_eq!(has_unstaged_changes(&status_v2("")), false);
+        assert_eq!(has_unstaged_changes(&status_v2("MM src/main.rs\n")), true);
+        assert_eq!(has_unstaged_changes(&status_v2(" D src/old.rs\n")), true);
+    }
+
+    /// In-memory backend for exercising ref logic without a repository.
+    #[derive(Default)]
+    struct FakeBackend {
+        refs: Vec<&'static str>,
+        current: &'static str,
+        merge_bases: Vec<(&'static str, &'static str, &'static str)>,
+        config: Vec<(&'static str, &'static str)>,
+        symbolic: Vec<(&'static str, &'static str)>,
+    }
+
+    impl GitBackend for FakeBackend {
+        fn toplevel(&self, _dir: &Path) -> Option<PathBuf> {
+            None
+        }
+        fn rev_parse(&self, _dir: &Path, rev: &str) -> Result<String, String> {
+            Ok(rev.to_string())
+        }
+        fn ref_exists(&self, _dir: &Path, refname: &str) -> bool {
+            self.refs.contains(&refname)
+        }
+        fn symbolic_target(&self, _dir: &Path, refname: &str) -> Option<String> {
+            self.symbolic
+                .iter()
+                .find(|(name, _)| *name == refname)
+                .map(|(_, target)| target.to_string())
+        }
+        fn current_branch(&self, _dir: &Path) -> Result<String, String> {
+            Ok(self.current.to_string())
+        }
+        fn local_branches(&self, _dir: &Path) -> Result<Vec<String>, String> {
+            Ok(Vec::new())
+        }
+        fn merge_base(&self, _dir: &Path, rev1: &str, rev2: &str) -> Result<String, String> {
+            self.merge_bases
+                .iter()
+                .find(|(a, b, _)| *a == rev1 && *b == rev2)
+                .map(|(_, _, base)| base.to_string())
+                .ok_or_else(|| "no merge base".to_string())
+        }
+        fn count_commits(&self, _dir: &Path, _base: &str, _head: &str) -> Result<usize, String> {
+            Ok(0)
+        }
+        fn ahead_behind(
+            &self,
+            _dir: &Path,
+            _base: &str,
+            _head: &str,
+        ) -> Result<(usize, usize), String> {
+            Ok((0, 0))
+        }
+        fn git_dir(&self, _dir: &Path) -> Option<PathBuf> {
+            None
+        }
+        fn commit_parents(&self, _dir: &Path, _rev: &str) -> Result<Vec<String>, String> {
+            Ok(Vec::new())
+        }
+        fn read_blob(&self, _dir: &Path, _rev: &str, _path: &str) -> Result<Vec<u8>, String> {
+            Err("no blobs".to_string())
+        }
+        fn list_tree(&self, _dir: &Path, _rev: &str) -> Result<Vec<String>, String> {
+            Ok(Vec::new())
+        }
+        fn config_value(&self, _dir: &Path, key: &str) -> Option<String> {
+            self.config
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, value)| value.to_string())
+        }
+        fn head_commit_time(&self, _dir: &Path) -> Option<i64> {
+            None
+        }
+        fn hash_blob(&self, _dir: &Path, _content: &[u8]) -> Result<String, String> {
+            Err("no hashing".to_string())
+        }
+    }
+
+    #[test]
+    fn test_get_branch_base_prefers_main_merge_base() {
+        let backend = FakeBackend {
+            refs: vec!["refs/heads/main"],
+            current: "feature",
+            merge_bases: vec![("main", "feature", "abc123")],
+            ..Default::default()
+        };
+        let base = get_branch_base_with(&backend, Path::new("/repo"), "feature").unwrap();
+        assert_eq!(base, "abc123");
+    }
+
+    #[test]
+    fn test_get_branch_base_falls_back_to_current_branch_name() {
+        let backend = FakeBackend {
+            current: "develop",
+            ..Default::default()
+        };
+        let base = get_branch_base_with(&backend, Path::new("/repo"), "feature").unwrap();
+        assert_eq!(base, "develop");
+    }
+
+    fn base_for(backend: &FakeBackend, branch: &str) -> (String, &'static str) {
+        resolve_base_branch(backend, Path::new("/repo"), branch).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_base_branch_order() {
+        let mut backend = FakeBackend {
+            refs: vec![
+                "refs/heads/master",
+                "refs/heads/develop",
+                "refs/remotes/origin/trunk",
+                "refs/remotes/origin/feature",
+            ],
+            current: "feature",
+            config: vec![
+                (BASE_BRANCH_CONFIG_KEY, "release"),
+                ("branch.feature.remote", "."),
+                ("branch.feature.merge", "refs/heads/develop"),
+            ],
+            symbolic: vec![("refs/remotes/origin/HEAD", "refs/remotes/origin/trunk")],
+            ..Default::default()
+        };
+        assert_eq!(
+            base_for(&backend, "feature"),
+            ("release".to_string(), "configured")
+        );
+
+        backend.config.remove(0);
+        assert_eq!(
+            base_for(&backend, "feature"),
+            ("develop".to_string(), "upstream")
+        );
+
+        // Tracking its own remote copy is not a base.
+        backend.config = vec![
+            ("branch.feature.remote", "origin"),
+            ("branch.feature.merge", "refs/heads/feature"),
+        ];
+        assert_eq!(
+            base_for(&backend, "feature"),
+            ("origin/trunk".to_string(), "origin-head")
+        );
+
+        backend.symbolic.clear();
+        assert_eq!(
+            base_for(&backend, "feature"),
+            ("master".to_string(), "common-name")
+        );
+
+        backend.refs = vec!["refs/remotes/origin/trunk"];
+        assert_eq!(
+            base_for(&backend, "feature"),
+            ("origin/trunk".to_string(), "common-name")
+        );
+
+        backend.refs.clear();
+        assert_eq!(
+            base_for(&backend, "feature"),
+            ("feature".to_string(), "current-branch")
+        );
+    }
+
+    #[test]
+    fn test_resolve_branch_base_in_repo() {
+        let repo = TestRepo::new("base");
+        repo.git(&["checkout", "-q", "-b", "master"]);
+        repo.write("a.txt", "a\n");
+        let root = repo.commit_all("initial");
+        repo.git(&["checkout", "-q", "-b", "feature"]);
+        repo.write("b.txt", "b\n");
+        repo.commit_all("feature work");
+
+        let base = resolve_branch_base(repo.dir(), "feature").unwrap();
+        assert_eq!(base.base, "master");
+        assert_eq!(base.reason, "common-name");
+        assert_eq!(base.merge_base.as_deref(), Some(root.as_str()));
+
+        let diff = get_branch_diff(repo.dir(), "feature", &DiffOptions::default()).unwrap();
+        assert_eq!(diff.files.len(), 1);
+
+        set_base_branch(repo.dir(), Some("feature")).unwrap();
+        assert_eq!(
+            resolve_branch_base(repo.dir(), "feature").unwrap().reason,
+            "configured"
+        );
+        set_base_branch(repo.dir(), None).unwrap();
+        set_base_branch(repo.dir(), None).unwrap();
+        assert_eq!(
+            resolve_branch_base(repo.dir(), "feature").unwrap().base,
+            "master"
+        );
     }
 
     #[test]
     fn test_get_change_status_from_porcelain() {
         let output = "M  src/staged.rs\n M src/unstaged.rs\n";
-        let status = get_change_status_from_porcelain(output);
+        let status = get_change_status_from_porcelain(&status_v2(output));
         assert!(status.has_staged);
         assert!(status.has_unstaged);
 
@@ -1740,8 +5826,1130 @@ mod tests {
         assert!(!empty_status.has_staged);
         assert!(!empty_status.has_unstaged);
 
-        let only_untracked = get_change_status_from_porcelain("?? new.rs\n");
+        let only_untracked = get_change_status_from_porcelain(&status_v2("?? new.rs\n"));
         assert!(!only_untracked.has_staged);
         assert!(only_untracked.has_unstaged);
     }
+
+    #[test]
+    fn test_review_snapshot_includes_untracked_files() {
+        let repo = TestRepo::new("review-snapshot");
+        repo.write(".gitignore", "*.log\n");
+        repo.write("a.txt", "one\n");
+        let head = repo.commit_all("initial");
+        repo.write("a.txt", "two\n");
+        repo.write("new.txt", "untracked\n");
+        repo.write("debug.log", "ignored\n");
+
+        assert!(get_review_snapshot(repo.dir()).unwrap().is_none());
+        let snapshot = record_review_snapshot(repo.dir(), None).unwrap();
+        assert_eq!(snapshot.ref_name, "refs/air/reviewed/main");
+        assert_eq!(snapshot.head.as_deref(), Some(head.as_str()));
+
+        let tree = repo.git(&["ls-tree", "--name-only", &snapshot.tree]);
+        assert_eq!(
+            tree.lines().collect::<Vec<_>>(),
+            [".gitignore", "a.txt", "new.txt"]
+        );
+        assert_eq!(
+            repo.git(&["show", &format!("{}:a.txt", snapshot.commit)]),
+            "two"
+        );
+
+        // The user's index is left alone.
+        assert_eq!(repo.git(&["diff", "--cached", "--name-only"]), "");
+        assert!(repo.git(&["status", "--porcelain"]).contains("?? new.txt"));
+    }
+
+    #[test]
+    fn test_diff_since_review() {
+        let repo = TestRepo::new("review-diff");
+        repo.write("a.txt", "one\n");
+        repo.write("b.txt", "one\n");
+        repo.commit_all("initial");
+        assert!(get_diff_since_review(repo.dir(), &DiffOptions::default()).is_err());
+
+        repo.write("a.txt", "reviewed\n");
+        record_review_snapshot(repo.dir(), None).unwrap();
+        repo.write("b.txt", "after review\n");
+        repo.write("c.txt", "new after review\n");
+
+        let result = get_diff_since_review(repo.dir(), &DiffOptions::default()).unwrap();
+        assert_eq!(file_paths(&result), ["b.txt", "c.txt"]);
+        assert!(result.diff.contains("+after review"));
+        assert!(!result.diff.contains("reviewed"));
+    }
+
+    #[test]
+    fn test_review_records_the_session_not_later_edits() {
+        let repo = TestRepo::new("review-session-snapshot");
+        repo.write("a.txt", "one\n");
+        repo.commit_all("initial");
+        repo.write("a.txt", "reviewed\n");
+        let session = open_review_session(repo.dir(), &DiffOptions::default()).unwrap();
+
+        // The agent keeps editing while the review is open.
+        repo.write("a.txt", "edited during review\n");
+        let snapshot = record_review_snapshot(repo.dir(), Some(&session.id)).unwrap();
+        assert_eq!(snapshot.commit, session.id);
+
+        let result = get_diff_since_review(repo.dir(), &DiffOptions::default()).unwrap();
+        assert_eq!(file_paths(&result), ["a.txt"]);
+        assert!(result.diff.contains("-reviewed\n+edited during review\n"));
+    }
+
+    #[test]
+    fn test_review_snapshots_are_keyed_by_branch() {
+        let repo = TestRepo::new("review-branches");
+        repo.write("a.txt", "one\n");
+        repo.commit_all("initial");
+        record_review_snapshot(repo.dir(), None).unwrap();
+
+        repo.git(&["checkout", "-q", "-b", "feature"]);
+        assert!(get_review_snapshot(repo.dir()).unwrap().is_none());
+        let snapshot = record_review_snapshot(repo.dir(), None).unwrap();
+        assert_eq!(snapshot.ref_name, "refs/air/reviewed/feature");
+
+        // Every worktree has a detached HEAD of its own.
+        repo.git(&["checkout", "-q", "--detach"]);
+        let detached = record_review_snapshot(repo.dir(), None).unwrap().ref_name;
+        assert!(detached.starts_with("refs/air/reviewed-detached/"));
+        let other = repo.dir().join("other");
+        repo.git(&["worktree", "add", "-q", "--detach", other.to_str().unwrap()]);
+        assert!(get_review_snapshot(&other).unwrap().is_none());
+        let other_ref = record_review_snapshot(&other, None).unwrap().ref_name;
+        assert!(other_ref.starts_with("refs/air/reviewed-detached/"));
+        assert_ne!(other_ref, detached);
+        assert_eq!(
+            get_review_snapshot(repo.dir()).unwrap().unwrap().ref_name,
+            detached
+        );
+        assert!(list_branches(repo.dir())
+            .unwrap()
+            .iter()
+            .all(|b| !b.name.contains("reviewed")));
+    }
+
+    #[test]
+    fn test_review_session_is_frozen() {
+        let repo = TestRepo::new("review-session");
+        repo.write("staged.txt", "one\n");
+        repo.write("edited.txt", "one\n");
+        let head = repo.commit_all("initial");
+        repo.write("staged.txt", "staged\n");
+        repo.git(&["add", "staged.txt"]);
+        repo.write("edited.txt", "edited\n");
+        repo.write("new.txt", "untracked\n");
+        repo.write("big.log", &"x".repeat(4096));
+
+        let limited = DiffOptions {
+            untracked_file_limit: Some(1024),
+            ..Default::default()
+        };
+        let session = open_review_session(repo.dir(), &limited).unwrap();
+        assert_eq!(session.head.as_deref(), Some(head.as_str()));
+
+        // The agent keeps working after the session was opened.
+        repo.write("edited.txt", "edited again\n");
+        repo.write("later.txt", "too late\n");
+        repo.git(&["add", "-A"]);
+
+        let opts = DiffOptions::default();
+        let unstaged = get_session_unstaged_diff(repo.dir(), &session.id, &opts).unwrap();
+        assert_eq!(file_paths(&unstaged), ["edited.txt", "new.txt"]);
+        assert!(unstaged.diff.contains("+edited\n"));
+        assert!(!unstaged.diff.contains("edited again"));
+
+        let staged = get_session_staged_diff(repo.dir(), &session.id, &opts).unwrap();
+        assert_eq!(file_paths(&staged), ["staged.txt"]);
+
+        let status = get_session_change_status(repo.dir(), &session.id).unwrap();
+        assert!(status.has_staged && status.has_unstaged);
+        assert_eq!(
+            get_file_at_ref(repo.dir(), &session.id, "edited.txt").unwrap(),
+            "edited\n"
+        );
+        let session_files = list_files_at_ref(repo.dir(), &session.id).unwrap();
+        assert!(!session_files.contains(&"later.txt".to_string()));
+        assert!(!session_files.contains(&"big.log".to_string()));
+
+        // The index and HEAD are read as they were at open time too.
+        let index = resolve_session_ref(repo.dir(), &session.id, ":0").unwrap();
+        assert_eq!(index, session.index);
+        assert_eq!(
+            get_file_at_ref(repo.dir(), &index, "edited.txt").unwrap(),
+            "one\n"
+        );
+        assert_eq!(
+            resolve_session_ref(repo.dir(), &session.id, "HEAD").unwrap(),
+            head
+        );
+        assert_eq!(
+            resolve_session_ref(repo.dir(), &session.id, "main~1").unwrap(),
+            "main~1"
+        );
+    }
+
+    #[test]
+    fn test_review_session_on_unborn_branch() {
+        let repo = TestRepo::new("review-session-unborn");
+        repo.write("a.txt", "staged\n");
+        repo.git(&["add", "a.txt"]);
+        repo.write("b.txt", "untracked\n");
+
+        let session = open_review_session(repo.dir(), &DiffOptions::default()).unwrap();
+        assert!(session.head.is_none());
+
+        let opts = DiffOptions::default();
+        let staged = get_session_staged_diff(repo.dir(), &session.id, &opts).unwrap();
+        assert_eq!(file_paths(&staged), ["a.txt"]);
+        let unstaged = get_session_unstaged_diff(repo.dir(), &session.id, &opts).unwrap();
+        assert_eq!(file_paths(&unstaged), ["b.txt"]);
+        assert!(get_review_session(repo.dir(), "not-a-session").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_modes_and_file_types() {
+        let repo = TestRepo::new("modes");
+        repo.write("script.sh", "echo hi\n");
+        repo.write("target.txt", "pointed at\n");
+        repo.write("becomes-link.txt", "regular for now\n");
+        repo.commit_all("initial");
+
+        repo.git(&["update-index", "--chmod=+x", "script.sh"]);
+        fs::remove_file(repo.dir().join("becomes-link.txt")).unwrap();
+        std::os::unix::fs::symlink("target.txt", repo.dir().join("becomes-link.txt")).unwrap();
+        repo.git(&["add", "becomes-link.txt"]);
+        let staged = get_staged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+
+        let script = staged.files.iter().find(|f| f.path == "script.sh").unwrap();
+        assert_eq!(script.old_mode.as_deref(), Some("100644"));
+        assert_eq!(script.new_mode.as_deref(), Some("100755"));
+        assert_eq!(script.file_type.as_deref(), Some("executable"));
+        assert_eq!(script.old_file_type.as_deref(), Some("regular"));
+
+        let link = staged
+            .files
+            .iter()
+            .find(|f| f.path == "becomes-link.txt")
+            .unwrap();
+        assert_eq!(link.old_file_type.as_deref(), Some("regular"));
+        assert_eq!(link.file_type.as_deref(), Some("symlink"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_untracked_symlinks_and_executables() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let repo = TestRepo::new("untracked-modes");
+        repo.write("target.txt", "pointed at\n");
+        repo.commit_all("initial");
+        repo.write("run.sh", "#!/bin/sh\n");
+        let script = repo.dir().join("run.sh");
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        std::os::unix::fs::symlink("target.txt", repo.dir().join("link")).unwrap();
+        std::os::unix::fs::symlink("missing", repo.dir().join("dangling")).unwrap();
+
+        let result = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        let by_path = |path: &str| result.files.iter().find(|f| f.path == path).unwrap();
+        assert_eq!(by_path("run.sh").file_type.as_deref(), Some("executable"));
+        assert_eq!(by_path("link").new_mode.as_deref(), Some("120000"));
+        assert_eq!(by_path("dangling").file_type.as_deref(), Some("symlink"));
+
+        let link = result
+            .file_diffs
+            .iter()
+            .find(|fd| fd.path() == "link")
+            .unwrap();
+        assert_eq!(link.hunks[0].lines[0].content, "target.txt");
+        assert!(link.hunks[0].lines[0].no_newline);
+        assert!(!result.diff.contains("+pointed at"));
+
+        repo.git(&["config", "core.fileMode", "false"]);
+        let result = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        let script = result.files.iter().find(|f| f.path == "run.sh").unwrap();
+        assert_eq!(script.new_mode.as_deref(), Some("100644"));
+    }
+
+    /// A repo with a "lib" submodule at the first of two lib commits.
+    fn repo_with_submodule(lib: &TestRepo) -> (TestRepo, String, String) {
+        lib.write("lib.txt", "one\n");
+        let first = lib.commit_all("lib one");
+        lib.write("lib.txt", "two\n");
+        let second = lib.commit_all("lib two");
+        lib.git(&["checkout", "-q", &first]);
+
+        let repo = TestRepo::new("super");
+        repo.write("a.txt", "one\n");
+        repo.commit_all("initial");
+        repo.git(&[
+            "-c",
+            "protocol.file.allow=always",
+            "submodule",
+            "add",
+            "-q",
+            lib.dir().to_str().unwrap(),
+            "lib",
+        ]);
+        repo.commit_all("add lib");
+        (repo, first, second)
+    }
+
+    #[test]
+    fn test_submodule_pointer_change() {
+        let lib = TestRepo::new("lib");
+        let (repo, first, second) = repo_with_submodule(&lib);
+        repo.git(&["-C", "lib", "checkout", "-q", &second]);
+
+        let result = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        assert_eq!(file_paths(&result), ["lib"]);
+        let file = &result.files[0];
+        assert_eq!(file.file_type.as_deref(), Some("submodule"));
+        assert_eq!(
+            file.submodule,
+            Some(SubmoduleChange {
+                old_commit: Some(first.clone()),
+                new_commit: Some(second.clone()),
+                dirty: false,
+            })
+        );
+
+        repo.write("lib/lib.txt", "uncommitted\n");
+        let result = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        assert!(result.files[0].submodule.as_ref().unwrap().dirty);
+
+        let ignored = DiffOptions {
+            ignore_submodules: true,
+            ..Default::default()
+        };
+        assert!(get_unstaged_diff(repo.dir(), &ignored)
+            .unwrap()
+            .files
+            .is_empty());
+
+        let expanded =
+            get_submodule_diff(repo.dir(), "lib", &first, &second, &DiffOptions::default())
+                .unwrap();
+        assert_eq!(expanded.commits.len(), 1);
+        assert_eq!(expanded.commits[0].message, "lib two");
+        assert_eq!(file_paths(&expanded.diff), ["lib.txt"]);
+        assert!(expanded.diff.diff.contains("+two"));
+    }
+
+    #[test]
+    fn test_untracked_nested_repo_is_a_gitlink() {
+        let repo = TestRepo::new("nested-outer");
+        repo.write("a.txt", "one\n");
+        repo.commit_all("initial");
+        repo.git(&["init", "-q", "nested"]);
+        repo.write("nested/inner.txt", "inner\n");
+        repo.git(&["-C", "nested", "add", "-A"]);
+        repo.git(&[
+            "-C",
+            "nested",
+            "-c",
+            "user.name=Test User",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "-q",
+            "-m",
+            "inner",
+        ]);
+        let head = repo.git(&["-C", "nested", "rev-parse", "HEAD"]);
+
+        let result = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        assert_eq!(file_paths(&result), ["nested"]);
+        assert_eq!(result.files[0].new_mode.as_deref(), Some("160000"));
+        assert_eq!(
+            result.files[0].submodule.as_ref().unwrap().new_commit,
+            Some(head)
+        );
+        assert!(!result.diff.contains("inner"));
+
+        let ignored = DiffOptions {
+            ignore_submodules: true,
+            ..Default::default()
+        };
+        let result = get_unstaged_diff(repo.dir(), &ignored).unwrap();
+        assert!(result.files.is_empty());
+        assert!(result.diff.is_empty());
+    }
+
+    #[test]
+    fn test_parse_status_v2_records() {
+        let hash = "0".repeat(40);
+        let output = format!(
+            concat!(
+                "1 .M N... 100644 100644 100644 {h} {h}  leading space.txt\0",
+                "2 R. N... 100644 100644 100644 {h} {h} R100 new\nline.txt\0old name.txt\0",
+                "u UU N... 100644 100644 100644 100644 {h} {h} {h} both.txt\0",
+                "? nested/\0",
+                "! ignored.log\0",
+            ),
+            h = hash
+        );
+        let entries = parse_status_v2(&output);
+
+        assert_eq!(
+            entries,
+            vec![
+                StatusEntry {
+                    index: ' ',
+                    worktree: 'M',
+                    path: " leading space.txt".to_string(),
+                    orig_path: None,
+                },
+                StatusEntry {
+                    index: 'R',
+                    worktree: ' ',
+                    path: "new\nline.txt".to_string(),
+                    orig_path: Some("old name.txt".to_string()),
+                },
+                StatusEntry {
+                    index: 'U',
+                    worktree: 'U',
+                    path: "both.txt".to_string(),
+                    orig_path: None,
+                },
+                StatusEntry {
+                    index: '?',
+                    worktree: '?',
+                    path: "nested".to_string(),
+                    orig_path: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_stack_log_keeps_paragraphs_and_pipes() {
+        let output = concat!(
+            "aaa\x1fa1\x1fFix a | b\x1fFirst paragraph.\n\nGG-ID: one\n\0",
+            "bbb\x1fb2\x1fSecond\x1f\0",
+        );
+        let entries = parse_stack_log(output);
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].title, "Fix a | b");
+        assert_eq!(entries[0].gg_id.as_deref(), Some("one"));
+        assert_eq!(entries[1].hash, "bbb");
+        assert_eq!(entries[1].position, 1);
+        assert_eq!(entries[1].gg_id, None);
+    }
+
+    /// File names git would quote or that break line- and space-based parsing.
+    #[cfg(unix)]
+    const ADVERSARIAL_NAMES: [&str; 7] = [
+        "with space.txt",
+        "quote\"d.txt",
+        "new\nline.txt",
+        "tab\there.txt",
+        "ünïcödé.txt",
+        "a -> b.txt",
+        " leading.txt",
+    ];
+
+    #[cfg(unix)]
+    #[test]
+    fn test_adversarial_paths_round_trip() {
+        let repo = TestRepo::new("adversarial-paths");
+        for name in ADVERSARIAL_NAMES {
+            repo.write(name, "one\n");
+        }
+        let first = repo.commit_all("initial");
+        for name in ADVERSARIAL_NAMES {
+            repo.write(name, "one\ntwo\n");
+        }
+        repo.write("untracked \"new\"\n.txt", "new\n");
+
+        let mut expected: Vec<&str> = ADVERSARIAL_NAMES.to_vec();
+        expected.push("untracked \"new\"\n.txt");
+        expected.sort();
+
+        let unstaged = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        let mut paths = file_paths(&unstaged);
+        paths.sort();
+        assert_eq!(paths, expected);
+        // Stats only fill in when the parsed diff paths match the file list.
+        assert!(unstaged.files.iter().all(|f| f.additions > 0));
+
+        let status = get_git_change_status(repo.dir()).unwrap();
+        assert!(status.has_unstaged && !status.has_staged);
+
+        repo.git(&["add", "-A"]);
+        repo.git(&["mv", "new\nline.txt", "moved\tto here.txt"]);
+        let staged = get_staged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        let moved = staged
+            .files
+            .iter()
+            .find(|f| f.path == "moved\tto here.txt")
+            .unwrap();
+        assert_eq!(moved.status, "renamed");
+        assert_eq!(moved.old_path.as_deref(), Some("new\nline.txt"));
+
+        let second = repo.commit_all("second");
+        let commit = get_commit_diff(repo.dir(), &second, &DiffOptions::default()).unwrap();
+        assert!(file_paths(&commit).contains(&"moved\tto here.txt"));
+        let range = get_range_diff(
+            repo.dir(),
+            &format!("{}..{}", first, second),
+            &DiffOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(range.files.len(), commit.files.len());
+        assert!(list_files_at_ref(repo.dir(), &second)
+            .unwrap()
+            .contains(&"quote\"d.txt".to_string()));
+    }
+
+    #[test]
+    fn test_adversarial_commit_messages() {
+        let repo = TestRepo::new("adversarial-messages");
+        repo.write("a.txt", "one\n");
+        let message = "Subject | with pipe\n\nFirst paragraph | x\n\nSecond paragraph\n\nSigned-off-by: A <a@example.com>";
+        repo.git(&["add", "-A"]);
+        repo.git(&["commit", "-q", "-m", message]);
+        repo.write("a.txt", "two\n");
+        repo.commit_all("plain");
+
+        let commits = list_commits(repo.dir(), &LogOptions::default()).unwrap();
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[1].message, "Subject | with pipe");
+        assert!(commits[1]
+            .body
+            .contains("First paragraph | x\n\nSecond paragraph"));
+        assert_eq!(commits[1].trailers[0].key, "Signed-off-by");
+        assert_eq!(commits[0].message, "plain");
+
+        repo.git(&[
+            "checkout",
+            "-q",
+            "-b",
+            "feature branch | x".replace(' ', "-").as_str(),
+        ]);
+        let branches = list_branches(repo.dir()).unwrap();
+        assert!(branches.iter().any(|b| b.name == "feature-branch-|-x"));
+    }
+
+    #[test]
+    fn test_untracked_size_limits() {
+        let repo = TestRepo::new("untracked-limits");
+        repo.write("a.txt", "one\n");
+        repo.commit_all("initial");
+        repo.write("big.log", &"x".repeat(4096));
+        repo.write("small1.txt", &"y".repeat(100));
+        repo.write("small2.txt", &"z".repeat(100));
+
+        let opts = DiffOptions {
+            untracked_file_limit: Some(1024),
+            untracked_total_limit: Some(150),
+            ..Default::default()
+        };
+        let result = get_unstaged_diff(repo.dir(), &opts).unwrap();
+        let by_path = |path: &str| result.files.iter().find(|f| f.path == path).unwrap();
+
+        assert_eq!(by_path("big.log").too_large, Some(4096));
+        assert!(result
+            .diff
+            .contains("File big.log added, too large to show (4096 bytes)"));
+        assert!(!result.diff.contains("xxxx"));
+        // The first small file uses up the budget; the second is left out.
+        assert_eq!(by_path("small1.txt").too_large, None);
+        assert_eq!(by_path("small1.txt").additions, 1);
+        assert_eq!(by_path("small2.txt").too_large, Some(100));
+
+        let unlimited = DiffOptions {
+            untracked_file_limit: None,
+            untracked_total_limit: None,
+            ..Default::default()
+        };
+        let result = get_unstaged_diff(repo.dir(), &unlimited).unwrap();
+        assert!(result.files.iter().all(|f| f.too_large.is_none()));
+        assert!(result.diff.contains("xxxx"));
+    }
+
+    #[test]
+    fn test_untracked_binary_sniffing() {
+        assert!(looks_binary(b"PK\x03\x04\x00\x00"));
+        assert!(looks_binary(&[0x01, 0x02, 0x03, 0x04, b'a']));
+        assert!(!looks_binary("caf\u{e9}\n".as_bytes()));
+        assert!(!looks_binary(b"caf\xe9 latin-1\n"));
+        assert!(!looks_binary(b"\x1b[31mred\x1b[0m\r\n\ttab\n"));
+
+        let repo = TestRepo::new("untracked-binary");
+        repo.write("a.txt", "one\n");
+        repo.commit_all("initial");
+        repo.write_bytes("latin1.txt", b"caf\xe9\n");
+        repo.write_bytes("blob.bin", b"abc\x00def");
+
+        let result = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        let by_path = |path: &str| result.files.iter().find(|f| f.path == path).unwrap();
+        assert!(by_path("blob.bin").is_binary);
+        assert!(!by_path("latin1.txt").is_binary);
+        assert_eq!(by_path("latin1.txt").additions, 1);
+    }
+
+    #[test]
+    fn test_intent_to_add_is_added_once() {
+        let repo = TestRepo::new("intent-to-add");
+        repo.write("a.txt", "one\n");
+        repo.commit_all("initial");
+        repo.write("planned.txt", "new\n");
+        repo.git(&["add", "-N", "planned.txt"]);
+
+        let result = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        assert_eq!(file_paths(&result), ["planned.txt"]);
+        assert_eq!(result.files[0].status, "added");
+        assert_eq!(result.file_diffs.len(), 1);
+        assert_eq!(result.diff.matches("diff --git").count(), 1);
+
+        let staged = get_staged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        assert!(staged.files.is_empty());
+        assert!(get_git_change_status(repo.dir()).unwrap().has_unstaged);
+    }
+
+    #[test]
+    fn test_diff_reports_encoding_and_line_endings() {
+        let repo = TestRepo::new("encodings");
+        let utf16 =
+            |text: &str| -> Vec<u8> { text.encode_utf16().flat_map(u16::to_le_bytes).collect() };
+        repo.write(
+            ".gitattributes",
+            "declared.txt working-tree-encoding=UTF-16LE\n",
+        );
+        repo.write_bytes("latin.txt", b"caf\xe9\n");
+        repo.write_bytes("crlf.txt", b"a\r\nb\r\n");
+        repo.write_bytes("declared.txt", &utf16("one\ntwo\n"));
+        repo.commit_all("initial");
+
+        repo.write_bytes("latin.txt", b"caf\xe9 cr\xe8me br\xfbl\xe9e\n");
+        repo.write_bytes("crlf.txt", b"a\r\nb\r\nc\r\n");
+        repo.write_bytes("declared.txt", &utf16("one\ntwo\nthree\n"));
+        repo.write_bytes("mixed.txt", b"a\r\nb\n");
+        let mut bom = vec![0xff, 0xfe];
+        bom.extend(utf16("hi\n"));
+        repo.write_bytes("bom.txt", &bom);
+
+        let result = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        let info = |path: &str| {
+            let file = result.files.iter().find(|f| f.path == path).unwrap();
+            (
+                file.encoding.as_deref(),
+                file.line_endings.as_deref(),
+                file.is_binary,
+            )
+        };
+        assert_eq!(info("latin.txt"), (Some("windows-1252"), Some("lf"), false));
+        assert_eq!(info("crlf.txt"), (Some("UTF-8"), Some("crlf"), false));
+        assert_eq!(info("declared.txt"), (Some("UTF-16LE"), Some("lf"), false));
+        assert_eq!(info("mixed.txt"), (Some("UTF-8"), Some("mixed"), false));
+        assert_eq!(info("bom.txt"), (Some("UTF-16LE"), Some("lf"), false));
+        assert!(result
+            .diff
+            .contains("+caf\u{e9} cr\u{e8}me br\u{fb}l\u{e9}e\n"));
+        assert!(result.diff.contains("+three\n"));
+        assert!(result.diff.contains("+hi\n"));
+
+        assert_eq!(
+            get_file_at_ref(repo.dir(), "HEAD", "latin.txt").unwrap(),
+            "caf\u{e9}\n"
+        );
+        assert_eq!(
+            read_worktree_file(repo.dir(), "declared.txt").unwrap(),
+            "one\ntwo\nthree\n"
+        );
+    }
+
+    fn numbered_lines(lines: impl IntoIterator<Item = u32>) -> String {
+        lines.into_iter().map(|n| format!("line {}\n", n)).collect()
+    }
+
+    fn staged_content(repo: &TestRepo, path: &str) -> String {
+        repo.git(&["show", &format!(":{}", path)]) + "\n"
+    }
+
+    fn whole_hunk(file_path: &str, hunk: &crate::diff::DiffHunk) -> PatchSelection {
+        let range = |start: u32, count: u32| LineRange {
+            start,
+            end: start + count.max(1) - 1,
+        };
+        PatchSelection {
+            file_path: file_path.to_string(),
+            old_lines: vec![range(hunk.old_start, hunk.old_lines)],
+            new_lines: vec![range(hunk.new_start, hunk.new_lines)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_stage_and_unstage_hunks_and_lines() {
+        let repo = TestRepo::new("partial-stage");
+        repo.write("f.txt", &numbered_lines(1..=20));
+        repo.commit_all("initial");
+        let edited = numbered_lines(1..=20)
+            .replace("line 2\n", "line two\nline 2.5\n")
+            .replace("line 15\n", "line fifteen\n");
+        repo.write("f.txt", &edited);
+
+        // The second hunk only.
+        let unstaged = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        let hunks = &unstaged.file_diffs[0].hunks;
+        assert_eq!(hunks.len(), 2);
+        stage_selection(repo.dir(), &[whole_hunk("f.txt", &hunks[1])]).unwrap();
+        assert_eq!(
+            staged_content(&repo, "f.txt"),
+            numbered_lines(1..=20).replace("line 15\n", "line fifteen\n")
+        );
+
+        // Just the added "line 2.5" from the first hunk; "line 2" stays.
+        let selection = PatchSelection {
+            file_path: "f.txt".to_string(),
+            new_lines: vec![LineRange { start: 3, end: 3 }],
+            ..Default::default()
+        };
+        stage_selection(repo.dir(), &[selection]).unwrap();
+        let index = staged_content(&repo, "f.txt");
+        assert!(index.starts_with("line 1\nline 2\nline 2.5\nline 3\n"));
+
+        // Take the fifteenth line's change back out of the index.
+        let selection = PatchSelection {
+            file_path: "f.txt".to_string(),
+            old_lines: vec![LineRange { start: 15, end: 15 }],
+            new_lines: vec![LineRange { start: 16, end: 16 }],
+            ..Default::default()
+        };
+        unstage_selection(repo.dir(), &[selection]).unwrap();
+        assert_eq!(
+            staged_content(&repo, "f.txt"),
+            numbered_lines(1..=20).replace("line 2\n", "line 2\nline 2.5\n")
+        );
+        assert_eq!(
+            fs::read_to_string(repo.dir().join("f.txt")).unwrap(),
+            edited
+        );
+
+        // Paths are taken literally, never as globs: "*.rs" isn't "a.rs",
+        // and the untracked "a[1].txt" isn't the tracked "a1.txt".
+        repo.write("*.rs", "star\n");
+        repo.write("a.rs", "a\n");
+        repo.write("a1.txt", "one\n");
+        repo.commit_all("globs");
+        repo.write("*.rs", "star edited\n");
+        repo.write("a.rs", "a edited\n");
+        repo.write("a1.txt", "one edited\n");
+        repo.write("a[1].txt", "bracket\n");
+        for path in ["*.rs", "a[1].txt"] {
+            let selection = PatchSelection {
+                file_path: path.to_string(),
+                old_lines: vec![LineRange { start: 1, end: 1 }],
+                new_lines: vec![LineRange { start: 1, end: 1 }],
+                ..Default::default()
+            };
+            stage_selection(repo.dir(), &[selection]).unwrap();
+        }
+        assert_eq!(staged_content(&repo, "*.rs"), "star edited\n");
+        assert_eq!(staged_content(&repo, "a[1].txt"), "bracket\n");
+        assert_eq!(staged_content(&repo, "a.rs"), "a\n");
+        assert_eq!(staged_content(&repo, "a1.txt"), "one\n");
+    }
+
+    #[test]
+    fn test_stage_part_of_new_file() {
+        let repo = TestRepo::new("partial-new");
+        repo.write("base.txt", "base\n");
+        repo.commit_all("initial");
+        repo.write("new.txt", &numbered_lines(1..=3));
+
+        let selection = PatchSelection {
+            file_path: "new.txt".to_string(),
+            new_lines: vec![
+                LineRange { start: 1, end: 1 },
+                LineRange { start: 3, end: 3 },
+            ],
+            ..Default::default()
+        };
+        stage_selection(repo.dir(), &[selection]).unwrap();
+        assert_eq!(staged_content(&repo, "new.txt"), "line 1\nline 3\n");
+
+        // Unstaging part of a file HEAD doesn't have leaves the rest staged.
+        let selection = PatchSelection {
+            file_path: "new.txt".to_string(),
+            new_lines: vec![LineRange { start: 1, end: 1 }],
+            ..Default::default()
+        };
+        unstage_selection(repo.dir(), &[selection]).unwrap();
+        assert_eq!(staged_content(&repo, "new.txt"), "line 3\n");
+    }
+
+    #[test]
+    fn test_stage_selection_ignores_textconv() {
+        let repo = TestRepo::new("partial-textconv");
+        repo.write(".gitattributes", "*.up diff=upper\n");
+        repo.git(&["config", "diff.upper.textconv", "tr a-z A-Z <"]);
+        repo.write("f.up", "a\nb\n");
+        repo.commit_all("initial");
+        repo.write("f.up", "a\nb\nc\n");
+
+        let selection = PatchSelection {
+            file_path: "f.up".to_string(),
+            new_lines: vec![LineRange { start: 3, end: 3 }],
+            ..Default::default()
+        };
+        stage_selection(repo.dir(), &[selection]).unwrap();
+        assert_eq!(staged_content(&repo, "f.up"), "a\nb\nc\n");
+
+        // Only a patch that no longer fits is a conflict.
+        let err = apply_patch(
+            repo.dir(),
+            b"not a patch\n".to_vec(),
+            &["--cached"],
+            "the index",
+        )
+        .unwrap_err();
+        assert!(!err.starts_with("Conflict:"), "{}", err);
+    }
+
+    #[test]
+    fn test_stage_selection_detects_changed_file() {
+        let repo = TestRepo::new("partial-conflict");
+        repo.write("f.txt", &numbered_lines(1..=5));
+        repo.commit_all("initial");
+        repo.write("f.txt", &numbered_lines(1..=6));
+
+        let unstaged = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        let file_diff = &unstaged.file_diffs[0];
+        let selection = PatchSelection {
+            old_blob: file_diff.old_blob.clone(),
+            new_blob: file_diff.new_blob.clone(),
+            ..whole_hunk("f.txt", &file_diff.hunks[0])
+        };
+
+        repo.write("f.txt", &numbered_lines(1..=7));
+        let err = stage_selection(repo.dir(), std::slice::from_ref(&selection)).unwrap_err();
+        assert!(err.starts_with("Conflict:"), "{}", err);
+
+        repo.git(&["checkout", "--", "f.txt"]);
+        let err = stage_selection(repo.dir(), &[selection]).unwrap_err();
+        assert!(err.starts_with("Conflict:"), "{}", err);
+        assert_eq!(staged_content(&repo, "f.txt"), numbered_lines(1..=5));
+    }
+
+    #[test]
+    fn test_discard_hunk_and_undo() {
+        let repo = TestRepo::new("discard-hunk");
+        repo.write("f.txt", &numbered_lines(1..=20));
+        repo.commit_all("initial");
+        let edited = numbered_lines(1..=20)
+            .replace("line 2\n", "line two\n")
+            .replace("line 15\n", "line fifteen\n");
+        repo.write("f.txt", &edited);
+        let read = || fs::read_to_string(repo.dir().join("f.txt")).unwrap();
+
+        let unstaged = get_unstaged_diff(repo.dir(), &DiffOptions::default()).unwrap();
+        let backup = discard_selection(
+            repo.dir(),
+            &[whole_hunk("f.txt", &unstaged.file_diffs[0].hunks[0])],
+        )
+        .unwrap();
+        assert_eq!(backup.paths, vec!["f.txt"]);
+        assert_eq!(
+            read(),
+            numbered_lines(1..=20).replace("line 15\n", "line fifteen\n")
+        );
+
+        // An edit made after the discard survives the undo.
+        repo.write("f.txt", &read().replace("line 19\n", "line nineteen\n"));
+        let undone = undo_last_discard(repo.dir()).unwrap().unwrap();
+        assert_eq!(undone.commit, backup.commit);
+        assert_eq!(read(), edited.replace("line 19\n", "line nineteen\n"));
+        assert!(get_last_discard(repo.dir()).unwrap().is_none());
+        assert!(undo_last_discard(repo.dir()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_discard_files_and_undo() {
+        let repo = TestRepo::new("discard-files");
+        repo.write("tracked.txt", "one\n");
+        repo.commit_all("initial");
+        repo.write("tracked.txt", "two\n");
+        repo.write("scratch.txt", "notes\n");
+
+        discard_files(repo.dir(), &["tracked.txt".to_string()]).unwrap();
+        discard_files(repo.dir(), &["scratch.txt".to_string()]).unwrap();
+        assert_eq!(repo.git(&["status", "--porcelain"]), "");
+
+        // Undo pops one discard at a time, newest first.
+        let undone = undo_last_discard(repo.dir()).unwrap().unwrap();
+        assert_eq!(undone.paths, vec!["scratch.txt"]);
+        assert_eq!(
+            fs::read_to_string(repo.dir().join("scratch.txt")).unwrap(),
+            "notes\n"
+        );
+        assert_eq!(
+            get_last_discard(repo.dir()).unwrap().unwrap().paths,
+            vec!["tracked.txt"]
+        );
+        undo_last_discard(repo.dir()).unwrap().unwrap();
+        assert_eq!(
+            fs::read_to_string(repo.dir().join("tracked.txt")).unwrap(),
+            "two\n"
+        );
+        assert!(get_last_discard(repo.dir()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_discard_failing_midway_can_be_undone() {
+        let repo = TestRepo::new("discard-midway");
+        repo.write("tracked.txt", "one\n");
+        repo.write("[odd]*.txt", "one\n");
+        repo.commit_all("initial");
+        repo.write("tracked.txt", "two\n");
+        repo.write("[odd]*.txt", "two\n");
+        repo.write("scratch.txt", "notes\n");
+
+        // Tracked files are reverted, then deleting the untracked one fails.
+        let paths = ["tracked.txt", "[odd]*.txt", "scratch.txt"].map(String::from);
+        let err = with_discard_backup(repo.dir(), &paths, || {
+            repo.git(&[
+                "checkout-index",
+                "--force",
+                "--",
+                "tracked.txt",
+                "[odd]*.txt",
+            ]);
+            Err("Failed to delete scratch.txt: Permission denied".to_string())
+        })
+        .unwrap_err();
+        assert!(err.contains(DISCARD_REF), "{}", err);
+        assert_eq!(repo.git(&["status", "--porcelain"]), "?? scratch.txt");
+
+        let backup = get_last_discard(repo.dir()).unwrap().unwrap();
+        assert_eq!(backup.paths, paths);
+        undo_last_discard(repo.dir()).unwrap().unwrap();
+        let read = |path: &str| fs::read_to_string(repo.dir().join(path)).unwrap();
+        assert_eq!(read("tracked.txt"), "two\n");
+        assert_eq!(read("[odd]*.txt"), "two\n");
+        assert_eq!(read("scratch.txt"), "notes\n");
+        // The stand-in entry written before the discard is gone too.
+        assert!(get_last_discard(repo.dir()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_undo_discard_refuses_overlapping_edit() {
+        let repo = TestRepo::new("discard-conflict");
+        repo.write("f.txt", "a\nb\nc\n");
+        repo.commit_all("initial");
+        repo.write("f.txt", "a\nB\nc\n");
+
+        discard_files(repo.dir(), &["f.txt".to_string()]).unwrap();
+        repo.write("f.txt", "a\nbee\nc\n");
+
+        let err = undo_last_discard(repo.dir()).unwrap_err();
+        assert!(err.starts_with("Conflict:"), "{}", err);
+        assert_eq!(
+            fs::read_to_string(repo.dir().join("f.txt")).unwrap(),
+            "a\nbee\nc\n"
+        );
+        assert!(get_last_discard(repo.dir()).unwrap().is_some());
+    }
+
+    fn committed(outcome: CommitOutcome) -> CommitInfo {
+        match outcome {
+            CommitOutcome::Committed { commit } => *commit,
+            other => panic!("expected a commit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_create_commit_refuses_changes_made_after_the_review() {
+        let repo = TestRepo::new("commit-session");
+        repo.write("a.txt", "a\n");
+        repo.commit_all("initial");
+        repo.write("a.txt", "reviewed\n");
+        repo.write("new.txt", "new\n");
+        let head = repo.git(&["rev-parse", "HEAD"]);
+
+        let session = open_review_session(repo.dir(), &DiffOptions::default()).unwrap();
+        repo.write("new.txt", "edited after the review\n");
+        let opts = CommitOptions {
+            message: "Approved".to_string(),
+            files: vec!["a.txt".to_string(), "new.txt".to_string()],
+            session: Some(session.id.clone()),
+            ..Default::default()
+        };
+        let err = create_commit(repo.dir(), &opts).unwrap_err();
+        assert!(err.contains("new.txt"), "{}", err);
+        assert!(!err.contains("a.txt"), "{}", err);
+        assert_eq!(repo.git(&["rev-parse", "HEAD"]), head);
+
+        // Staged mode checks the index, not the worktree.
+        repo.git(&["add", "a.txt"]);
+        let session = open_review_session(repo.dir(), &DiffOptions::default()).unwrap();
+        let opts = CommitOptions {
+            message: "Approved".to_string(),
+            session: Some(session.id.clone()),
+            ..Default::default()
+        };
+        repo.git(&["add", "new.txt"]);
+        let err = create_commit(repo.dir(), &opts).unwrap_err();
+        assert!(err.contains("new.txt"), "{}", err);
+        assert_eq!(repo.git(&["rev-parse", "HEAD"]), head);
+
+        repo.git(&["rm", "--cached", "--quiet", "new.txt"]);
+        repo.write("a.txt", "unstaged edits don't count\n");
+        let commit = committed(create_commit(repo.dir(), &opts).unwrap());
+        assert_eq!(commit.message, "Approved");
+        assert_eq!(repo.git(&["show", "HEAD:a.txt"]), "reviewed");
+    }
+
+    #[test]
+    fn test_create_commit_from_index_and_from_files() {
+        let repo = TestRepo::new("create-commit");
+        repo.write("a.txt", "a\n");
+        repo.write("b.txt", "b\n");
+        repo.commit_all("initial");
+        repo.write("a.txt", "a2\n");
+        repo.write("b.txt", "b2\n");
+        repo.git(&["add", "a.txt"]);
+
+        let opts = CommitOptions {
+            message: "Update a".to_string(),
+            reviewed_by: Some("Rita Reviewer <rita@example.com>".to_string()),
+            ..Default::default()
+        };
+        let commit = committed(create_commit(repo.dir(), &opts).unwrap());
+        assert_eq!(commit.message, "Update a");
+        assert_eq!(commit.trailers.len(), 1);
+        assert_eq!(commit.trailers[0].key, "Reviewed-by");
+        assert_eq!(commit.trailers[0].value, "Rita Reviewer <rita@example.com>");
+        assert_eq!(repo.git(&["status", "--porcelain"]), "M b.txt");
+
+        // Chosen files, untracked ones included; what's staged stays staged.
+        repo.write("c.txt", "c\n");
+        repo.write("new.txt", "new\n");
+        repo.git(&["add", "c.txt"]);
+        let opts = CommitOptions {
+            message: "Update b".to_string(),
+            files: vec!["b.txt".to_string(), "new.txt".to_string()],
+            ..Default::default()
+        };
+        let commit = committed(create_commit(repo.dir(), &opts).unwrap());
+        assert_eq!(
+            repo.git(&["show", "--format=", "--name-only", &commit.hash]),
+            "b.txt\nnew.txt"
+        );
+        assert_eq!(repo.git(&["status", "--porcelain"]), "A  c.txt");
+
+        // Amending with no message keeps the old one.
+        let opts = CommitOptions {
+            amend: true,
+            reviewed_by: Some("Rita Reviewer <rita@example.com>".to_string()),
+            ..Default::default()
+        };
+        let amended = committed(create_commit(repo.dir(), &opts).unwrap());
+        assert_eq!(amended.message, "Update b");
+        assert_eq!(amended.parents, commit.parents);
+        assert_eq!(amended.trailers.len(), 1);
+
+        let err = create_commit(repo.dir(), &CommitOptions::default()).unwrap_err();
+        assert_eq!(err, "Commit message is empty");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_commit_reports_hook_failures() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let repo = TestRepo::new("commit-hooks");
+        repo.write("a.txt", "a\n");
+        repo.commit_all("initial");
+        let head = repo.git(&["rev-parse", "HEAD"]);
+        let hook = |name: &str, script: &str| {
+            let path = repo.dir().join(".git/hooks").join(name);
+            fs::write(&path, script).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        hook(
+            "pre-commit",
+            "#!/bin/sh\ngit diff --cached --name-only | grep -q bad && { echo \"lint failed\"; exit 3; }\nexit 0\n",
+        );
+        hook(
+            "commit-msg",
+            "#!/bin/sh\ngrep -q TICKET- \"$1\" || { echo \"missing ticket\" >&2; exit 1; }\n",
+        );
+
+        // pre-commit sees the chosen files, not what happens to be staged,
+        // and turning them down leaves the index as it was.
+        repo.write("bad.txt", "x\n");
+        let status = repo.git(&["status", "--porcelain"]);
+        let opts = CommitOptions {
+            message: "TICKET-1 add".to_string(),
+            files: vec!["bad.txt".to_string()],
+            ..Default::default()
+        };
+        match create_commit(repo.dir(), &opts).unwrap() {
+            CommitOutcome::HookFailed {
+                hook,
+                exit_code,
+                output,
+            } => {
+                assert_eq!(hook, "pre-commit");
+                assert_eq!(exit_code, Some(3));
+                assert_eq!(output, "lint failed\n");
+            }
+            other => panic!("expected a hook failure, got {:?}", other),
+        }
+        assert_eq!(repo.git(&["status", "--porcelain"]), status);
+
+        repo.write("a.txt", "a2\n");
+        repo.git(&["add", "a.txt"]);
+        let opts = CommitOptions {
+            message: "no ticket".to_string(),
+            ..Default::default()
+        };
+        match create_commit(repo.dir(), &opts).unwrap() {
+            CommitOutcome::HookFailed { hook, output, .. } => {
+                assert_eq!(hook, "commit-msg");
+                assert_eq!(output, "missing ticket\n");
+            }
+            other => panic!("expected a hook failure, got {:?}", other),
+        }
+        assert_eq!(repo.git(&["rev-parse", "HEAD"]), head);
+
+        let opts = CommitOptions {
+            message: "TICKET-2 update a".to_string(),
+            ..Default::default()
+        };
+        committed(create_commit(repo.dir(), &opts).unwrap());
+
+        // prepare-commit-msg runs before commit-msg, and every hook just once.
+        hook(
+            "prepare-commit-msg",
+            "#!/bin/sh\n[ \"$2\" = message ] && printf '\\nTICKET-3\\n' >> \"$1\"\nexit 0\n",
+        );
+        hook("post-commit", "#!/bin/sh\necho commit >> .git/hook-log\n");
+        hook("post-rewrite", "#!/bin/sh\necho \"$1\" >> .git/hook-log\n");
+        repo.write("a.txt", "a3\n");
+        repo.git(&["add", "a.txt"]);
+        let opts = CommitOptions {
+            message: "prepared".to_string(),
+            ..Default::default()
+        };
+        committed(create_commit(repo.dir(), &opts).unwrap());
+        let message = repo.git(&["log", "-1", "--format=%B"]);
+        assert_eq!(message.matches("TICKET-3").count(), 1, "{}", message);
+
+        let opts = CommitOptions {
+            amend: true,
+            ..Default::default()
+        };
+        committed(create_commit(repo.dir(), &opts).unwrap());
+        assert_eq!(
+            fs::read_to_string(repo.dir().join(".git/hook-log")).unwrap(),
+            "commit\ncommit\namend\n"
+        );
+    }
 }
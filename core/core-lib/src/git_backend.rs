@@ -0,0 +1,677 @@
+//! Read-only repository lookups used by `git.rs`, behind a swappable
+//! backend: finding the repository, resolving refs, walking commit parents,
+//! reading blobs and trees, reading config and hashing blobs.
+//!
+//! Everything else still goes through the git CLI in `git.rs`: diff and log
+//! text, so the output matches what users see in a terminal, and everything
+//! that writes to the repository.
+
+use git2::{ObjectType, Repository, Sort, TreeWalkMode, TreeWalkResult};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+pub trait GitBackend {
+    /// Top-level directory of the working tree containing `dir`, with
+    /// symlinks resolved.
+    fn toplevel(&self, dir: &Path) -> Option<PathBuf>;
+
+    /// The repository's git directory; for a linked worktree, its private one
+    /// (where MERGE_HEAD, rebase-merge/ and friends live). Symlinks resolved.
+    fn git_dir(&self, dir: &Path) -> Option<PathBuf>;
+
+    /// Resolve a revision to a full commit/object hash.
+    fn rev_parse(&self, dir: &Path, rev: &str) -> Result<String, String>;
+
+    /// Whether a fully qualified ref (e.g. `refs/heads/main`) exists.
+    fn ref_exists(&self, dir: &Path, refname: &str) -> bool;
+
+    /// Target of a symbolic ref such as `refs/remotes/origin/HEAD`, fully
+    /// qualified (e.g. `refs/remotes/origin/main`).
+    fn symbolic_target(&self, dir: &Path, refname: &str) -> Option<String>;
+
+    /// Short name of the checked-out branch, or "HEAD" when detached.
+    fn current_branch(&self, dir: &Path) -> Result<String, String>;
+
+    /// Short names of all local branches, sorted.
+    fn local_branches(&self, dir: &Path) -> Result<Vec<String>, String>;
+
+    /// Best common ancestor of two revisions.
+    fn merge_base(&self, dir: &Path, rev1: &str, rev2: &str) -> Result<String, String>;
+
+    /// Number of commits reachable from `head` but not from `base`.
+    fn count_commits(&self, dir: &Path, base: &str, head: &str) -> Result<usize, String>;
+
+    /// Commits `head` has that `base` lacks, and the reverse, counted in one walk.
+    fn ahead_behind(&self, dir: &Path, base: &str, head: &str) -> Result<(usize, usize), String>;
+
+    /// Full hashes of a commit's parents, in order.
+    fn commit_parents(&self, dir: &Path, rev: &str) -> Result<Vec<String>, String>;
+
+    /// Raw blob content of `path` at `rev`. A rev of `:0` (or any `:<stage>`)
+    /// reads from the index.
+    fn read_blob(&self, dir: &Path, rev: &str, path: &str) -> Result<Vec<u8>, String>;
+
+    /// Every file path in the tree at `rev`, recursively.
+    fn list_tree(&self, dir: &Path, rev: &str) -> Result<Vec<String>, String>;
+
+    /// Value of a git config key, if set.
+    fn config_value(&self, dir: &Path, key: &str) -> Option<String>;
+
+    /// Committer timestamp of HEAD, if there is one.
+    fn head_commit_time(&self, dir: &Path) -> Option<i64>;
+
+    /// The id `content` would have as a blob, without storing it.
+    fn hash_blob(&self, dir: &Path, content: &[u8]) -> Result<String, String>;
+}
+
+/// Both backends report paths the same way, whichever path `dir` was
+/// reached by.
+fn canonical(path: PathBuf) -> PathBuf {
+    path.canonicalize().unwrap_or(path)
+}
+
+/// The backend used by `git.rs`: libgit2 in-process, unless
+/// `AIR_GIT_BACKEND=cli` asks for the git CLI.
+pub fn default_backend() -> &'static dyn GitBackend {
+    match std::env::var("AIR_GIT_BACKEND").as_deref() {
+        Ok("cli") => &CliBackend,
+        _ => &Libgit2Backend,
+    }
+}
+
+// =============================================================================
+// git CLI
+// =============================================================================
+
+/// Backend that spawns the `git` executable for every operation.
+pub struct CliBackend;
+
+impl CliBackend {
+    fn run(dir: &Path, args: &[&str]) -> Result<Vec<u8>, String> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .map_err(|e| format!("Failed to execute git {}: {}", args[0], e))?;
+
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+
+        Ok(output.stdout)
+    }
+
+    fn run_string(dir: &Path, args: &[&str]) -> Result<String, String> {
+        Self::run(dir, args).map(|out| String::from_utf8_lossy(&out).trim().to_string())
+    }
+}
+
+impl GitBackend for CliBackend {
+    fn toplevel(&self, dir: &Path) -> Option<PathBuf> {
+        Self::run_string(dir, &["rev-parse", "--show-toplevel"])
+            .ok()
+            .map(|p| canonical(PathBuf::from(p)))
+    }
+
+    fn git_dir(&self, dir: &Path) -> Option<PathBuf> {
+        Self::run_string(dir, &["rev-parse", "--absolute-git-dir"])
+            .ok()
+            .map(|p| canonical(PathBuf::from(p)))
+    }
+
+    fn rev_parse(&self, dir: &Path, rev: &str) -> Result<String, String> {
+        Self::run_string(
+            dir,
+            &["rev-parse", "--verify", "--quiet", "--end-of-options", rev],
+        )
+        .map_err(|_| format!("Unknown revision: {}", rev))
+    }
+
+    fn ref_exists(&self, dir: &Path, refname: &str) -> bool {
+        Self::run(dir, &["show-ref", "--verify", "--quiet", refname]).is_ok()
+    }
+
+    fn symbolic_target(&self, dir: &Path, refname: &str) -> Option<String> {
+        Self::run_string(dir, &["symbolic-ref", "-q", refname])
+            .ok()
+            .filter(|t| !t.is_empty())
+    }
+
+    fn current_branch(&self, dir: &Path) -> Result<String, String> {
+        Self::run_string(dir, &["rev-parse", "--abbrev-ref", "HEAD"])
+    }
+
+    fn local_branches(&self, dir: &Path) -> Result<Vec<String>, String> {
+        let out = Self::run_string(
+            dir,
+            &["for-each-ref", "--format=%(refname:short)", "refs/heads/"],
+        )?;
+        Ok(out
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_string())
+            .collect())
+    }
+
+    fn merge_base(&self, dir: &Path, rev1: &str, rev2: &str) -> Result<String, String> {
+        Self::run_string(dir, &["merge-base", "--end-of-options", rev1, rev2])
+    }
+
+    fn count_commits(&self, dir: &Path, base: &str, head: &str) -> Result<usize, String> {
+        let out = Self::run_string(
+            dir,
+            &[
+                "rev-list",
+                "--count",
+                "--end-of-options",
+                &format!("{}..{}", base, head),
+            ],
+        )?;
+        out.parse::<usize>()
+            .map_err(|e| format!("Failed to parse commit count: {}", e))
+    }
+
+    fn ahead_behind(&self, dir: &Path, base: &str, head: &str) -> Result<(usize, usize), String> {
+        let out = Self::run_string(
+            dir,
+            &[
+                "rev-list",
+                "--left-right",
+                "--count",
+                "--end-of-options",
+                &format!("{}...{}", base, head),
+            ],
+        )?;
+        let counts: Vec<usize> = out
+            .split_whitespace()
+            .map(|n| n.parse::<usize>())
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("Failed to parse commit count: {}", e))?;
+        match counts[..] {
+            [behind, ahead] => Ok((ahead, behind)),
+            _ => Err(format!("Unexpected rev-list output: {}", out)),
+        }
+    }
+
+    fn commit_parents(&self, dir: &Path, rev: &str) -> Result<Vec<String>, String> {
+        let commit = format!("{}^{{commit}}", rev);
+        let out = Self::run_string(
+            dir,
+            &[
+                "rev-list",
+                "--parents",
+                "-n",
+                "1",
+                "--end-of-options",
+                &commit,
+            ],
+        )?;
+        Ok(out
+            .split_whitespace()
+            .skip(1)
+            .map(|p| p.to_string())
+            .collect())
+    }
+
+    fn read_blob(&self, dir: &Path, rev: &str, path: &str) -> Result<Vec<u8>, String> {
+        Self::run(
+            dir,
+            &["show", "--end-of-options", &format!("{}:{}", rev, path)],
+        )
+    }
+
+    fn list_tree(&self, dir: &Path, rev: &str) -> Result<Vec<String>, String> {
+        let out = Self::run(
+            dir,
+            &[
+                "ls-tree",
+                "-r",
+                "-z",
+                "--name-only",
+                "--end-of-options",
+                rev,
+            ],
+        )?;
+        Ok(out
+            .split(|b| *b == 0)
+            .filter(|p| !p.is_empty())
+            .map(|p| String::from_utf8_lossy(p).to_string())
+            .collect())
+    }
+
+    fn config_value(&self, dir: &Path, key: &str) -> Option<String> {
+        Self::run_string(dir, &["config", key]).ok()
+    }
+
+    fn head_commit_time(&self, dir: &Path) -> Option<i64> {
+        Self::run_string(dir, &["log", "-1", "--format=%ct"])
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    fn hash_blob(&self, dir: &Path, content: &[u8]) -> Result<String, String> {
+        let mut child = Command::new("git")
+            .args(["hash-object", "--stdin"])
+            .current_dir(dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to execute git hash-object: {}", e))?;
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(content)
+            .map_err(|e| format!("Failed to execute git hash-object: {}", e))?;
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("Failed to execute git hash-object: {}", e))?;
+
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+// =============================================================================
+// libgit2
+// =============================================================================
+
+/// In-process backend built on libgit2. Opens the repository per call, which
+/// is still far cheaper than spawning a process.
+pub struct Libgit2Backend;
+
+impl Libgit2Backend {
+    fn open(dir: &Path) -> Result<Repository, String> {
+        Repository::discover(dir).map_err(|e| format!("Failed to open repository: {}", e))
+    }
+
+    fn resolve(repo: &Repository, rev: &str) -> Result<git2::Oid, String> {
+        repo.revparse_single(rev)
+            .and_then(|obj| obj.peel_to_commit())
+            .map(|commit| commit.id())
+            .map_err(|e| format!("Unknown revision '{}': {}", rev, e.message()))
+    }
+}
+
+impl GitBackend for Libgit2Backend {
+    fn toplevel(&self, dir: &Path) -> Option<PathBuf> {
+        let repo = Self::open(dir).ok()?;
+        repo.workdir().map(|p| canonical(p.to_path_buf()))
+    }
+
+    fn git_dir(&self, dir: &Path) -> Option<PathBuf> {
+        let repo = Self::open(dir).ok()?;
+        Some(canonical(repo.path().to_path_buf()))
+    }
+
+    fn rev_parse(&self, dir: &Path, rev: &str) -> Result<String, String> {
+        let repo = Self::open(dir)?;
+        repo.revparse_single(rev)
+            .map(|obj| obj.id().to_string())
+            .map_err(|_| format!("Unknown revision: {}", rev))
+    }
+
+    fn ref_exists(&self, dir: &Path, refname: &str) -> bool {
+        Self::open(dir)
+            .map(|repo| repo.find_reference(refname).is_ok())
+            .unwrap_or(false)
+    }
+
+    fn symbolic_target(&self, dir: &Path, refname: &str) -> Option<String> {
+        let repo = Self::open(dir).ok()?;
+        let reference = repo.find_reference(refname).ok()?;
+        reference
+            .symbolic_target()
+            .ok()
+            .flatten()
+            .map(|t| t.to_string())
+    }
+
+    fn current_branch(&self, dir: &Path) -> Result<String, String> {
+        let repo = Self::open(dir)?;
+        let head = repo
+            .head()
+            .map_err(|e| format!("Failed to resolve current branch: {}", e.message()))?;
+        if repo.head_detached().unwrap_or(false) {
+            return Ok("HEAD".to_string());
+        }
+        Ok(head.shorthand().unwrap_or("HEAD").to_string())
+    }
+
+    fn local_branches(&self, dir: &Path) -> Result<Vec<String>, String> {
+        let repo = Self::open(dir)?;
+        let branches = repo
+            .branches(Some(git2::BranchType::Local))
+            .map_err(|e| format!("Failed to list branches: {}", e.message()))?;
+        let mut names: Vec<String> = branches
+            .filter_map(|b| b.ok())
+            .filter_map(|(branch, _)| branch.name().ok().flatten().map(|n| n.to_string()))
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn merge_base(&self, dir: &Path, rev1: &str, rev2: &str) -> Result<String, String> {
+        let repo = Self::open(dir)?;
+        let one = Self::resolve(&repo, rev1)?;
+        let two = Self::resolve(&repo, rev2)?;
+        repo.merge_base(one, two)
+            .map(|oid| oid.to_string())
+            .map_err(|e| format!("Failed to find merge-base: {}", e.message()))
+    }
+
+    fn count_commits(&self, dir: &Path, base: &str, head: &str) -> Result<usize, String> {
+        let repo = Self::open(dir)?;
+        let mut walk = repo
+            .revwalk()
+            .map_err(|e| format!("Failed to count commits: {}", e.message()))?;
+        walk.set_sorting(Sort::NONE)
+            .map_err(|e| format!("Failed to count commits: {}", e.message()))?;
+        walk.push(Self::resolve(&repo, head)?)
+            .map_err(|e| format!("Failed to count commits: {}", e.message()))?;
+        walk.hide(Self::resolve(&repo, base)?)
+            .map_err(|e| format!("Failed to count commits: {}", e.message()))?;
+        Ok(walk.filter(|oid| oid.is_ok()).count())
+    }
+
+    fn ahead_behind(&self, dir: &Path, base: &str, head: &str) -> Result<(usize, usize), String> {
+        let repo = Self::open(dir)?;
+        repo.graph_ahead_behind(Self::resolve(&repo, head)?, Self::resolve(&repo, base)?)
+            .map_err(|e| format!("Failed to count commits: {}", e.message()))
+    }
+
+    fn commit_parents(&self, dir: &Path, rev: &str) -> Result<Vec<String>, String> {
+        let repo = Self::open(dir)?;
+        let commit = repo
+            .find_commit(Self::resolve(&repo, rev)?)
+            .map_err(|e| format!("Failed to read commit: {}", e.message()))?;
+        Ok(commit.parent_ids().map(|oid| oid.to_string()).collect())
+    }
+
+    fn read_blob(&self, dir: &Path, rev: &str, path: &str) -> Result<Vec<u8>, String> {
+        let repo = Self::open(dir)?;
+
+        // ":<stage>" addresses the index rather than a commit.
+        if let Some(stage) = rev.strip_prefix(':') {
+            let stage = if stage.is_empty() {
+                0
+            } else {
+                stage
+                    .parse::<i32>()
+                    .map_err(|_| format!("Invalid index stage: {}", rev))?
+            };
+            let index = repo
+                .index()
+                .map_err(|e| format!("Failed to read index: {}", e.message()))?;
+            let entry = index
+                .get_path(Path::new(path), stage)
+                .ok_or_else(|| format!("fatal: path '{}' is not in the index", path))?;
+            let blob = repo
+                .find_blob(entry.id)
+                .map_err(|e| format!("Failed to read blob: {}", e.message()))?;
+            return Ok(blob.content().to_vec());
+        }
+
+        let tree = repo
+            .revparse_single(rev)
+            .and_then(|obj| obj.peel_to_tree())
+            .map_err(|e| format!("fatal: invalid object name '{}': {}", rev, e.message()))?;
+        let entry = tree
+            .get_path(Path::new(path))
+            .map_err(|_| format!("fatal: path '{}' does not exist in '{}'", path, rev))?;
+        let blob = entry
+            .to_object(&repo)
+            .and_then(|obj| obj.peel_to_blob())
+            .map_err(|e| format!("Failed to read blob: {}", e.message()))?;
+        Ok(blob.content().to_vec())
+    }
+
+    fn list_tree(&self, dir: &Path, rev: &str) -> Result<Vec<String>, String> {
+        let repo = Self::open(dir)?;
+        let tree = repo
+            .revparse_single(rev)
+            .and_then(|obj| obj.peel_to_tree())
+            .map_err(|e| format!("fatal: not a tree object '{}': {}", rev, e.message()))?;
+
+        let mut files = Vec::new();
+        tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() != Some(ObjectType::Tree) {
+                let name = String::from_utf8_lossy(entry.name_bytes());
+                files.push(format!("{}{}", root, name));
+            }
+            TreeWalkResult::Ok
+        })
+        .map_err(|e| format!("Failed to walk tree: {}", e.message()))?;
+        files.sort();
+        Ok(files)
+    }
+
+    fn config_value(&self, dir: &Path, key: &str) -> Option<String> {
+        let repo = Self::open(dir).ok()?;
+        repo.config().ok()?.get_string(key).ok()
+    }
+
+    fn head_commit_time(&self, dir: &Path) -> Option<i64> {
+        let repo = Self::open(dir).ok()?;
+        let commit = repo.head().ok()?.peel_to_commit().ok()?;
+        Some(commit.time().seconds())
+    }
+
+    fn hash_blob(&self, _dir: &Path, content: &[u8]) -> Result<String, String> {
+        git2::Oid::hash_object(ObjectType::Blob, content)
+            .map(|oid| oid.to_string())
+            .map_err(|e| format!("Failed to hash object: {}", e.message()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestRepo;
+
+    fn backends() -> [&'static dyn GitBackend; 2] {
+        [&CliBackend, &Libgit2Backend]
+    }
+
+    fn fixture() -> TestRepo {
+        let repo = TestRepo::new("backend");
+        repo.write("README.md", "hello\n");
+        repo.write("src/lib.rs", "pub fn a() {}\n");
+        repo.commit_all("initial");
+        repo.git(&["checkout", "-q", "-b", "feature"]);
+        repo.write("src/lib.rs", "pub fn a() {}\npub fn b() {}\n");
+        repo.commit_all("add b");
+        repo.write(
+            "src/lib.rs",
+            "pub fn a() {}\npub fn b() {}\npub fn c() {}\n",
+        );
+        repo.git(&["add", "src/lib.rs"]);
+        repo
+    }
+
+    #[test]
+    fn test_backends_agree_on_refs() {
+        let repo = fixture();
+        let main = repo.git(&["rev-parse", "main"]);
+
+        for backend in backends() {
+            assert_eq!(
+                backend.git_dir(repo.dir()).unwrap(),
+                repo.dir().join(".git").canonicalize().unwrap()
+            );
+            assert!(backend.ref_exists(repo.dir(), "refs/heads/main"));
+            assert!(!backend.ref_exists(repo.dir(), "refs/heads/nope"));
+            assert_eq!(backend.current_branch(repo.dir()).unwrap(), "feature");
+            assert_eq!(
+                backend.local_branches(repo.dir()).unwrap(),
+                vec!["feature".to_string(), "main".to_string()]
+            );
+            assert_eq!(backend.rev_parse(repo.dir(), "main").unwrap(), main);
+            assert!(backend.rev_parse(repo.dir(), "missing").is_err());
+            assert_eq!(
+                backend.merge_base(repo.dir(), "main", "feature").unwrap(),
+                main
+            );
+            assert_eq!(
+                backend
+                    .count_commits(repo.dir(), "main", "feature")
+                    .unwrap(),
+                1
+            );
+            assert_eq!(
+                backend.ahead_behind(repo.dir(), "main", "feature").unwrap(),
+                (1, 0)
+            );
+            assert_eq!(
+                backend.ahead_behind(repo.dir(), "feature", "main").unwrap(),
+                (0, 1)
+            );
+            assert_eq!(
+                backend.config_value(repo.dir(), "user.name").as_deref(),
+                Some("Test User")
+            );
+            assert_eq!(backend.head_commit_time(repo.dir()), Some(1704067200));
+        }
+    }
+
+    #[test]
+    fn test_backends_agree_on_blobs_and_trees() {
+        let repo = fixture();
+
+        for backend in backends() {
+            assert_eq!(
+                backend.read_blob(repo.dir(), "main", "src/lib.rs").unwrap(),
+                b"pub fn a() {}\n"
+            );
+            assert_eq!(
+                backend.read_blob(repo.dir(), ":0", "src/lib.rs").unwrap(),
+                b"pub fn a() {}\npub fn b() {}\npub fn c() {}\n"
+            );
+            assert!(backend.read_blob(repo.dir(), "HEAD", "missing.rs").is_err());
+            assert_eq!(
+                backend.list_tree(repo.dir(), "HEAD").unwrap(),
+                vec!["README.md".to_string(), "src/lib.rs".to_string()]
+            );
+        }
+    }
+
+    #[test]
+    fn test_backends_agree_on_parents() {
+        let repo = fixture();
+        repo.git(&["commit", "-q", "-m", "add c"]);
+        repo.git(&["checkout", "-q", "main"]);
+        repo.write("README.md", "hello\nworld\n");
+        repo.commit_all("readme");
+        repo.git(&["merge", "-q", "--no-ff", "-m", "merge", "feature"]);
+        let expected = vec![
+            repo.git(&["rev-parse", "HEAD^1"]),
+            repo.git(&["rev-parse", "HEAD^2"]),
+        ];
+        let root = repo.git(&["rev-list", "--max-parents=0", "HEAD"]);
+
+        for backend in backends() {
+            assert_eq!(
+                backend.commit_parents(repo.dir(), "HEAD").unwrap(),
+                expected
+            );
+            assert!(backend
+                .commit_parents(repo.dir(), &root)
+                .unwrap()
+                .is_empty());
+            assert!(backend.commit_parents(repo.dir(), "missing").is_err());
+        }
+    }
+
+    #[test]
+    fn test_backends_agree_on_symbolic_refs() {
+        let repo = fixture();
+        repo.git(&["update-ref", "refs/remotes/origin/main", "main"]);
+        repo.git(&[
+            "symbolic-ref",
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/main",
+        ]);
+
+        for backend in backends() {
+            assert_eq!(
+                backend
+                    .symbolic_target(repo.dir(), "refs/remotes/origin/HEAD")
+                    .as_deref(),
+                Some("refs/remotes/origin/main")
+            );
+            assert_eq!(backend.symbolic_target(repo.dir(), "refs/heads/main"), None);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_backends_agree_on_locations() {
+        let repo = fixture();
+        let toplevel = repo.dir().canonicalize().unwrap();
+        let linked = repo.dir().join("linked");
+        repo.git(&["worktree", "add", "-q", "--detach", "linked"]);
+        // The same repo reached through a symlink.
+        let alias = repo.dir().join("alias");
+        std::os::unix::fs::symlink(repo.dir(), &alias).unwrap();
+
+        for backend in backends() {
+            for dir in [repo.dir().join("src"), alias.join("src")] {
+                assert_eq!(backend.toplevel(&dir).unwrap(), toplevel);
+                assert_eq!(backend.git_dir(&dir).unwrap(), toplevel.join(".git"));
+            }
+            assert_eq!(
+                backend.toplevel(&alias.join("linked")).unwrap(),
+                toplevel.join("linked")
+            );
+            assert_eq!(
+                backend.git_dir(&linked).unwrap(),
+                toplevel.join(".git/worktrees/linked")
+            );
+        }
+    }
+
+    #[test]
+    fn test_backends_agree_on_blob_hashes() {
+        let repo = fixture();
+        let expected = repo.git(&["rev-parse", "main:README.md"]);
+
+        for backend in backends() {
+            assert_eq!(backend.hash_blob(repo.dir(), b"hello\n").unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_backends_never_read_revs_as_options() {
+        let repo = fixture();
+        let out = repo.dir().join("out.txt");
+        let rev = format!("--output={}", out.display());
+
+        for backend in backends() {
+            assert!(backend.rev_parse(repo.dir(), &rev).is_err());
+            assert!(backend.merge_base(repo.dir(), &rev, "main").is_err());
+            assert!(backend.count_commits(repo.dir(), "main", &rev).is_err());
+            assert!(backend.ahead_behind(repo.dir(), &rev, "main").is_err());
+            assert!(backend.commit_parents(repo.dir(), &rev).is_err());
+            assert!(backend.read_blob(repo.dir(), &rev, "README.md").is_err());
+            assert!(backend.list_tree(repo.dir(), &rev).is_err());
+        }
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn test_backends_report_detached_head() {
+        let repo = fixture();
+        repo.git(&["reset", "-q", "--hard"]);
+        repo.git(&["checkout", "-q", "--detach", "main"]);
+
+        for backend in backends() {
+            assert_eq!(backend.current_branch(repo.dir()).unwrap(), "HEAD");
+        }
+    }
+}
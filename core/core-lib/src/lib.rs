@@ -1,3 +1,9 @@
 pub mod config;
+pub mod diff;
+pub mod encoding;
 pub mod files;
 pub mod git;
+pub mod git_backend;
+
+#[cfg(test)]
+mod test_util;
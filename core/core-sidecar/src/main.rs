@@ -1,4 +1,4 @@
-use core_lib::{config, files, git};
+use core_lib::{config, diff, files, git};
 use serde::Deserialize;
 use serde_json::{json, Value};
 use std::io::{self, BufRead, Write};
@@ -45,6 +45,42 @@ fn param_u32(params: &Value, key: &str) -> Result<u32, String> {
         .ok_or_else(|| format!("missing or non-numeric param: {}", key))
 }
 
+fn param_str_list(params: &Value, key: &str) -> Result<Vec<String>, String> {
+    params
+        .get(key)
+        .and_then(|v| v.as_array())
+        .and_then(|items| {
+            items
+                .iter()
+                .map(|item| item.as_str().map(|s| s.to_string()))
+                .collect()
+        })
+        .ok_or_else(|| format!("missing or non-string-array param: {}", key))
+}
+
+fn param_opt_str(params: &Value, key: &str) -> Option<String> {
+    params
+        .get(key)
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+}
+
+/// Diff options are optional top-level params; missing ones keep their defaults.
+fn param_diff_options(params: &Value) -> Result<git::DiffOptions, String> {
+    if params.is_null() {
+        return Ok(git::DiffOptions::default());
+    }
+    serde_json::from_value(params.clone()).map_err(|e| format!("invalid diff options: {}", e))
+}
+
+/// Hunk and line selections for partial staging, as an array of objects.
+fn param_selections(params: &Value) -> Result<Vec<git::PatchSelection>, String> {
+    let selections = params
+        .get("selections")
+        .ok_or_else(|| "missing param: selections".to_string())?;
+    serde_json::from_value(selections.clone()).map_err(|e| format!("invalid selections: {}", e))
+}
+
 fn dispatch(method: &str, params: &Value) -> Result<Value, String> {
     match method {
         "is_git_repo" => {
@@ -53,60 +89,107 @@ fn dispatch(method: &str, params: &Value) -> Result<Value, String> {
         }
         "get_unstaged_diff" => {
             let path = PathBuf::from(param_str(params, "path")?);
-            git::get_unstaged_diff(&path).map(|v| serde_json::to_value(v).unwrap())
+            let opts = param_diff_options(params)?;
+            match param_opt_str(params, "session") {
+                Some(session) => git::get_session_unstaged_diff(&path, &session, &opts),
+                None => git::get_unstaged_diff(&path, &opts),
+            }
+            .map(|v| serde_json::to_value(v).unwrap())
         }
         "get_staged_diff" => {
             let path = PathBuf::from(param_str(params, "path")?);
-            git::get_staged_diff(&path).map(|v| serde_json::to_value(v).unwrap())
+            let opts = param_diff_options(params)?;
+            match param_opt_str(params, "session") {
+                Some(session) => git::get_session_staged_diff(&path, &session, &opts),
+                None => git::get_staged_diff(&path, &opts),
+            }
+            .map(|v| serde_json::to_value(v).unwrap())
         }
         "get_git_change_status" => {
             let path = PathBuf::from(param_str(params, "path")?);
-            git::get_git_change_status(&path).map(|v| serde_json::to_value(v).unwrap())
+            match param_opt_str(params, "session") {
+                Some(session) => git::get_session_change_status(&path, &session),
+                None => git::get_git_change_status(&path),
+            }
+            .map(|v| serde_json::to_value(v).unwrap())
+        }
+        "open_review_session" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let opts = param_diff_options(params)?;
+            git::open_review_session(&path, &opts).map(|v| serde_json::to_value(v).unwrap())
         }
         "get_commit_ref_diff" => {
             let path = PathBuf::from(param_str(params, "path")?);
             let commit = param_str(params, "commit")?;
+            let opts = param_diff_options(params)?;
             let r = if commit == "HEAD" {
-                git::get_head_diff(&path, 0)
+                git::get_head_diff(&path, 0, &opts)
             } else if let Some(num_str) = commit.strip_prefix("HEAD~") {
                 let n = num_str.parse::<u32>().unwrap_or(1);
-                git::get_head_diff(&path, n)
+                git::get_head_diff(&path, n, &opts)
             } else {
-                git::get_commit_diff(&path, &commit)
+                git::get_commit_diff(&path, &commit, &opts)
             };
             r.map(|v| serde_json::to_value(v).unwrap())
         }
         "get_range_diff" => {
             let path = PathBuf::from(param_str(params, "path")?);
             let range = param_str(params, "range")?;
-            git::get_range_diff(&path, &range).map(|v| serde_json::to_value(v).unwrap())
+            let opts = param_diff_options(params)?;
+            git::get_range_diff(&path, &range, &opts).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "get_interdiff" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let old = param_str(params, "old")?;
+            let new = param_opt_str(params, "new").unwrap_or_else(|| "HEAD".to_string());
+            let opts = param_diff_options(params)?;
+            git::get_interdiff(&path, &old, &new, &opts).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "parse_diff" => {
+            let diff_text = param_str(params, "diff")?;
+            Ok(serde_json::to_value(diff::parse_unified_diff(&diff_text)).unwrap())
         }
         "list_files" => {
             let path = PathBuf::from(param_str(params, "path")?);
-            files::list_files(&path, 10).map(|v| serde_json::to_value(v).unwrap())
+            match param_opt_str(params, "session") {
+                Some(session) => git::list_files_at_ref(&path, &session),
+                None => files::list_files(&path, 10),
+            }
+            .map(|v| serde_json::to_value(v).unwrap())
         }
         "read_file_content" => {
             let path = PathBuf::from(param_str(params, "path")?);
             let file_path = param_str(params, "filePath")?;
-            let full = path.join(file_path);
-            files::read_file(&full.to_string_lossy()).map(|v| json!(v))
+            if let Some(session) = param_opt_str(params, "session") {
+                return git::get_file_at_ref(&path, &session, &file_path).map(|v| json!(v));
+            }
+            git::read_worktree_file(&path, &file_path).map(|v| json!(v))
         }
         "read_file_content_base64" => {
             let path = PathBuf::from(param_str(params, "path")?);
             let file_path = param_str(params, "filePath")?;
+            if let Some(session) = param_opt_str(params, "session") {
+                return git::get_file_at_ref_base64(&path, &session, &file_path).map(|v| json!(v));
+            }
             let full = path.join(file_path);
             files::read_file_base64(&full.to_string_lossy()).map(|v| json!(v))
         }
         "get_file_at_ref" => {
             let path = PathBuf::from(param_str(params, "path")?);
-            let git_ref = param_str(params, "gitRef")?;
+            let mut git_ref = param_str(params, "gitRef")?;
             let file_path = param_str(params, "filePath")?;
+            if let Some(session) = param_opt_str(params, "session") {
+                git_ref = git::resolve_session_ref(&path, &session, &git_ref)?;
+            }
             git::get_file_at_ref(&path, &git_ref, &file_path).map(|v| json!(v))
         }
         "get_file_at_ref_base64" => {
             let path = PathBuf::from(param_str(params, "path")?);
-            let git_ref = param_str(params, "gitRef")?;
+            let mut git_ref = param_str(params, "gitRef")?;
             let file_path = param_str(params, "filePath")?;
+            if let Some(session) = param_opt_str(params, "session") {
+                git_ref = git::resolve_session_ref(&path, &session, &git_ref)?;
+            }
             git::get_file_at_ref_base64(&path, &git_ref, &file_path).map(|v| json!(v))
         }
         "get_lfs_file_at_ref" => {
@@ -123,13 +206,69 @@ fn dispatch(method: &str, params: &Value) -> Result<Value, String> {
         }
         "list_commits" => {
             let path = PathBuf::from(param_str(params, "path")?);
+            let opts: git::LogOptions = serde_json::from_value(params.clone())
+                .map_err(|e| format!("invalid log options: {}", e))?;
+            git::list_commits(&path, &opts).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "get_file_history" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let file_path = param_str(params, "filePath")?;
+            let skip = param_u32(params, "skip").unwrap_or(0);
             let limit = param_u32(params, "limit")?;
-            git::list_commits(&path, limit).map(|v| serde_json::to_value(v).unwrap())
+            git::get_file_history(&path, &file_path, skip, limit)
+                .map(|v| serde_json::to_value(v).unwrap())
         }
         "get_commit_diff" => {
             let path = PathBuf::from(param_str(params, "path")?);
             let hash = param_str(params, "hash")?;
-            git::get_commit_diff(&path, &hash).map(|v| serde_json::to_value(v).unwrap())
+            let opts = param_diff_options(params)?;
+            git::get_commit_diff(&path, &hash, &opts).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "get_commit_parents" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let hash = param_str(params, "hash")?;
+            git::get_commit_parents(&path, &hash).map(|v| json!(v))
+        }
+        "get_repo_state" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            git::get_repo_state(&path).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "get_conflict_versions" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let file_path = param_str(params, "filePath")?;
+            let opts = param_diff_options(params)?;
+            git::get_conflict_versions(&path, &file_path, &opts)
+                .map(|v| serde_json::to_value(v).unwrap())
+        }
+        "blame_range" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let git_ref = param_opt_str(params, "gitRef");
+            let file_path = param_str(params, "filePath")?;
+            let start = param_u32(params, "start")?;
+            let end = param_u32(params, "end")?;
+            git::blame_range(&path, git_ref.as_deref(), &file_path, start, end)
+                .map(|v| serde_json::to_value(v).unwrap())
+        }
+        "list_stashes" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            git::list_stashes(&path).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "get_stash_diff" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let stash = param_str(params, "stash")?;
+            let opts = param_diff_options(params)?;
+            git::get_stash_diff(&path, &stash, &opts).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "list_tags" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            git::list_tags(&path).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "get_tag_diff" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let from = param_str(params, "from")?;
+            let to = param_str(params, "to")?;
+            let opts = param_diff_options(params)?;
+            git::get_tag_diff(&path, &from, &to, &opts).map(|v| serde_json::to_value(v).unwrap())
         }
         "list_branches" => {
             let path = PathBuf::from(param_str(params, "path")?);
@@ -138,13 +277,82 @@ fn dispatch(method: &str, params: &Value) -> Result<Value, String> {
         "get_branch_diff" => {
             let path = PathBuf::from(param_str(params, "path")?);
             let branch = param_str(params, "branch")?;
-            git::get_branch_diff(&path, &branch).map(|v| serde_json::to_value(v).unwrap())
+            let opts = param_diff_options(params)?;
+            git::get_branch_diff(&path, &branch, &opts).map(|v| serde_json::to_value(v).unwrap())
         }
         "get_branch_base" => {
             let path = PathBuf::from(param_str(params, "path")?);
             let branch = param_str(params, "branch")?;
             git::get_branch_base(&path, &branch).map(|v| json!(v))
         }
+        "resolve_branch_base" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let branch = param_str(params, "branch")?;
+            git::resolve_branch_base(&path, &branch).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "set_base_branch" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let base = param_opt_str(params, "base");
+            git::set_base_branch(&path, base.as_deref()).map(|_| Value::Null)
+        }
+        "get_submodule_diff" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let submodule_path = param_str(params, "submodulePath")?;
+            let old_commit = param_str(params, "oldCommit")?;
+            let new_commit = param_str(params, "newCommit")?;
+            let opts = param_diff_options(params)?;
+            git::get_submodule_diff(&path, &submodule_path, &old_commit, &new_commit, &opts)
+                .map(|v| serde_json::to_value(v).unwrap())
+        }
+        "record_review_snapshot" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let session = param_opt_str(params, "session");
+            git::record_review_snapshot(&path, session.as_deref())
+                .map(|v| serde_json::to_value(v).unwrap())
+        }
+        "get_review_snapshot" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            git::get_review_snapshot(&path).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "get_diff_since_review" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let opts = param_diff_options(params)?;
+            git::get_diff_since_review(&path, &opts).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "stage_selection" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let selections = param_selections(params)?;
+            git::stage_selection(&path, &selections).map(|_| Value::Null)
+        }
+        "unstage_selection" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let selections = param_selections(params)?;
+            git::unstage_selection(&path, &selections).map(|_| Value::Null)
+        }
+        "discard_selection" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let selections = param_selections(params)?;
+            git::discard_selection(&path, &selections).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "discard_files" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let files = param_str_list(params, "files")?;
+            git::discard_files(&path, &files).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "get_last_discard" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            git::get_last_discard(&path).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "undo_last_discard" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            git::undo_last_discard(&path).map(|v| serde_json::to_value(v).unwrap())
+        }
+        "create_commit" => {
+            let path = PathBuf::from(param_str(params, "path")?);
+            let opts: git::CommitOptions = serde_json::from_value(params.clone())
+                .map_err(|e| format!("invalid commit options: {}", e))?;
+            git::create_commit(&path, &opts).map(|v| serde_json::to_value(v).unwrap())
+        }
         "list_files_at_ref" => {
             let path = PathBuf::from(param_str(params, "path")?);
             let git_ref = param_str(params, "gitRef")?;
@@ -185,13 +393,15 @@ fn dispatch(method: &str, params: &Value) -> Result<Value, String> {
         "get_gg_stack_diff" => {
             let path = PathBuf::from(param_str(params, "path")?);
             let stack_name = param_str(params, "stackName")?;
-            git::get_gg_stack_diff(&path, &stack_name).map(|v| json!(v))
+            let opts = param_diff_options(params)?;
+            git::get_gg_stack_diff(&path, &stack_name, &opts).map(|v| json!(v))
         }
         "get_gg_entry_diff" => {
             let path = PathBuf::from(param_str(params, "path")?);
             let stack_name = param_str(params, "stackName")?;
             let hash = param_str(params, "hash")?;
-            git::get_gg_entry_diff(&path, &stack_name, &hash).map(|v| json!(v))
+            let opts = param_diff_options(params)?;
+            git::get_gg_entry_diff(&path, &stack_name, &hash, &opts).map(|v| json!(v))
         }
         "list_repos" => {
             let repos = config::list_repos()?;
@@ -238,7 +448,8 @@ fn dispatch(method: &str, params: &Value) -> Result<Value, String> {
             if !git::is_git_repo(&dir) {
                 return Err("Not a git repository".to_string());
             }
-            git::get_unstaged_diff(&dir).map(|v| serde_json::to_value(v).unwrap())
+            git::get_unstaged_diff(&dir, &git::DiffOptions::default())
+                .map(|v| serde_json::to_value(v).unwrap())
         }
         _ => Err(format!("__UNKNOWN_METHOD__:{}", method)),
     }
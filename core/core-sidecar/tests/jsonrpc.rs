@@ -100,3 +100,21 @@ fn handles_pipelined_requests_in_order() {
     drop(stdin);
     let _ = child.wait();
 }
+
+#[test]
+fn rejects_invalid_diff_options() {
+    let (mut child, mut reader) = spawn();
+    let mut stdin = child.stdin.take().unwrap();
+    send(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","id":4,"method":"get_staged_diff","params":{"path":"/tmp","ignoreWhitespace":"sometimes"}}"#,
+    );
+    let resp = recv(&mut reader);
+    assert_eq!(resp["id"], 4);
+    assert!(resp["error"]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("invalid diff options"));
+    drop(stdin);
+    let _ = child.wait();
+}
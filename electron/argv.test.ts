@@ -29,6 +29,27 @@ describe("parseLaunchArgs", () => {
     expect(b.initialDiffMode).toEqual({ type: "branch", value: "main" });
   });
 
+  it("parses --diff-since", () => {
+    const out = parseLaunchArgs(["--diff-since", "abc1234"], "/home/me");
+    expect(out.initialDiffMode).toEqual({ type: "since", value: "abc1234" });
+  });
+
+  it("parses --diff-stash", () => {
+    const out = parseLaunchArgs(["--diff-stash", "stash@{1}"], "/home/me");
+    expect(out.initialDiffMode).toEqual({ type: "stash", value: "stash@{1}" });
+  });
+
+  it("parses --diff-tags", () => {
+    const out = parseLaunchArgs(["--diff-tags", "v1.0..v1.1"], "/home/me");
+    expect(out.initialDiffMode).toEqual({ type: "tags", value: "v1.0..v1.1" });
+  });
+
+  it("parses --diff-conflicts without consuming the next arg", () => {
+    const out = parseLaunchArgs(["--diff-conflicts", "/repos/foo"], "/home/me");
+    expect(out.initialDiffMode).toEqual({ type: "conflicts", value: "" });
+    expect(out.workingDir).toBe("/repos/foo");
+  });
+
   it("uses positional arg as working directory", () => {
     const out = parseLaunchArgs(["/repos/foo"], "/home/me");
     expect(out.workingDir).toBe("/repos/foo");
@@ -1,7 +1,11 @@
 export type InitialDiffMode =
   | { type: "commit"; value: string }
   | { type: "range"; value: string }
-  | { type: "branch"; value: string };
+  | { type: "branch"; value: string }
+  | { type: "since"; value: string }
+  | { type: "stash"; value: string }
+  | { type: "tags"; value: string }
+  | { type: "conflicts"; value: "" };
 
 export interface LaunchArgs {
   workingDir: string;
@@ -49,6 +53,26 @@ export function parseLaunchArgs(argv: string[], defaultDir: string): LaunchArgs
         out.initialDiffMode = { type: "branch", value: argv[i + 1] ?? "" };
         i += 2;
         break;
+      case "--diff-since":
+      case "--since":
+        out.initialDiffMode = { type: "since", value: argv[i + 1] ?? "" };
+        i += 2;
+        break;
+      case "--diff-stash":
+      case "--stash":
+        out.initialDiffMode = { type: "stash", value: argv[i + 1] ?? "" };
+        i += 2;
+        break;
+      case "--diff-tags":
+      case "--tags":
+        out.initialDiffMode = { type: "tags", value: argv[i + 1] ?? "" };
+        i += 2;
+        break;
+      case "--diff-conflicts":
+      case "--conflicts":
+        out.initialDiffMode = { type: "conflicts", value: "" };
+        i += 1;
+        break;
       case "--feedback-pipe":
         out.feedbackPipe = argv[i + 1] ?? null;
         i += 2;
@@ -6,17 +6,45 @@ import { Sidecar } from "./sidecar.js";
 import { buildMenu } from "./menu.js";
 import { parseLaunchArgs, type LaunchArgs } from "./argv.js";
 
+/** First line of the feedback file when the review ended in a commit. */
+const COMMITTED_MARKER = "air:committed ";
+
+type CommitOutcome =
+  | { status: "committed"; commit: { hash: string; short_hash: string; message: string } }
+  | { status: "hook-failed"; hook: string; exit_code: number | null; output: string };
+
 let mainWindow: BrowserWindow | null = null;
 const sidecar = new Sidecar();
 let launchArgs: LaunchArgs;
 
+/**
+ * The review session working-copy reads are served from while one is open,
+ * so the file list, diff and expanded context all come from the same frozen
+ * state. The change-status poll is left live, as it is how edits get noticed.
+ */
+let reviewSession: string | null = null;
+const SESSION_METHODS = new Set<string>([
+  "get_unstaged_diff",
+  "get_staged_diff",
+  "list_files",
+  "read_file_content",
+  "read_file_content_base64",
+  "get_file_at_ref",
+  "get_file_at_ref_base64",
+]);
+
 const SIDECAR_METHODS = new Set<string>([
   "is_git_repo",
   "get_unstaged_diff",
   "get_staged_diff",
   "get_git_change_status",
+  "open_review_session",
+  "get_repo_state",
+  "get_conflict_versions",
   "get_commit_ref_diff",
   "get_range_diff",
+  "parse_diff",
+  "get_interdiff",
   "list_files",
   "read_file_content",
   "read_file_content_base64",
@@ -24,12 +52,32 @@ const SIDECAR_METHODS = new Set<string>([
   "get_file_at_ref_base64",
   "get_lfs_file_at_ref",
   "get_lfs_file_at_ref_base64",
+  "blame_range",
   "list_commits",
   "get_commit_diff",
+  "get_commit_parents",
+  "get_file_history",
   "list_branches",
   "get_branch_diff",
   "get_branch_base",
+  "resolve_branch_base",
+  "set_base_branch",
   "list_files_at_ref",
+  "get_submodule_diff",
+  "record_review_snapshot",
+  "get_review_snapshot",
+  "get_diff_since_review",
+  "stage_selection",
+  "unstage_selection",
+  "discard_selection",
+  "discard_files",
+  "get_last_discard",
+  "undo_last_discard",
+  "create_commit",
+  "list_stashes",
+  "get_stash_diff",
+  "list_tags",
+  "get_tag_diff",
   "has_gg_stacks",
   "list_worktrees",
   "has_worktrees",
@@ -90,21 +138,64 @@ function registerIpc(): void {
       case "get_initial_diff_mode":
         return launchArgs.initialDiffMode;
       case "submit_feedback": {
-        const feedback = String((params as { feedback?: string }).feedback ?? "");
+        const { feedback, mode } = params as { feedback?: string; mode?: string };
         if (launchArgs.feedbackPipe) {
-          await fs.promises.writeFile(launchArgs.feedbackPipe, feedback, "utf8");
+          await fs.promises.writeFile(launchArgs.feedbackPipe, String(feedback ?? ""), "utf8");
         } else {
-          process.stdout.write(feedback);
+          process.stdout.write(String(feedback ?? ""));
         }
+        await recordReviewSnapshot(mode);
         app.quit();
         return null;
       }
+      case "approve_and_commit": {
+        // A hook turning the commit down goes back to the window, so the
+        // reviewer can fix things and try again.
+        const { mode, ...commitParams } = params as { mode?: string };
+        // Only what the reviewer was shown gets committed.
+        const outcome = await sidecar.invoke<CommitOutcome>("create_commit", {
+          ...commitParams,
+          ...(reviewSession ? { session: reviewSession } : {}),
+          path: launchArgs.workingDir,
+        });
+        if (outcome.status !== "committed") {
+          return outcome;
+        }
+        const { hash, short_hash, message } = outcome.commit;
+        const report = launchArgs.jsonOutput
+          ? JSON.stringify({ outcome: "committed", commit: hash, message })
+          : `Approved and committed ${short_hash}: ${message}\n`;
+        if (launchArgs.feedbackPipe) {
+          // The launcher strips the marker line and prints the report.
+          await fs.promises.writeFile(
+            launchArgs.feedbackPipe,
+            `${COMMITTED_MARKER}${hash}\n${report}`,
+            "utf8"
+          );
+        } else {
+          process.stdout.write(report);
+        }
+        await recordReviewSnapshot(mode);
+        app.quit();
+        return outcome;
+      }
+      case "open_review_session": {
+        const session = await sidecar.invoke<{ id: string }>("open_review_session", params);
+        reviewSession = session.id;
+        return session;
+      }
+      case "close_review_session":
+        reviewSession = null;
+        return null;
       case "check_cli_installed":
         return await checkCliInstalled();
       case "install_cli":
         return await installCli();
       default:
         if (SIDECAR_METHODS.has(method)) {
+          if (reviewSession && SESSION_METHODS.has(method) && !("session" in params)) {
+            return sidecar.invoke(method, { ...params, session: reviewSession });
+          }
           return sidecar.invoke(method, params);
         }
         throw new Error(`unknown method: ${method}`);
@@ -124,6 +215,23 @@ function registerIpc(): void {
   });
 }
 
+// Remember what was reviewed so the next round can show only what changed
+// since. Only a review of the working copy has a state worth remembering, and
+// it runs after the feedback is out, so it never holds the launcher up. What
+// was reviewed is the session the diff was served from, not the worktree as
+// it is by now.
+async function recordReviewSnapshot(mode: string | undefined): Promise<void> {
+  if (mode !== "unstaged" && mode !== "staged") return;
+  try {
+    await sidecar.invoke("record_review_snapshot", {
+      path: launchArgs.workingDir,
+      ...(reviewSession ? { session: reviewSession } : {}),
+    });
+  } catch {
+    // not a git repo, or the snapshot failed
+  }
+}
+
 async function checkCliInstalled(): Promise<boolean> {
   if (process.platform === "win32") return false;
   const home = os.homedir();
@@ -46,12 +46,8 @@ import { DiffFileBody } from "./components/DiffFileBody";
 import { LazyDiffFile } from "./components/LazyDiffFile";
 import { estimateFileHeight } from "./lib/diffMetrics";
 import { parseDiffSafely } from "./lib/parseDiffSafely";
-import type { DiffModeConfig, CommitInfo, BranchInfo, GgStackInfo, GgStackEntry, WorktreeInfo, GitDiffResult, ChangedFile, ChangedFileRailItem, Comment } from "./types";
-
-type InitialDiffMode = {
-  type: "commit" | "range" | "branch";
-  value: string;
-};
+import { describeRepoState } from "./lib/conflictDiff";
+import type { DiffModeConfig, InitialDiffMode, RepoState, CommitInfo, CommitOutcome, BranchInfo, GgStackInfo, GgStackEntry, WorktreeInfo, GitDiffResult, ChangedFile, ChangedFileRailItem, Comment } from "./types";
 
 const MIN_RIGHT_RAIL_WIDTH = 240;
 const DEFAULT_RIGHT_RAIL_WIDTH = 320;
@@ -107,6 +103,7 @@ function App() {
     side: "old" | "new";
   } | null>(null);
   const [showPromptPreview, setShowPromptPreview] = useState(false);
+  const [commitError, setCommitError] = useState<string | null>(null);
   const [waitMode, setWaitMode] = useState(false);
   const [jsonOutput, setJsonOutput] = useState(false);
   const [initialDiffMode, setInitialDiffMode] = useState<InitialDiffMode | null>(null);
@@ -316,6 +313,39 @@ function App() {
           setSelectedCommit(null);
           setReviewingLabel(mode.value);
           await loadDiff({ mode: "branch", branchName: mode.value });
+        } else if (mode.type === "since") {
+          setDiffMode({ mode: "since", sinceRef: mode.value });
+          setSelectedCommit(null);
+          setSelectedBranch(null);
+          setReviewingLabel(`since ${mode.value}`);
+          await loadDiff({ mode: "since", sinceRef: mode.value });
+        } else if (mode.type === "stash") {
+          // "1" is short for "stash@{1}"
+          const stashRef = /^\d+$/.test(mode.value)
+            ? `stash@{${mode.value}}`
+            : mode.value || "stash@{0}";
+
+          setDiffMode({ mode: "stash", stashRef });
+          setSelectedCommit(null);
+          setSelectedBranch(null);
+          setReviewingLabel(stashRef);
+          await loadDiff({ mode: "stash", stashRef });
+        } else if (mode.type === "tags") {
+          setDiffMode({ mode: "tags", tagRange: mode.value });
+          setSelectedCommit(null);
+          setSelectedBranch(null);
+          setReviewingLabel(mode.value);
+          await loadDiff({ mode: "tags", tagRange: mode.value });
+        } else if (mode.type === "conflicts") {
+          const state = await invoke<RepoState>("get_repo_state", {
+            path: workingDir,
+          });
+
+          setDiffMode({ mode: "conflicts" });
+          setSelectedCommit(null);
+          setSelectedBranch(null);
+          setReviewingLabel(describeRepoState(state));
+          await loadDiff({ mode: "conflicts" });
         }
 
         setViewMode("diff");
@@ -694,6 +724,19 @@ function App() {
             gitRef: ":0",
             filePath: fileName,
           });
+        } else if (diffMode.mode === "conflicts") {
+          content = await invoke<string>("get_file_at_ref", {
+            path: workingDir,
+            gitRef: ":3",
+            filePath: fileName,
+          });
+        } else if (diffMode.mode === "tags" && diffMode.tagRange) {
+          const toTag = diffMode.tagRange.split("..")[1];
+          content = await invoke<string>("get_file_at_ref", {
+            path: workingDir,
+            gitRef: `refs/tags/${toTag}`,
+            filePath: fileName,
+          });
         } else if (diffMode.mode === "range" && diffMode.range) {
           const parts = diffMode.range.split("..");
           const toRef = parts[parts.length - 1] || "HEAD";
@@ -703,7 +746,7 @@ function App() {
             filePath: fileName,
           });
         } else {
-          const ref = selectedCommit?.hash || selectedBranch?.name || diffMode.commitRef || "HEAD";
+          const ref = selectedCommit?.hash || selectedBranch?.name || diffMode.commitRef || diffMode.stashRef || "HEAD";
           content = await invoke<string>("get_file_at_ref", {
             path: workingDir,
             gitRef: ref,
@@ -837,6 +880,23 @@ function App() {
       return "HEAD";
     }
 
+    if (diffMode.mode === "conflicts") {
+      return ":2"; // "ours" - conflicts compare ours vs theirs
+    }
+
+    if (diffMode.mode === "stash" && diffMode.stashRef) {
+      return `${diffMode.stashRef}^1`; // the commit the stash was made on
+    }
+
+    if (diffMode.mode === "since") {
+      // The old series replayed onto the new base, as reported by get_interdiff
+      return diffResult?.old_side ?? diffMode.sinceRef ?? "HEAD";
+    }
+
+    if (diffMode.mode === "tags" && diffMode.tagRange) {
+      return `refs/tags/${diffMode.tagRange.split("..")[0]}`; // the older tag
+    }
+
     if (diffMode.mode === "commit" && diffMode.commitRef) {
       // Handles handleRefSelect (sets diffMode but not selectedCommit)
       // and handleStackEntrySelect (also sets diffMode.commitRef)
@@ -861,7 +921,7 @@ function App() {
     }
 
     return "HEAD";
-  }, [workingDir, selectedCommit, selectedBranch, diffMode]);
+  }, [workingDir, selectedCommit, selectedBranch, diffMode, diffResult]);
 
   const fetchOldSourceForFile = useCallback(async (file: any) => {
     if (!workingDir) return;
@@ -914,6 +974,18 @@ function App() {
     } else if (diffMode.mode === "staged") {
       // Staged diff compares index vs HEAD, so old side = HEAD
       oldRef = "HEAD";
+    } else if (diffMode.mode === "conflicts") {
+      // Conflicts compare index stage 2 ("ours") vs stage 3 ("theirs")
+      oldRef = ":2";
+    } else if (diffMode.mode === "stash" && diffMode.stashRef) {
+      // Stash entries are diffed against the commit they were made on
+      oldRef = `${diffMode.stashRef}^1`;
+    } else if (diffMode.mode === "since") {
+      // Interdiff: old side is the old series replayed onto the new base
+      oldRef = diffResult?.old_side ?? diffMode.sinceRef ?? "HEAD";
+    } else if (diffMode.mode === "tags" && diffMode.tagRange) {
+      // Tag range is "v1..v2" — old side is the older tag
+      oldRef = `refs/tags/${diffMode.tagRange.split("..")[0]}`;
     } else if (diffMode.mode === "commit") {
       oldRef = `${diffMode.commitRef || "HEAD"}~1`;
     } else if (diffMode.mode === "range" && diffMode.range) {
@@ -953,8 +1025,20 @@ function App() {
           gitRef: ":0",
           filePath,
         });
+      } else if (diffMode.mode === "conflicts") {
+        content = await invoke<string>("get_file_at_ref", {
+          path: workingDir,
+          gitRef: ":3",
+          filePath,
+        });
+      } else if (diffMode.mode === "tags" && diffMode.tagRange) {
+        content = await invoke<string>("get_file_at_ref", {
+          path: workingDir,
+          gitRef: `refs/tags/${diffMode.tagRange.split("..")[1]}`,
+          filePath,
+        });
       } else {
-        const ref = diffMode.commitRef || diffMode.branchName || "HEAD";
+        const ref = diffMode.commitRef || diffMode.branchName || diffMode.stashRef || "HEAD";
         content = await invoke<string>("get_file_at_ref", {
           path: workingDir,
           gitRef: ref,
@@ -1449,7 +1533,10 @@ function App() {
         selectedBranch,
       });
       try {
-        await invoke("submit_feedback", { feedback: JSON.stringify(feedback) });
+        await invoke("submit_feedback", {
+          feedback: JSON.stringify(feedback),
+          mode: diffMode.mode,
+        });
       } catch (err) {
         console.error("Failed to submit JSON feedback:", err);
       }
@@ -1458,6 +1545,31 @@ function App() {
     setShowPromptPreview(true);
   };
 
+  // Only a review of the working copy, with nothing left to fix, can end in
+  // a commit; the app quits once it's made.
+  const canApproveAndCommit =
+    waitMode &&
+    comments.length === 0 &&
+    (diffMode.mode === "staged" || diffMode.mode === "unstaged") &&
+    changedFiles.length > 0;
+
+  const handleApproveAndCommit = async (message: string) => {
+    setCommitError(null);
+    try {
+      const outcome = await invoke<CommitOutcome>("approve_and_commit", {
+        message,
+        // Unstaged review: commit what was reviewed, not whatever is staged.
+        files: diffMode.mode === "unstaged" ? changedFiles.map((file) => file.path) : [],
+        mode: diffMode.mode,
+      });
+      if (outcome.status === "hook-failed") {
+        setCommitError(`${outcome.hook} hook failed:\n${outcome.output}`);
+      }
+    } catch (err) {
+      setCommitError(String(err));
+    }
+  };
+
   const handleInstallCli = async () => {
     try {
       const result = await invoke<{
@@ -2160,6 +2272,9 @@ function App() {
           onOpenCommentOverview={() => setShowCommentOverview(true)}
           onPreviewPrompt={handlePreviewPrompt}
           onGeneratePrompt={handleGeneratePrompt}
+          canApproveAndCommit={canApproveAndCommit}
+          commitError={commitError}
+          onApproveAndCommit={(message) => void handleApproveAndCommit(message)}
           onInstallCli={handleInstallCli}
           onEditComment={updateComment}
           onDeleteComment={deleteComment}
@@ -2190,6 +2305,7 @@ function App() {
           })}
           onClose={() => setShowPromptPreview(false)}
           waitMode={waitMode}
+          diffMode={diffMode.mode}
         />
       )}
 
@@ -1,14 +1,16 @@
 import { useEffect, useState } from "react";
 import { parsePromptLines } from "../lib/promptParser";
 import { invoke } from "../lib/bridge";
+import type { DiffMode } from "../types";
 
 interface PromptPreviewProps {
   prompt: string;
   onClose: () => void;
   waitMode: boolean;
+  diffMode: DiffMode;
 }
 
-export function PromptPreview({ prompt, onClose, waitMode }: PromptPreviewProps) {
+export function PromptPreview({ prompt, onClose, waitMode, diffMode }: PromptPreviewProps) {
   const [editablePrompt, setEditablePrompt] = useState(prompt);
   const [copied, setCopied] = useState(false);
   const [viewMode, setViewMode] = useState<"rich" | "raw">("rich");
@@ -32,7 +34,7 @@ export function PromptPreview({ prompt, onClose, waitMode }: PromptPreviewProps)
 
   const handleSubmitAndExit = async () => {
     try {
-      await invoke("submit_feedback", { feedback: editablePrompt });
+      await invoke("submit_feedback", { feedback: editablePrompt, mode: diffMode });
     } catch (err) {
       console.error("Failed to submit feedback:", err);
     }
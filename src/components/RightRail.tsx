@@ -1,3 +1,4 @@
+import { useState } from "react";
 import type { Comment, ChangedFileRailItem } from "../types";
 import { FileList } from "./FileList";
 import { RailComments } from "./RailComments";
@@ -18,6 +19,9 @@ interface RightRailProps {
   onStartResize: () => void;
   onPreviewPrompt: () => void;
   onGeneratePrompt: () => void;
+  canApproveAndCommit: boolean;
+  commitError: string | null;
+  onApproveAndCommit: (message: string) => void;
   onInstallCli: () => void;
   onScrollToFile: (path: string) => void;
   onPreviewFile: (path: string) => void;
@@ -46,6 +50,9 @@ export function RightRail({
   onStartResize,
   onPreviewPrompt,
   onGeneratePrompt,
+  canApproveAndCommit,
+  commitError,
+  onApproveAndCommit,
   onInstallCli,
   onScrollToFile,
   onPreviewFile,
@@ -57,6 +64,8 @@ export function RightRail({
   onStartEditComment,
   onStopEditComment,
 }: RightRailProps) {
+  const [commitMessage, setCommitMessage] = useState("");
+
   if (!visible) return null;
 
   return (
@@ -78,7 +87,7 @@ export function RightRail({
       />
 
       <section className="flex min-h-0 flex-[3] flex-col border-b border-ctp-surface1">
-        {(comments.length > 0 || cliInstalled === false || cliJustInstalled) && (
+        {(comments.length > 0 || canApproveAndCommit || cliInstalled === false || cliJustInstalled) && (
           <div className="border-b border-ctp-surface1 p-3">
             <div className="mb-1.5 text-[10px] font-semibold uppercase tracking-widest text-ctp-overlay0">
               Review
@@ -111,6 +120,30 @@ export function RightRail({
                   </button>
                 </>
               )}
+              {canApproveAndCommit && (
+                <>
+                  <textarea
+                    value={commitMessage}
+                    onChange={(event) => setCommitMessage(event.target.value)}
+                    placeholder="Commit message"
+                    rows={3}
+                    className="w-full resize-none rounded-sm border border-ctp-surface1 bg-canvas px-2 py-1.5 text-xs text-ink-primary placeholder:text-ctp-overlay0 focus:border-accent-review focus:outline-none"
+                  />
+                  <button
+                    type="button"
+                    onClick={() => onApproveAndCommit(commitMessage)}
+                    disabled={commitMessage.trim() === ""}
+                    className="w-full rounded-sm border border-accent-review bg-surface-hover px-2 py-1.5 text-left text-xs font-medium text-ink-primary transition-colors hover:bg-ctp-surface0 disabled:cursor-not-allowed disabled:opacity-50"
+                  >
+                    Approve and commit
+                  </button>
+                  {commitError && (
+                    <pre className="max-h-40 overflow-auto whitespace-pre-wrap rounded-sm border border-ctp-red/50 px-2 py-1.5 text-[11px] text-ctp-red">
+                      {commitError}
+                    </pre>
+                  )}
+                </>
+              )}
               {(cliInstalled === false || cliJustInstalled) && (
                 <div className="relative">
                   <button
@@ -1,6 +1,13 @@
 import { useState, useEffect, useCallback } from "react";
 import { invoke, listen } from "../lib/bridge";
-import type { GitDiffResult, DiffModeConfig, GitChangeStatus } from "../types";
+import type {
+  GitDiffResult,
+  DiffModeConfig,
+  GitChangeStatus,
+  RepoState,
+  ConflictVersions,
+  InterdiffResult,
+} from "../types";
 
 export function useGit(workingDir: string | null, skipAutoLoad = false) {
   const [isGitRepo, setIsGitRepo] = useState(false);
@@ -65,6 +72,14 @@ export function useGit(workingDir: string | null, skipAutoLoad = false) {
     try {
       let result: GitDiffResult;
 
+      // Working-copy reviews are read from a session frozen at load time;
+      // everything else is already immutable.
+      if (config.mode === "unstaged" || config.mode === "staged") {
+        await invoke("open_review_session", { path: workingDir });
+      } else {
+        await invoke("close_review_session");
+      }
+
       if (config.mode === "unstaged") {
         result = await invoke<GitDiffResult>("get_unstaged_diff", {
           path: workingDir,
@@ -88,6 +103,40 @@ export function useGit(workingDir: string | null, skipAutoLoad = false) {
           path: workingDir,
           branch: config.branchName,
         });
+      } else if (config.mode === "since" && config.sinceRef) {
+        const interdiff = await invoke<InterdiffResult>("get_interdiff", {
+          path: workingDir,
+          old: config.sinceRef,
+        });
+        result = { ...interdiff.interdiff, old_side: interdiff.old_side };
+      } else if (config.mode === "stash" && config.stashRef) {
+        result = await invoke<GitDiffResult>("get_stash_diff", {
+          path: workingDir,
+          stash: config.stashRef,
+        });
+      } else if (config.mode === "tags" && config.tagRange) {
+        const [from, to] = config.tagRange.split("..");
+        result = await invoke<GitDiffResult>("get_tag_diff", {
+          path: workingDir,
+          from,
+          to,
+        });
+      } else if (config.mode === "conflicts") {
+        const state = await invoke<RepoState>("get_repo_state", {
+          path: workingDir,
+        });
+        const versions = await Promise.all(
+          state.conflicted_files.map((file) =>
+            invoke<ConflictVersions>("get_conflict_versions", {
+              path: workingDir,
+              filePath: file.path,
+            })
+          )
+        );
+        result = {
+          diff: versions.map((version) => version.diff.diff).join(""),
+          files: versions.flatMap((version) => version.diff.files),
+        };
       } else {
         throw new Error("Invalid diff mode configuration");
       }
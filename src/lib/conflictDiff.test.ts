@@ -0,0 +1,17 @@
+import { describe, expect, it } from "vitest";
+import { describeRepoState } from "./conflictDiff";
+
+describe("describeRepoState", () => {
+  it("names the operation, its progress and the conflict count", () => {
+    expect(
+      describeRepoState({
+        operation: "rebase",
+        operation_head: null,
+        rebase_branch: "feature",
+        rebase_step: 2,
+        rebase_total: 5,
+        conflicted_files: [{ path: "f.txt", conflict: "both-modified" }],
+      })
+    ).toBe("rebase 2/5: 1 conflicted file");
+  });
+});
@@ -0,0 +1,11 @@
+import type { RepoState } from "../types";
+
+/** Short label for the operation in progress, e.g. "rebase 2/5: 3 conflicted files". */
+export function describeRepoState(state: RepoState): string {
+  let label = state.operation ?? "conflicts";
+  if (state.rebase_step !== null && state.rebase_total !== null) {
+    label += ` ${state.rebase_step}/${state.rebase_total}`;
+  }
+  const count = state.conflicted_files.length;
+  return `${label}: ${count} conflicted file${count === 1 ? "" : "s"}`;
+}
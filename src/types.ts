@@ -24,6 +24,23 @@ export interface ChangedFileRailItem {
 export interface GitDiffResult {
   diff: string;
   files: ChangedFile[];
+  old_side?: string; // commit or tree the old side was read from, when the mode doesn't imply it
+}
+
+export interface RangeDiffPair {
+  old_index: number | null;
+  old_hash: string | null;
+  new_index: number | null;
+  new_hash: string | null;
+  status: "unchanged" | "modified" | "removed" | "added";
+  subject: string;
+  changes: string;
+}
+
+export interface InterdiffResult {
+  pairs: RangeDiffPair[];
+  interdiff: GitDiffResult;
+  old_side: string;
 }
 
 export interface GitChangeStatus {
@@ -41,19 +58,58 @@ export interface Comment {
   createdAt: string;
 }
 
-export type DiffMode = "unstaged" | "staged" | "commit" | "range" | "branch";
+export type DiffMode =
+  | "unstaged"
+  | "staged"
+  | "commit"
+  | "range"
+  | "branch"
+  | "since"
+  | "stash"
+  | "tags"
+  | "conflicts";
 
 export interface DiffModeConfig {
   mode: DiffMode;
   commitRef?: string;
   range?: string;
   branchName?: string;
+  sinceRef?: string;
+  stashRef?: string;
+  tagRange?: string;
 }
 
 export type InitialDiffMode =
   | { type: "commit"; value: string }
   | { type: "range"; value: string }
-  | { type: "branch"; value: string };
+  | { type: "branch"; value: string }
+  | { type: "since"; value: string }
+  | { type: "stash"; value: string }
+  | { type: "tags"; value: string }
+  | { type: "conflicts"; value: "" };
+
+export interface ConflictedFile {
+  path: string;
+  conflict: string;
+}
+
+export interface RepoState {
+  operation: string | null;
+  operation_head: string | null;
+  rebase_branch: string | null;
+  rebase_step: number | null;
+  rebase_total: number | null;
+  conflicted_files: ConflictedFile[];
+}
+
+export interface ConflictVersions {
+  path: string;
+  base: string | null;
+  ours: string | null;
+  theirs: string | null;
+  is_binary: boolean;
+  diff: GitDiffResult; // ours to theirs
+}
 
 export interface CommitInfo {
   hash: string;
@@ -64,6 +120,10 @@ export interface CommitInfo {
   refs: string;
 }
 
+export type CommitOutcome =
+  | { status: "committed"; commit: CommitInfo }
+  | { status: "hook-failed"; hook: string; exit_code: number | null; output: string };
+
 export interface BranchInfo {
   name: string;
   short_hash: string;